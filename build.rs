@@ -0,0 +1,4 @@
+fn main() {
+    // New migration directories are embedded by `embed_migrations!`
+    println!("cargo:rerun-if-changed=migrations");
+}
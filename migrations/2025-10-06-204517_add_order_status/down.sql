@@ -0,0 +1,8 @@
+DROP TABLE order_status_history;
+
+ALTER TABLE orders ALTER COLUMN status DROP DEFAULT;
+ALTER TABLE orders
+ALTER COLUMN status TYPE VARCHAR(20) USING status::text;
+ALTER TABLE orders ALTER COLUMN status SET DEFAULT 'pending';
+
+DROP TYPE order_status;
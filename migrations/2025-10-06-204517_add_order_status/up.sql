@@ -0,0 +1,22 @@
+CREATE TYPE order_status AS ENUM (
+  'pending',
+  'paid',
+  'shipped',
+  'delivered',
+  'cancelled',
+  'refunded'
+);
+
+ALTER TABLE orders ALTER COLUMN status DROP DEFAULT;
+ALTER TABLE orders
+ALTER COLUMN status TYPE order_status USING status::order_status;
+ALTER TABLE orders ALTER COLUMN status SET DEFAULT 'pending';
+
+CREATE TABLE order_status_history (
+  id SERIAL PRIMARY KEY,
+  order_id UUID NOT NULL REFERENCES orders(id) ON DELETE CASCADE,
+  from_status order_status,
+  to_status order_status NOT NULL,
+  changed_by UUID REFERENCES users(id) ON DELETE SET NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
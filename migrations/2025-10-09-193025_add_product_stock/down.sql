@@ -0,0 +1,6 @@
+DROP TABLE stock_movements;
+DROP TYPE stock_reason;
+
+ALTER TABLE products
+DROP COLUMN reserved,
+DROP COLUMN stock;
@@ -0,0 +1,30 @@
+-- Products that exist before this migration get a NULL stock: untracked and
+-- sellable without a limit, so the catalogue stays orderable after deploy.
+-- Rollout: restock each product (or import it with a stock) to start tracking
+-- it. Products created from now on are tracked from 0.
+ALTER TABLE products
+ADD COLUMN stock INT CHECK (stock >= 0),
+ADD COLUMN reserved INT NOT NULL DEFAULT 0 CHECK (reserved >= 0 AND reserved <= stock);
+
+ALTER TABLE products ALTER COLUMN stock SET DEFAULT 0;
+
+CREATE TYPE stock_reason AS ENUM (
+  'restock',
+  'correction',
+  'damaged',
+  'returned',
+  'reserved',
+  'released',
+  'sold'
+);
+
+CREATE TABLE stock_movements (
+  id SERIAL PRIMARY KEY,
+  product_id INT NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+  quantity INT NOT NULL,
+  reason stock_reason NOT NULL,
+  note TEXT,
+  order_id UUID REFERENCES orders(id) ON DELETE SET NULL,
+  created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,3 @@
+ALTER TABLE order_items
+DROP COLUMN discount_id,
+DROP COLUMN original_price;
@@ -0,0 +1,7 @@
+ALTER TABLE order_items
+ADD COLUMN original_price float,
+ADD COLUMN discount_id INT REFERENCES discounts(id) ON DELETE SET NULL;
+
+UPDATE order_items SET original_price = price;
+
+ALTER TABLE order_items ALTER COLUMN original_price SET NOT NULL;
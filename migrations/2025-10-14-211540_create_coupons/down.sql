@@ -0,0 +1,4 @@
+ALTER TABLE orders DROP COLUMN coupon_id;
+ALTER TABLE carts DROP COLUMN coupon_id;
+DROP TABLE coupon_redemptions;
+DROP TABLE coupons;
@@ -0,0 +1,25 @@
+CREATE TABLE coupons (
+  id SERIAL PRIMARY KEY,
+  code VARCHAR(30) NOT NULL UNIQUE,
+  discount_id INT NOT NULL REFERENCES discounts(id) ON DELETE CASCADE,
+  usage_limit INT,
+  per_user_limit INT,
+  min_subtotal float,
+  expires_at TIMESTAMP,
+  is_active BOOLEAN NOT NULL DEFAULT true,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE coupon_redemptions (
+  id SERIAL PRIMARY KEY,
+  coupon_id INT NOT NULL REFERENCES coupons(id) ON DELETE CASCADE,
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  order_id UUID NOT NULL REFERENCES orders(id) ON DELETE CASCADE,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+ALTER TABLE carts
+ADD COLUMN coupon_id INT REFERENCES coupons(id) ON DELETE SET NULL;
+
+ALTER TABLE orders
+ADD COLUMN coupon_id INT REFERENCES coupons(id) ON DELETE SET NULL;
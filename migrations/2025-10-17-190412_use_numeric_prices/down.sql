@@ -0,0 +1,14 @@
+ALTER TABLE coupons
+ALTER COLUMN min_subtotal TYPE float USING min_subtotal::float;
+
+ALTER TABLE order_items
+ALTER COLUMN price TYPE float USING price::float,
+ALTER COLUMN original_price TYPE float USING original_price::float;
+
+ALTER TABLE orders
+DROP COLUMN currency,
+ALTER COLUMN total TYPE float USING total::float;
+
+ALTER TABLE products
+DROP COLUMN currency,
+ALTER COLUMN price TYPE float USING price::float;
@@ -0,0 +1,14 @@
+ALTER TABLE products
+ALTER COLUMN price TYPE NUMERIC USING price::numeric,
+ADD COLUMN currency VARCHAR(3) NOT NULL DEFAULT 'usd';
+
+ALTER TABLE orders
+ALTER COLUMN total TYPE NUMERIC USING total::numeric,
+ADD COLUMN currency VARCHAR(3) NOT NULL DEFAULT 'usd';
+
+ALTER TABLE order_items
+ALTER COLUMN price TYPE NUMERIC USING price::numeric,
+ALTER COLUMN original_price TYPE NUMERIC USING original_price::numeric;
+
+ALTER TABLE coupons
+ALTER COLUMN min_subtotal TYPE NUMERIC USING min_subtotal::numeric;
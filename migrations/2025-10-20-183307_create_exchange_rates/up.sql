@@ -0,0 +1,5 @@
+CREATE TABLE exchange_rates (
+  currency VARCHAR(3) PRIMARY KEY,
+  rate NUMERIC NOT NULL CHECK (rate > 0),
+  updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,4 @@
+ALTER TABLE users
+ADD COLUMN hashed_rt TEXT;
+
+DROP TABLE refresh_tokens;
@@ -0,0 +1,5 @@
+DROP TABLE login_ip_attempts;
+
+ALTER TABLE users
+DROP COLUMN failed_login_attempts,
+DROP COLUMN locked_until;
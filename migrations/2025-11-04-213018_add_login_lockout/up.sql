@@ -0,0 +1,10 @@
+ALTER TABLE users
+ADD COLUMN failed_login_attempts INTEGER NOT NULL DEFAULT 0,
+ADD COLUMN locked_until TIMESTAMP;
+
+CREATE TABLE login_ip_attempts (
+  ip VARCHAR(45) PRIMARY KEY,
+  failed_attempts INTEGER NOT NULL DEFAULT 0,
+  locked_until TIMESTAMP,
+  last_failed_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,18 @@
+UPDATE stock_movements SET reason = 'returned' WHERE reason = 'refunded';
+
+ALTER TYPE stock_reason RENAME TO stock_reason_old;
+
+CREATE TYPE stock_reason AS ENUM (
+  'restock',
+  'correction',
+  'damaged',
+  'returned',
+  'reserved',
+  'released',
+  'sold'
+);
+
+ALTER TABLE stock_movements
+ALTER COLUMN reason TYPE stock_reason USING reason::text::stock_reason;
+
+DROP TYPE stock_reason_old;
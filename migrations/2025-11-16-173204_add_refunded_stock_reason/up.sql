@@ -0,0 +1 @@
+ALTER TYPE stock_reason ADD VALUE 'refunded';
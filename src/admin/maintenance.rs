@@ -0,0 +1,157 @@
+use crate::auth::handlers::publish_user_created;
+use crate::config::Config;
+use crate::discount::handlers::publish_discount_created;
+use crate::discount::models::Discount;
+use crate::order::handlers::publish_status_change;
+use crate::order::models::{Order, OrderStatus};
+use crate::utils::AppError;
+use chrono::{Days, Utc};
+use diesel::prelude::*;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use uuid::Uuid;
+
+/// Rebuilds the full text index behind `/api/search` and refreshes planner stats
+pub async fn reindex_search(
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    diesel::sql_query("REINDEX INDEX products_search_vector_idx")
+        .execute(conn)
+        .await?;
+
+    diesel::sql_query("ANALYZE products").execute(conn).await?;
+
+    println!("Search index rebuilt");
+
+    Ok(())
+}
+
+/// Publishes the event again with the current state of the record
+pub async fn replay_event(
+    kind: &str,
+    id: &str,
+    config: &Config,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    use crate::schema::{discounts, order_status_history, orders, users};
+
+    match kind {
+        "user" => {
+            let email = users::table
+                .filter(users::email.eq(id))
+                .select(users::email)
+                .first::<String>(conn)
+                .await
+                .map_err(|e| not_found(e, "User not found"))?;
+
+            publish_user_created(&config.rmq, &email).await?;
+        }
+        "discount" => {
+            let id: i32 = id
+                .parse()
+                .map_err(|_| AppError::BadRequest(format!("Invalid discount id: {}", id)))?;
+
+            let discount = discounts::table
+                .find(id)
+                .select(Discount::as_select())
+                .first(conn)
+                .await
+                .map_err(|e| not_found(e, "Discount not found"))?;
+
+            publish_discount_created(&config.rmq, &discount).await?;
+        }
+        "order" => {
+            let id: Uuid = id
+                .parse()
+                .map_err(|_| AppError::BadRequest(format!("Invalid order id: {}", id)))?;
+
+            let order = orders::table
+                .find(id)
+                .select(Order::as_select())
+                .first(conn)
+                .await
+                .map_err(|e| not_found(e, "Order not found"))?;
+
+            let from = order_status_history::table
+                .filter(order_status_history::order_id.eq(id))
+                .order(order_status_history::created_at.desc())
+                .select(order_status_history::from_status)
+                .first::<Option<OrderStatus>>(conn)
+                .await
+                .optional()?
+                .flatten()
+                .unwrap_or(OrderStatus::Pending);
+
+            publish_status_change(&config.rmq, &order, from, conn).await?;
+        }
+        _ => {
+            return Err(AppError::BadRequest(format!(
+                "Unknown event kind: {}, expected user, discount or order",
+                kind
+            )));
+        }
+    }
+
+    println!("Replayed {} event for {}", kind, id);
+
+    Ok(())
+}
+
+/// Empties carts not touched for `days` days and drops their coupons
+pub async fn purge_carts(
+    days: Option<&str>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    use crate::schema::{cart_products, carts};
+
+    let days: u64 = match days {
+        Some(days) => days
+            .parse()
+            .map_err(|_| AppError::BadRequest(format!("Invalid number of days: {}", days)))?,
+        None => 30,
+    };
+
+    let cutoff = Utc::now()
+        .date_naive()
+        .checked_sub_days(Days::new(days))
+        .ok_or_else(|| AppError::BadRequest(format!("Invalid number of days: {}", days)))?;
+
+    let (carts_count, items_count) = conn
+        .transaction::<(usize, usize), diesel::result::Error, _>(move |conn| {
+            Box::pin(async move {
+                let cart_ids: Vec<i32> = carts::table
+                    .filter(carts::updated_at.lt(cutoff))
+                    .select(carts::id)
+                    .load(conn)
+                    .await?;
+
+                let items_count = diesel::delete(
+                    cart_products::table.filter(cart_products::cart_id.eq_any(&cart_ids)),
+                )
+                .execute(conn)
+                .await?;
+
+                diesel::update(carts::table.filter(carts::id.eq_any(&cart_ids)))
+                    .set(carts::coupon_id.eq(None::<i32>))
+                    .execute(conn)
+                    .await?;
+
+                Ok((cart_ids.len(), items_count))
+            })
+        })
+        .await?;
+
+    println!(
+        "Purged {} items from {} carts idle since {}",
+        items_count, carts_count, cutoff
+    );
+
+    Ok(())
+}
+
+fn not_found(err: diesel::result::Error, message: &str) -> AppError {
+    match err {
+        diesel::result::Error::NotFound => AppError::NotFound(message.to_owned()),
+        e => e.into(),
+    }
+}
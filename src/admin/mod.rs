@@ -0,0 +1,62 @@
+mod maintenance;
+mod products;
+mod users;
+
+use crate::config::Config;
+use crate::pool::get_pool;
+use std::io::BufRead;
+
+pub const USAGE: &str = "usage: shop-admin <command>
+
+  create-admin <email>                    password is read from stdin
+  set-role <email> <user|seller|admin>
+  reset-password <email>                  password is read from stdin
+  reindex-search
+  export-products [file]                  JSON, to stdout without a file
+  import-products <file>                  creates every product in the file
+  replay-event user <email>
+  replay-event discount <id>
+  replay-event order <id>
+  purge-carts [days]                      empties carts idle for 30 days by default";
+
+pub async fn run(args: &[String], config: &Config) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let pool = get_pool(&config.database.url).await?;
+    let mut conn = pool
+        .get()
+        .await
+        .map_err(|e| format!("Failed to get a connection: {}", e))?;
+
+    let res = match args.as_slice() {
+        ["create-admin", email] => users::create_admin(email, &read_password()?, &mut conn).await,
+        ["set-role", email, role] => users::set_role(email, role, &mut conn).await,
+        ["reset-password", email] => {
+            users::reset_password(email, &read_password()?, &mut conn).await
+        }
+        ["reindex-search"] => maintenance::reindex_search(&mut conn).await,
+        ["export-products"] => products::export(None, &mut conn).await,
+        ["export-products", file] => products::export(Some(file), &mut conn).await,
+        ["import-products", file] => products::import(file, &mut conn).await,
+        ["replay-event", kind, id] => maintenance::replay_event(kind, id, config, &mut conn).await,
+        ["purge-carts"] => maintenance::purge_carts(None, &mut conn).await,
+        ["purge-carts", days] => maintenance::purge_carts(Some(days), &mut conn).await,
+        _ => return Err(USAGE.to_owned()),
+    };
+
+    res.map_err(|e| e.to_string())
+}
+
+/// First line of stdin, so passwords stay out of the shell history
+fn read_password() -> Result<String, String> {
+    eprint!("Password: ");
+
+    let mut password = String::new();
+
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|e| format!("Failed to read the password: {}", e))?;
+
+    Ok(password.trim_end_matches(['\r', '\n']).to_owned())
+}
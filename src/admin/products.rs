@@ -0,0 +1,153 @@
+use crate::discount::pricing::get_product_categories;
+use crate::product::models::{NewProduct, NewStockMovement, Product, ProductCategory, StockReason};
+use crate::utils::{AppError, Money};
+use diesel::prelude::*;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+#[derive(Serialize, Debug)]
+struct ExportedProduct {
+    id: i32,
+    title: String,
+    price: Money,
+    description: String,
+    image: Option<String>,
+    /// `null` while the stock is not tracked
+    stock: Option<i32>,
+    category_ids: Vec<i32>,
+}
+
+/// Same shape as an export, `id` is ignored since every entry becomes a new product.
+/// A missing or `null` stock imports the product untracked.
+#[derive(Deserialize)]
+struct ImportedProduct {
+    #[serde(flatten)]
+    product: NewProduct,
+    #[serde(default)]
+    stock: Option<i32>,
+    #[serde(default)]
+    category_ids: Vec<i32>,
+}
+
+pub async fn export(
+    file: Option<&str>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    use crate::schema::products;
+
+    let products = products::table
+        .select(Product::as_select())
+        .order(products::id.asc())
+        .load(conn)
+        .await?;
+
+    let ids: Vec<i32> = products.iter().map(|product| product.id).collect();
+    let mut categories = get_product_categories(&ids, conn).await?;
+
+    let exported: Vec<ExportedProduct> = products
+        .into_iter()
+        .map(|product| ExportedProduct {
+            category_ids: categories.remove(&product.id).unwrap_or_default(),
+            id: product.id,
+            title: product.title,
+            price: product.price,
+            description: product.description,
+            image: product.image,
+            stock: product.stock,
+        })
+        .collect();
+
+    let json = serde_json::to_string_pretty(&exported)?;
+
+    match file {
+        Some(file) => {
+            std::fs::write(file, json)?;
+            eprintln!("Exported {} products to {}", exported.len(), file);
+        }
+        None => println!("{}", json),
+    }
+
+    Ok(())
+}
+
+/// All or nothing: one invalid entry rolls back the whole file
+pub async fn import(
+    file: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    use crate::schema::{product_categories, products, stock_movements};
+
+    let entries: Vec<ImportedProduct> = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+
+    for (index, entry) in entries.iter().enumerate() {
+        entry
+            .product
+            .validate()
+            .map_err(|e| AppError::BadRequest(format!("Product {}: {}", index + 1, e)))?;
+
+        if entry.stock.is_some_and(|stock| stock < 0) {
+            return Err(AppError::BadRequest(format!(
+                "Product {}: stock cannot be negative",
+                index + 1
+            )));
+        }
+    }
+
+    let count = entries.len();
+
+    conn.transaction::<(), diesel::result::Error, _>(move |conn| {
+        Box::pin(async move {
+            for entry in entries {
+                let id = diesel::insert_into(products::table)
+                    .values(&entry.product)
+                    .returning(products::id)
+                    .get_result::<i32>(conn)
+                    .await?;
+
+                diesel::update(products::table.find(id))
+                    .set(products::stock.eq(entry.stock))
+                    .execute(conn)
+                    .await?;
+
+                if let Some(stock) = entry.stock.filter(|stock| *stock > 0) {
+                    let movement = NewStockMovement {
+                        product_id: id,
+                        quantity: stock,
+                        reason: StockReason::Restock,
+                        note: Some("Imported".to_owned()),
+                        order_id: None,
+                        created_by: None,
+                    };
+
+                    diesel::insert_into(stock_movements::table)
+                        .values(&movement)
+                        .execute(conn)
+                        .await?;
+                }
+
+                let links: Vec<ProductCategory> = entry
+                    .category_ids
+                    .iter()
+                    .map(|category_id| ProductCategory {
+                        product_id: id,
+                        category_id: *category_id,
+                    })
+                    .collect();
+
+                diesel::insert_into(product_categories::table)
+                    .values(&links)
+                    .execute(conn)
+                    .await?;
+            }
+
+            Ok(())
+        })
+    })
+    .await?;
+
+    println!("Imported {} products", count);
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use crate::auth::handlers::{create_hash, insert_user, revoke_user_sessions};
+use crate::auth::models::{NewUser, SafeUser, User, UserRole};
+use crate::utils::AppError;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use uuid::Uuid;
+use validator::Validate;
+
+/// Admins created here skip email verification
+pub async fn create_admin(
+    email: &str,
+    password: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    let payload = NewUser {
+        email: email.to_owned(),
+        password: password.to_owned(),
+    };
+
+    payload
+        .validate()
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let user_data = User {
+        id: Uuid::new_v4(),
+        email: payload.email,
+        password_hash: create_hash(payload.password).await?,
+        role: UserRole::Admin.as_str().to_owned(),
+        email_verified_at: Some(Utc::now().naive_utc()),
+        verification_sent_at: None,
+        failed_login_attempts: 0,
+        locked_until: None,
+        totp_secret: None,
+        totp_enabled_at: None,
+        totp_last_step: None,
+        password_reset_sent_at: None,
+    };
+
+    let user = conn
+        .transaction::<SafeUser, diesel::result::Error, _>(move |conn| {
+            Box::pin(async move { insert_user(&user_data, conn).await })
+        })
+        .await?;
+
+    println!("Created admin {} ({})", user.email, user.id);
+
+    Ok(())
+}
+
+/// Signs the user out everywhere so the new role is in the next token
+pub async fn set_role(
+    email: &str,
+    role: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    use crate::schema::users;
+
+    let role: UserRole = role
+        .parse()
+        .map_err(|_| AppError::BadRequest(format!("Unknown role: {}", role)))?;
+
+    let user = diesel::update(users::table.filter(users::email.eq(email)))
+        .set(users::role.eq(role.as_str()))
+        .returning(SafeUser::as_returning())
+        .get_result(conn)
+        .await
+        .map_err(user_not_found)?;
+
+    revoke_user_sessions(&user.id, conn).await?;
+
+    println!("{} is now {}", user.email, user.role);
+
+    Ok(())
+}
+
+/// Also lifts a login lockout and signs the user out everywhere
+pub async fn reset_password(
+    email: &str,
+    password: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    use crate::schema::users;
+
+    let payload = NewUser {
+        email: email.to_owned(),
+        password: password.to_owned(),
+    };
+
+    payload
+        .validate()
+        .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+    let password_hash = create_hash(payload.password).await?;
+
+    let user = conn
+        .transaction::<SafeUser, diesel::result::Error, _>(move |conn| {
+            Box::pin(async move {
+                let user = diesel::update(users::table.filter(users::email.eq(&payload.email)))
+                    .set((
+                        users::password_hash.eq(&password_hash),
+                        users::failed_login_attempts.eq(0),
+                        users::locked_until.eq(None::<NaiveDateTime>),
+                    ))
+                    .returning(SafeUser::as_returning())
+                    .get_result(conn)
+                    .await?;
+
+                revoke_user_sessions(&user.id, conn).await?;
+
+                Ok(user)
+            })
+        })
+        .await
+        .map_err(user_not_found)?;
+
+    println!("Password of {} has been reset", user.email);
+
+    Ok(())
+}
+
+fn user_not_found(err: diesel::result::Error) -> AppError {
+    match err {
+        diesel::result::Error::NotFound => AppError::NotFound("User not found".to_owned()),
+        e => e.into(),
+    }
+}
@@ -0,0 +1,128 @@
+use super::models::{AccessTokenClaims, ApiKey, AuthError};
+use super::rbac::Permission;
+use crate::utils::types::Pool;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{Duration, Utc};
+use data_encoding::HEXLOWER;
+use diesel::prelude::*;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+const KEY_PREFIX: &str = "sk_";
+
+/// Leading characters of a key kept in plain text so users can tell keys apart
+const DISPLAY_PREFIX_LEN: usize = 11;
+
+/// `last_used_at` is written at most once per this many seconds per key
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+pub fn generate_key() -> String {
+    format!(
+        "{}{}{}",
+        KEY_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    )
+}
+
+pub fn display_prefix(key: &str) -> String {
+    key.chars().take(DISPLAY_PREFIX_LEN).collect()
+}
+
+/// Keys are random, so an unsalted SHA-256 is enough and lets them be looked up by hash
+pub fn hash_key(key: &str) -> String {
+    HEXLOWER.encode(&Sha256::digest(key.as_bytes()))
+}
+
+/// Resolves the `X-Api-Key` header into the owner's `AccessTokenClaims` and
+/// stores them in the request extensions, where the `AccessTokenClaims`
+/// extractor picks them up. Requests without the header pass through.
+pub async fn authenticate(
+    State(pool): State<Pool>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let Some(key) = req.headers().get(API_KEY_HEADER) else {
+        return Ok(next.run(req).await);
+    };
+
+    let key = key
+        .to_str()
+        .map_err(|_| AuthError::InvalidToken)?
+        .to_owned();
+
+    let mut conn = pool.get().await.map_err(|_| AuthError::FailedTask)?;
+
+    let claims = resolve(&key, &mut conn).await?;
+
+    req.extensions_mut().insert(claims);
+
+    Ok(next.run(req).await)
+}
+
+/// The role is read from the user on every request, so role changes apply to existing keys
+async fn resolve(
+    key: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<AccessTokenClaims, AuthError> {
+    use crate::schema::{api_keys, users};
+
+    let now = Utc::now().naive_utc();
+
+    let (api_key, (email, role)) = api_keys::table
+        .inner_join(users::table)
+        .filter(api_keys::key_hash.eq(hash_key(key)))
+        .filter(api_keys::revoked_at.is_null())
+        .select((ApiKey::as_select(), (users::email, users::role)))
+        .first::<(ApiKey, (String, String))>(conn)
+        .await
+        .optional()
+        .map_err(|_| AuthError::FailedTask)?
+        .ok_or(AuthError::InvalidToken)?;
+
+    if api_key
+        .expires_at
+        .is_some_and(|expires_at| expires_at < now)
+    {
+        return Err(AuthError::InvalidToken);
+    }
+
+    let stale = now - Duration::seconds(LAST_USED_RESOLUTION_SECONDS);
+
+    diesel::update(api_keys::table.find(&api_key.id))
+        .filter(
+            api_keys::last_used_at
+                .is_null()
+                .or(api_keys::last_used_at.lt(stale)),
+        )
+        .set(api_keys::last_used_at.eq(now))
+        .execute(conn)
+        .await
+        .map_err(|_| AuthError::FailedTask)?;
+
+    let scopes = api_key
+        .scopes
+        .iter()
+        .filter_map(|scope| scope.parse::<Permission>().ok())
+        .collect();
+
+    Ok(AccessTokenClaims {
+        sub: api_key.user_id.to_string(),
+        email,
+        role,
+        exp: api_key.expires_at.map_or(usize::MAX, |expires_at| {
+            expires_at.and_utc().timestamp() as usize
+        }),
+        sid: None,
+        api_key_id: Some(api_key.id.to_string()),
+        scopes: Some(scopes),
+    })
+}
@@ -1,449 +1,1860 @@
 #![allow(dead_code, unused)]
+use super::api_keys::{display_prefix, generate_key, hash_key};
+use super::keys::JwtKeys;
+use super::lockout;
 use super::models::{
-    AccessToken, AccessTokenClaims, AuthError, LoginUser, NewRefreshToken, NewUser,
-    RefreshTokenClaims, SafeUser, SafeUserWithCart, Tokens, UpdateUser, UpdateUserPayload, User,
-    UserEmail,
+    AccessToken, AccessTokenClaims, ApiKey, ApiKeyInfo, AuthError, CreateApiKey, CreatedApiKey,
+    EmailVerificationClaims, ForgotPassword, JwkSet, LoginResponse, LoginUser, MfaChallenge,
+    MfaCode, MfaEnrollingUser, MfaEnrollment, MfaPendingClaims, MfaPolicy, MfaRecoveryCodes,
+    NewApiKey, NewMfaRecoveryCode, NewOidcState, NewPasswordResetToken, NewRefreshToken, NewUser,
+    NewUserIdentity, OidcCallback, OidcState, PasswordResetToken, RefreshToken, RefreshTokenClaims,
+    ResetPassword, SafeUser, SafeUserWithCart, Session, Tokens, UpdateMfaPolicy, UpdateUser,
+    UpdateUserPayload, UpdateUserRole, User, UserEmail, UserRole, VerifyEmail, VerifyMfa,
 };
-use crate::utils::internal_error;
+use super::oidc::{self, IdTokenClaims, OidcError, OidcSettings};
+use super::totp;
+use crate::config::{AuthConfig, Config, RmqConfig};
+use crate::utils::AppError;
+use crate::utils::ValidatedJson;
 use crate::utils::types::Pool;
 use axum::RequestPartsExt;
-use axum::extract::FromRequestParts;
+use axum::extract::{ConnectInfo, FromRef, FromRequestParts};
 use axum::http::HeaderMap;
+use axum::http::header::{HeaderName, SET_COOKIE};
 use axum::http::request::Parts;
 use axum::{
-    extract::{Json, Path, State},
+    extract::{Json, Path, Query, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Redirect},
 };
 use axum_extra::TypedHeader;
-use axum_extra::headers::Authorization;
 use axum_extra::headers::authorization::Bearer;
-use axum_validated_extractors::ValidatedJson;
+use axum_extra::headers::{Authorization, Cookie};
 use bcrypt::{BcryptError, BcryptResult, DEFAULT_COST, hash, verify};
 use chrono::{Duration, Local, TimeZone, Utc};
 use diesel::dsl::sql;
 use diesel::{prelude::*, update};
-use diesel_async::{AsyncConnection, RunQueryDsl};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, TokenData, Validation, decode, encode};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use std::env;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
 use std::time::Instant;
 use uuid::Uuid;
 
 const QUEUE_NAME: &str = "user";
 
+const VERIFY_EMAIL_PURPOSE: &str = "verify_email";
+
+/// How long a verification link stays valid
+const EMAIL_VERIFICATION_TTL_HOURS: i64 = 24;
+
+/// Minimum delay between two verification emails for the same user
+const VERIFICATION_RESEND_SECONDS: i64 = 60;
+
+/// How long a password reset link stays valid
+const PASSWORD_RESET_TTL_MINUTES: i64 = 30;
+
+/// Minimum delay between two password reset emails for the same address
+const PASSWORD_RESET_RESEND_SECONDS: i64 = 60;
+
+const MFA_LOGIN_PURPOSE: &str = "mfa_login";
+
+const MFA_ENROLL_PURPOSE: &str = "mfa_enroll";
+
+/// Time to finish the second login step
+const MFA_TOKEN_TTL_MINUTES: i64 = 5;
+
+/// Time to come back from the identity provider
+const OIDC_STATE_TTL_MINUTES: i64 = 10;
+
+diesel::define_sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
 pub async fn create_user(
     State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
     ValidatedJson(payload): ValidatedJson<NewUser>,
-) -> Result<Json<SafeUser>, (StatusCode, String)> {
+) -> Result<Json<SafeUser>, AppError> {
+    let mut conn = pool.get().await?;
+
+    let hashed_pass = create_hash(payload.password).await?;
+
+    let user_data = User {
+        id: Uuid::new_v4(),
+        email: payload.email,
+        password_hash: hashed_pass,
+        role: "user".to_owned(),
+        email_verified_at: None,
+        verification_sent_at: Some(Utc::now().naive_utc()),
+        failed_login_attempts: 0,
+        locked_until: None,
+        totp_secret: None,
+        totp_enabled_at: None,
+        totp_last_step: None,
+        password_reset_sent_at: None,
+    };
+
+    let res = conn
+        .transaction::<SafeUser, diesel::result::Error, _>(move |conn| {
+            Box::pin(async move { insert_user(&user_data, conn).await })
+        })
+        .await?;
+
+    if let Err(er) = publish_user_created(&config.rmq, &res.email).await {
+        eprintln!("Failed to publish event: {:?}", er);
+    }
+
+    if let Err(er) = send_verification_email(&res.id, &res.email, &config).await {
+        eprintln!("Failed to send verification email: {:?}", er);
+    }
+
+    Ok(Json(res))
+}
+
+/// Inserts the user together with the cart, notification subscriptions and
+/// profile every account starts with. Run it inside a transaction.
+pub async fn insert_user(
+    user_data: &User,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<SafeUser, diesel::result::Error> {
     use crate::cart::models::NewCart;
     use crate::notification::models::UserSubscriptions;
     use crate::user::models::Profile;
 
-    use axum_shop::schema::{carts, profiles, user_subscriptions, users};
+    use crate::schema::{carts, profiles, user_subscriptions, users};
+
+    let user_id = user_data.id;
+
+    let user = diesel::insert_into(users::table)
+        .values(user_data)
+        .returning(SafeUser::as_returning())
+        .get_result(conn)
+        .await?;
+
+    let updated_at = Local::now().date_naive();
+
+    let cart_data = NewCart {
+        user_id,
+        updated_at,
+    };
+
+    let subs_data = UserSubscriptions {
+        user_id,
+        channel: "email".to_owned(),
+        orders_notifications: true,
+        discount_notifications: true,
+        newsletter_notifications: true,
+    };
+
+    let profile_data = Profile {
+        id: Uuid::new_v4(),
+        user_id,
+        first_name: None,
+        last_name: None,
+        phone_number: None,
+        birth_date: None,
+        language: "en".to_owned(),
+        currency: "usd".to_owned(),
+    };
+
+    diesel::insert_into(carts::table)
+        .values(&cart_data)
+        .execute(conn)
+        .await?;
+
+    diesel::insert_into(user_subscriptions::table)
+        .values(&subs_data)
+        .execute(conn)
+        .await?;
+
+    diesel::insert_into(profiles::table)
+        .values(&profile_data)
+        .execute(conn)
+        .await?;
+
+    Ok(user)
+}
+
+pub async fn publish_user_created(rmq: &RmqConfig, email: &str) -> Result<(), AppError> {
+    let event = serde_json::json!({
+        "type": "WelcomeUser",
+        "event": "user_created",
+        "email": email,
+    })
+    .to_string();
+
+    crate::rmq::client::publish_event(rmq, QUEUE_NAME, &event).await
+}
+
+pub async fn verify_email(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    ValidatedJson(payload): ValidatedJson<VerifyEmail>,
+) -> Result<Json<SafeUser>, AppError> {
+    use crate::schema::users;
+
+    let invalid_token = || AppError::BadRequest("Invalid or expired verification token".to_owned());
+
+    let claims =
+        decode_token::<EmailVerificationClaims>(&payload.token, &config.auth.refresh_secret)
+            .await
+            .map_err(|_| invalid_token())?
+            .claims;
+
+    if claims.purpose != VERIFY_EMAIL_PURPOSE {
+        return Err(invalid_token());
+    }
+
+    let id = Uuid::parse_str(&claims.sub).map_err(|_| invalid_token())?;
+
+    let mut conn = pool.get().await?;
+
+    // Links sent before an email change point to the old address and stop working
+    let user = users::table
+        .find(&id)
+        .filter(users::email.eq(&claims.email))
+        .select(SafeUser::as_select())
+        .first(&mut conn)
+        .await
+        .optional()?
+        .ok_or_else(invalid_token)?;
+
+    if user.email_verified_at.is_some() {
+        return Ok(Json(user));
+    }
+
+    let res = diesel::update(users::table.find(&id))
+        .set(users::email_verified_at.eq(Utc::now().naive_utc()))
+        .returning(SafeUser::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+pub async fn resend_verification_email(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    claims: AccessTokenClaims,
+) -> Result<(), AppError> {
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
+
+    let id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
+
+    let now = Utc::now().naive_utc();
+    let threshold = now - Duration::seconds(VERIFICATION_RESEND_SECONDS);
+
+    let user = diesel::update(
+        users::table
+            .find(&id)
+            .filter(users::email_verified_at.is_null())
+            .filter(
+                users::verification_sent_at
+                    .is_null()
+                    .or(users::verification_sent_at.lt(threshold)),
+            ),
+    )
+    .set(users::verification_sent_at.eq(now))
+    .returning(SafeUser::as_returning())
+    .get_result(&mut conn)
+    .await
+    .optional()?;
+
+    let Some(user) = user else {
+        let verified = users::table
+            .find(&id)
+            .select(users::email_verified_at.is_not_null())
+            .get_result::<bool>(&mut conn)
+            .await?;
+
+        if verified {
+            return Err(AppError::Conflict("Email is already verified".to_owned()));
+        }
+
+        return Err(AppError::TooManyRequests(
+            "Please wait before requesting another verification email".to_owned(),
+        ));
+    };
+
+    send_verification_email(&user.id, &user.email, &config).await
+}
+
+/// Always answers the same way and does the work in the background,
+/// so the response does not reveal whether the email is registered
+pub async fn forgot_password(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    ValidatedJson(payload): ValidatedJson<ForgotPassword>,
+) -> StatusCode {
+    tokio::spawn(async move {
+        if let Err(er) = send_password_reset(&payload.email, &pool, &config).await {
+            eprintln!("Failed to send password reset: {:?}", er);
+        }
+    });
+
+    StatusCode::ACCEPTED
+}
+
+pub async fn reset_password(
+    State(pool): State<Pool>,
+    ValidatedJson(payload): ValidatedJson<ResetPassword>,
+) -> Result<(), AppError> {
+    use crate::schema::{password_reset_tokens, users};
+
+    let invalid_token = || AppError::BadRequest("Invalid or expired reset token".to_owned());
+
+    let (token_id, secret) = payload.token.split_once('.').ok_or_else(invalid_token)?;
+    let token_id = Uuid::parse_str(token_id).map_err(|_| invalid_token())?;
+
+    let mut conn = pool.get().await?;
+
+    let token = password_reset_tokens::table
+        .find(&token_id)
+        .filter(password_reset_tokens::used_at.is_null())
+        .filter(password_reset_tokens::expires_at.gt(Utc::now().naive_utc()))
+        .select(PasswordResetToken::as_select())
+        .first(&mut conn)
+        .await
+        .optional()?
+        .ok_or_else(invalid_token)?;
+
+    if !validate_hash(secret.to_owned(), token.token_hash.clone()).await? {
+        return Err(invalid_token());
+    }
+
+    let password_hash = create_hash(payload.password).await?;
+
+    conn.transaction::<(), diesel::result::Error, _>(move |mut conn| {
+        Box::pin(async move {
+            let now = Utc::now().naive_utc();
+
+            // Single use: a concurrent request with the same token finds nothing to update
+            diesel::update(
+                password_reset_tokens::table
+                    .find(&token.id)
+                    .filter(password_reset_tokens::used_at.is_null()),
+            )
+            .set(password_reset_tokens::used_at.eq(now))
+            .returning(password_reset_tokens::id)
+            .get_result::<Uuid>(&mut conn)
+            .await?;
+
+            // Other links sent to the user are no longer needed
+            diesel::update(
+                password_reset_tokens::table
+                    .filter(password_reset_tokens::user_id.eq(&token.user_id))
+                    .filter(password_reset_tokens::used_at.is_null()),
+            )
+            .set(password_reset_tokens::used_at.eq(now))
+            .execute(&mut conn)
+            .await?;
+
+            diesel::update(users::table.find(&token.user_id))
+                .set(users::password_hash.eq(&password_hash))
+                .execute(&mut conn)
+                .await?;
+
+            revoke_user_sessions(&token.user_id, conn).await?;
+
+            Ok(())
+        })
+    })
+    .await
+    .map_err(|e| match e {
+        diesel::result::Error::NotFound => invalid_token(),
+        e => e.into(),
+    })?;
+
+    Ok(())
+}
+
+/// Reset tokens look like `<row id>.<secret>`, only a hash of the secret is stored
+async fn send_password_reset(email: &str, pool: &Pool, config: &Config) -> Result<(), AppError> {
+    use crate::schema::{password_reset_tokens, users};
+
+    let mut conn = pool.get().await?;
+
+    let now = Utc::now().naive_utc();
+    let threshold = now - Duration::seconds(PASSWORD_RESET_RESEND_SECONDS);
+
+    // Unknown and throttled addresses are skipped alike, the caller never learns which
+    let Some(user_id) = diesel::update(
+        users::table.filter(users::email.eq(email)).filter(
+            users::password_reset_sent_at
+                .is_null()
+                .or(users::password_reset_sent_at.lt(threshold)),
+        ),
+    )
+    .set(users::password_reset_sent_at.eq(now))
+    .returning(users::id)
+    .get_result::<Uuid>(&mut conn)
+    .await
+    .optional()?
+    else {
+        return Ok(());
+    };
+
+    let token_id = Uuid::new_v4();
+    let secret = Uuid::new_v4().simple().to_string();
+
+    let new_token = NewPasswordResetToken {
+        id: token_id,
+        user_id,
+        token_hash: create_hash(secret.clone()).await?,
+        expires_at: (Utc::now() + Duration::minutes(PASSWORD_RESET_TTL_MINUTES)).naive_utc(),
+    };
+
+    diesel::insert_into(password_reset_tokens::table)
+        .values(&new_token)
+        .execute(&mut conn)
+        .await?;
+
+    let event = serde_json::json!({
+        "type": "PasswordReset",
+        "event": "password_reset_requested",
+        "email": email,
+        "reset_url": format!(
+            "{}/reset-password?token={}.{}",
+            config.server.app_url, token_id, secret
+        ),
+    })
+    .to_string();
+
+    crate::rmq::client::publish_event(&config.rmq, QUEUE_NAME, &event).await
+}
+
+async fn send_verification_email(
+    user_id: &Uuid,
+    email: &str,
+    config: &Config,
+) -> Result<(), AppError> {
+    let claims = EmailVerificationClaims {
+        sub: user_id.to_string(),
+        email: email.to_owned(),
+        purpose: VERIFY_EMAIL_PURPOSE.to_owned(),
+        exp: (Utc::now() + Duration::hours(EMAIL_VERIFICATION_TTL_HOURS)).timestamp() as usize,
+    };
+
+    let token = encode_token(claims, &config.auth.refresh_secret).await?;
+
+    let event = serde_json::json!({
+        "type": "VerifyEmail",
+        "event": "verification_requested",
+        "email": email,
+        "verify_url": format!("{}/verify-email?token={}", config.server.app_url, token),
+        "token": token,
+    })
+    .to_string();
+
+    crate::rmq::client::publish_event(&config.rmq, QUEUE_NAME, &event).await
+}
+
+pub async fn get_user_by_id(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<SafeUser>, AppError> {
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
+
+    let res = users::table
+        .filter(users::id.eq(&id))
+        .select(SafeUser::as_select())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+pub async fn get_user_by_email(
+    State(pool): State<Pool>,
+    ValidatedJson(payload): ValidatedJson<UserEmail>,
+) -> Result<Json<SafeUser>, AppError> {
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
+
+    let res = users::table
+        .filter(users::email.eq(&payload.email))
+        .select(SafeUser::as_select())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+pub async fn delete_user(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<SafeUser>, AppError> {
+    use crate::schema::carts;
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
+
+    diesel::delete(carts::table.filter(carts::user_id.eq(&id)))
+        .execute(&mut conn)
+        .await?;
+
+    let res = diesel::delete(users::table.find(&id))
+        .returning(SafeUser::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+/// Role changes apply on the next login, every session is revoked to force it
+pub async fn update_user_role(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<UpdateUserRole>,
+) -> Result<Json<SafeUser>, AppError> {
+    use crate::schema::users;
+
+    if claims.sub == id.to_string() {
+        return Err(AppError::BadRequest(
+            "You cannot change your own role".to_owned(),
+        ));
+    }
+
+    let mut conn = pool.get().await?;
+
+    let res = diesel::update(users::table.find(&id))
+        .set(users::role.eq(payload.role.as_str()))
+        .returning(SafeUser::as_returning())
+        .get_result(&mut conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("User not found".to_owned()),
+            e => e.into(),
+        })?;
+
+    revoke_user_sessions(&id, &mut conn).await?;
+
+    Ok(Json(res))
+}
+
+pub async fn update_user_email_or_password(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    Path(id): Path<Uuid>,
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<UpdateUserPayload>,
+) -> Result<Json<SafeUser>, AppError> {
+    use crate::schema::users;
+    let now = Instant::now();
+
+    claims.authorize_owner(&id)?;
+
+    let mut conn = pool.get().await?;
+
+    let user = users::table
+        .find(id)
+        .select(User::as_select())
+        .get_result(&mut conn)
+        .await?;
+
+    if payload.email.is_none() && payload.new_password.is_none() {
+        return Err(AppError::BadRequest(
+            "At least one field to update must be provided".to_owned(),
+        ));
+    }
+
+    if payload.new_password.is_some() && payload.current_password.is_none() {
+        return Err(AppError::Unauthorized(
+            "Current password is required to update password".to_owned(),
+        ));
+    }
+
+    let mut new_hash: Option<String> = None;
+
+    if let (Some(cur), Some(new)) = (payload.current_password, payload.new_password) {
+        new_hash = Some(create_hash(new).await?);
+
+        if !validate_hash(cur, user.password_hash).await? {
+            return Err(AppError::Unauthorized("Invalid password".to_owned()));
+        }
+    };
+
+    let email_changed = payload
+        .email
+        .as_ref()
+        .is_some_and(|email| *email != user.email);
+
+    let updated_user = UpdateUser {
+        email: payload.email,
+        password_hash: new_hash,
+    };
+
+    println!("updated user: {:?}", updated_user);
+
+    let mut res = diesel::update(users::table.find(&id))
+        .set(&updated_user)
+        .returning(SafeUser::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    // A new address has to be verified again
+    if email_changed {
+        res = diesel::update(users::table.find(&id))
+            .set((
+                users::email_verified_at.eq(None::<chrono::NaiveDateTime>),
+                users::verification_sent_at.eq(Utc::now().naive_utc()),
+            ))
+            .returning(SafeUser::as_returning())
+            .get_result(&mut conn)
+            .await?;
+
+        if let Err(er) = send_verification_email(&res.id, &res.email, &config).await {
+            eprintln!("Failed to send verification email: {:?}", er);
+        }
+    }
+
+    println!("Time: {:.2?}", now.elapsed());
+    Ok(Json(res))
+}
+
+pub async fn get_all_users(
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<SafeUserWithCart>>, AppError> {
+    use crate::cart::models::SafeCart;
+    use crate::schema::carts;
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let rows = users::table
+        .inner_join(carts::table)
+        .select((SafeUser::as_select(), SafeCart::as_select()))
+        .load::<(SafeUser, SafeCart)>(&mut conn)
+        .await?;
 
-    let hashed_pass = create_hash(payload.password_hash).await?;
+    let res = rows
+        .into_iter()
+        .map(|(user, cart)| SafeUserWithCart { user, cart })
+        .collect();
+
+    Ok(Json(res))
+}
+
+pub async fn get_current_user(
+    State(pool): State<Pool>,
+    claims: AccessTokenClaims,
+) -> Result<Json<SafeUserWithCart>, AppError> {
+    use crate::cart::models::SafeCart;
+    use crate::schema::carts;
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
+
+    let user_id = Uuid::parse_str(&claims.sub).unwrap();
+
+    let (user, cart) = users::table
+        .filter(users::id.eq(&user_id))
+        .inner_join(carts::table)
+        .select((SafeUser::as_select(), SafeCart::as_select()))
+        .get_result::<(SafeUser, SafeCart)>(&mut conn)
+        .await?;
+
+    let res = SafeUserWithCart { user, cart };
+
+    Ok(Json(res))
+}
+
+/// Unknown emails, wrong passwords and locked accounts all get the same
+/// `WrongCredentials` response so the endpoint does not reveal which accounts exist.
+pub async fn login_user(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    State(keys): State<Arc<JwtKeys>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ValidatedJson(payload): ValidatedJson<LoginUser>,
+) -> Result<Json<LoginResponse>, AppError> {
+    use crate::schema::users;
+    let now = Instant::now();
+    let mut conn = pool.get().await?;
+
+    let ip = lockout::client_ip(addr, &headers, config.server.client_ip_header.as_deref());
+
+    if lockout::ip_locked(&ip, &mut conn).await? {
+        return Err(AuthError::TooManyAttempts.into());
+    }
+
+    let user = users::table
+        .filter(users::email.eq(payload.email))
+        .select(User::as_select())
+        .first(&mut conn)
+        .await
+        .optional()?;
+
+    let Some(user) = user else {
+        // Spend the same time as a real check so response times don't leak which emails exist
+        validate_hash(payload.password, dummy_hash().await?).await?;
+
+        lockout::record_failure(None, &ip, &mut conn).await?;
+
+        return Err(AuthError::WrongCredentials.into());
+    };
+
+    if lockout::is_locked(user.locked_until) {
+        // Same work as a wrong password, an early return would time as "this account exists"
+        validate_hash(payload.password, user.password_hash.clone()).await?;
+
+        lockout::record_failure(None, &ip, &mut conn).await?;
+
+        return Err(AuthError::WrongCredentials.into());
+    }
+
+    if !validate_hash(payload.password, user.password_hash.clone()).await? {
+        lockout::record_failure(Some(&user.id), &ip, &mut conn).await?;
+
+        return Err(AuthError::WrongCredentials.into());
+    }
+
+    lockout::record_success(&user.id, &mut conn).await?;
+
+    if let Some(challenge) = mfa_challenge(&user, &config.auth, &mut conn).await? {
+        return Ok(Json(LoginResponse::MfaRequired(challenge)));
+    }
+
+    let tokens = issue_tokens(
+        &user,
+        &Uuid::new_v4(),
+        user_agent(&headers),
+        &config.auth,
+        &keys,
+        &mut conn,
+    )
+    .await?;
+
+    println!("Time: {:.2?}", now.elapsed());
+
+    Ok(Json(LoginResponse::Tokens(tokens)))
+}
+
+/// Lets an admin clear the failed login counter and lock of an account
+pub async fn unlock_user(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<SafeUser>, AppError> {
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
+
+    let res = diesel::update(users::table.find(&id))
+        .set((
+            users::failed_login_attempts.eq(0),
+            users::locked_until.eq(None::<chrono::NaiveDateTime>),
+        ))
+        .returning(SafeUser::as_returning())
+        .get_result(&mut conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("User not found".to_owned()),
+            e => e.into(),
+        })?;
+
+    Ok(Json(res))
+}
+
+/// Second login step, trades the pending token and a TOTP or recovery code for a session
+pub async fn verify_mfa(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    State(keys): State<Arc<JwtKeys>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ValidatedJson(payload): ValidatedJson<VerifyMfa>,
+) -> Result<Json<Tokens>, AppError> {
+    use crate::schema::users;
+
+    let invalid_token = || AppError::Unauthorized("Invalid or expired MFA token".to_owned());
+
+    let mut conn = pool.get().await?;
+
+    let ip = lockout::client_ip(addr, &headers, config.server.client_ip_header.as_deref());
+
+    if lockout::ip_locked(&ip, &mut conn).await? {
+        return Err(AuthError::TooManyAttempts.into());
+    }
+
+    let id = decode_mfa_token(
+        &payload.mfa_token,
+        MFA_LOGIN_PURPOSE,
+        &config.auth.refresh_secret,
+    )
+    .await
+    .map_err(|_| invalid_token())?;
+
+    let user = users::table
+        .find(&id)
+        .select(User::as_select())
+        .first(&mut conn)
+        .await
+        .optional()?
+        .ok_or_else(invalid_token)?;
+
+    if lockout::is_locked(user.locked_until) {
+        return Err(AuthError::TooManyAttempts.into());
+    }
+
+    if !consume_mfa_code(&user, &payload.code, &mut conn).await? {
+        lockout::record_failure(Some(&user.id), &ip, &mut conn).await?;
+
+        return Err(AppError::Unauthorized("Invalid code".to_owned()));
+    }
+
+    lockout::record_success(&user.id, &mut conn).await?;
+
+    let tokens = issue_tokens(
+        &user,
+        &Uuid::new_v4(),
+        user_agent(&headers),
+        &config.auth,
+        &keys,
+        &mut conn,
+    )
+    .await?;
+
+    Ok(Json(tokens))
+}
+
+/// Starts enrollment with a new secret. 2FA stays off until `confirm_mfa`.
+pub async fn enroll_mfa(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    enrolling: MfaEnrollingUser,
+) -> Result<Json<MfaEnrollment>, AppError> {
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
+
+    let user = find_user(&enrolling.id, &mut conn).await?;
+
+    let supported = user
+        .role
+        .parse::<UserRole>()
+        .is_ok_and(|role| role.supports_mfa());
+
+    if !supported {
+        return Err(AppError::Forbidden(
+            "Two-factor authentication is only available for admin and seller accounts".to_owned(),
+        ));
+    }
+
+    if user.totp_enabled_at.is_some() {
+        return Err(AppError::Conflict(
+            "Two-factor authentication is already enabled".to_owned(),
+        ));
+    }
+
+    let secret = totp::generate_secret();
+
+    diesel::update(users::table.find(&user.id))
+        .set((
+            users::totp_secret.eq(&secret),
+            users::totp_last_step.eq(None::<i64>),
+        ))
+        .execute(&mut conn)
+        .await?;
+
+    Ok(Json(MfaEnrollment {
+        otpauth_uri: totp::otpauth_uri(&secret, &user.email, &config.auth.totp_issuer),
+        secret,
+    }))
+}
+
+/// Turns 2FA on once a code from the authenticator app checks out.
+/// When enrollment was forced at login, the login is completed as well.
+pub async fn confirm_mfa(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    State(keys): State<Arc<JwtKeys>>,
+    headers: HeaderMap,
+    enrolling: MfaEnrollingUser,
+    ValidatedJson(payload): ValidatedJson<MfaCode>,
+) -> Result<Json<MfaRecoveryCodes>, AppError> {
+    use crate::schema::users;
+
+    let mut conn = pool.get().await?;
+
+    let user = find_user(&enrolling.id, &mut conn).await?;
+
+    if user.totp_enabled_at.is_some() {
+        return Err(AppError::Conflict(
+            "Two-factor authentication is already enabled".to_owned(),
+        ));
+    }
+
+    let Some(secret) = user.totp_secret.as_deref() else {
+        return Err(AppError::BadRequest(
+            "Start the enrollment first".to_owned(),
+        ));
+    };
+
+    let step = totp::verify(secret, &payload.code, None)
+        .ok_or(AppError::BadRequest("Invalid code".to_owned()))?;
+
+    let user_id = user.id;
+
+    let recovery_codes = conn
+        .transaction::<Vec<String>, diesel::result::Error, _>(move |mut conn| {
+            Box::pin(async move {
+                diesel::update(users::table.find(&user_id))
+                    .set((
+                        users::totp_enabled_at.eq(Utc::now().naive_utc()),
+                        users::totp_last_step.eq(step),
+                    ))
+                    .execute(&mut conn)
+                    .await?;
+
+                replace_recovery_codes(&user_id, conn).await
+            })
+        })
+        .await?;
+
+    let tokens = if enrolling.from_login {
+        Some(
+            issue_tokens(
+                &user,
+                &Uuid::new_v4(),
+                user_agent(&headers),
+                &config.auth,
+                &keys,
+                &mut conn,
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    Ok(Json(MfaRecoveryCodes {
+        recovery_codes,
+        tokens,
+    }))
+}
+
+/// Turns 2FA off, not allowed while the role requires it
+pub async fn disable_mfa(
+    State(pool): State<Pool>,
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<MfaCode>,
+) -> Result<(), AppError> {
+    use crate::schema::{mfa_recovery_codes, users};
+
+    let id = Uuid::parse_str(&claims.sub)?;
+
+    let mut conn = pool.get().await?;
+
+    let user = find_user(&id, &mut conn).await?;
+
+    if user.totp_enabled_at.is_none() {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication is not enabled".to_owned(),
+        ));
+    }
+
+    if role_requires_mfa(&user.role, &mut conn).await? {
+        return Err(AppError::Forbidden(
+            "Two-factor authentication is required for your role".to_owned(),
+        ));
+    }
+
+    if !consume_mfa_code(&user, &payload.code, &mut conn).await? {
+        return Err(AppError::BadRequest("Invalid code".to_owned()));
+    }
+
+    conn.transaction::<(), diesel::result::Error, _>(move |mut conn| {
+        Box::pin(async move {
+            diesel::update(users::table.find(&id))
+                .set((
+                    users::totp_secret.eq(None::<String>),
+                    users::totp_enabled_at.eq(None::<chrono::NaiveDateTime>),
+                    users::totp_last_step.eq(None::<i64>),
+                ))
+                .execute(&mut conn)
+                .await?;
+
+            diesel::delete(mfa_recovery_codes::table.filter(mfa_recovery_codes::user_id.eq(&id)))
+                .execute(&mut conn)
+                .await?;
+
+            Ok(())
+        })
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Invalidates the remaining recovery codes and hands out a new set
+pub async fn regenerate_recovery_codes(
+    State(pool): State<Pool>,
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<MfaCode>,
+) -> Result<Json<MfaRecoveryCodes>, AppError> {
+    let id = Uuid::parse_str(&claims.sub)?;
+
+    let mut conn = pool.get().await?;
+
+    let user = find_user(&id, &mut conn).await?;
+
+    if user.totp_enabled_at.is_none() {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication is not enabled".to_owned(),
+        ));
+    }
+
+    if !consume_mfa_code(&user, &payload.code, &mut conn).await? {
+        return Err(AppError::BadRequest("Invalid code".to_owned()));
+    }
+
+    let recovery_codes = conn
+        .transaction::<Vec<String>, diesel::result::Error, _>(move |conn| {
+            Box::pin(async move { replace_recovery_codes(&id, conn).await })
+        })
+        .await?;
+
+    Ok(Json(MfaRecoveryCodes {
+        recovery_codes,
+        tokens: None,
+    }))
+}
+
+pub async fn get_mfa_policies(State(pool): State<Pool>) -> Result<Json<Vec<MfaPolicy>>, AppError> {
+    use crate::schema::mfa_policies;
+
+    let mut conn = pool.get().await?;
+
+    let res = mfa_policies::table
+        .select(MfaPolicy::as_select())
+        .load(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+/// Requiring 2FA applies from the next login, users of the role without it
+/// are asked to enroll before they get a session
+pub async fn set_mfa_policy(
+    State(pool): State<Pool>,
+    Path(role): Path<UserRole>,
+    ValidatedJson(payload): ValidatedJson<UpdateMfaPolicy>,
+) -> Result<Json<MfaPolicy>, AppError> {
+    use crate::schema::mfa_policies;
+
+    if !role.supports_mfa() {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication can only be required for admin and seller roles".to_owned(),
+        ));
+    }
+
+    let mut conn = pool.get().await?;
+
+    let now = Utc::now().naive_utc();
+
+    let res = diesel::insert_into(mfa_policies::table)
+        .values((
+            mfa_policies::role.eq(role.as_str()),
+            mfa_policies::required.eq(payload.required),
+            mfa_policies::updated_at.eq(now),
+        ))
+        .on_conflict(mfa_policies::role)
+        .do_update()
+        .set((
+            mfa_policies::required.eq(payload.required),
+            mfa_policies::updated_at.eq(now),
+        ))
+        .returning(MfaPolicy::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+/// Users with 2FA on, or whose role requires it, get a short-lived token
+/// for the second step instead of a session
+async fn mfa_challenge(
+    user: &User,
+    auth: &AuthConfig,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<Option<MfaChallenge>, AppError> {
+    let purpose = if user.totp_enabled_at.is_some() {
+        MFA_LOGIN_PURPOSE
+    } else if role_requires_mfa(&user.role, conn).await? {
+        MFA_ENROLL_PURPOSE
+    } else {
+        return Ok(None);
+    };
+
+    let claims = MfaPendingClaims {
+        sub: user.id.to_string(),
+        purpose: purpose.to_owned(),
+        exp: (Utc::now() + Duration::minutes(MFA_TOKEN_TTL_MINUTES)).timestamp() as usize,
+    };
+
+    let mfa_token = encode_token(claims, &auth.refresh_secret).await?;
+
+    Ok(Some(MfaChallenge {
+        mfa_token,
+        enrollment_required: purpose == MFA_ENROLL_PURPOSE,
+    }))
+}
+
+/// Roles without a policy row don't require 2FA
+pub async fn role_requires_mfa(
+    role: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::mfa_policies;
+
+    let required = mfa_policies::table
+        .find(role)
+        .select(mfa_policies::required)
+        .first::<bool>(conn)
+        .await
+        .optional()?;
+
+    Ok(required.unwrap_or(false))
+}
+
+/// Checks a TOTP or recovery code and marks it as used
+async fn consume_mfa_code(
+    user: &User,
+    code: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::{mfa_recovery_codes, users};
+
+    let Some(secret) = user
+        .totp_secret
+        .as_deref()
+        .filter(|_| user.totp_enabled_at.is_some())
+    else {
+        return Ok(false);
+    };
+
+    if let Some(step) = totp::verify(secret, code, user.totp_last_step) {
+        // Conditional so two requests racing with the same code can't both pass
+        let updated = diesel::update(users::table.find(&user.id))
+            .filter(
+                users::totp_last_step
+                    .is_null()
+                    .or(users::totp_last_step.lt(step)),
+            )
+            .set(users::totp_last_step.eq(step))
+            .execute(conn)
+            .await?;
+
+        return Ok(updated == 1);
+    }
+
+    let used = diesel::update(mfa_recovery_codes::table)
+        .filter(mfa_recovery_codes::user_id.eq(&user.id))
+        .filter(mfa_recovery_codes::code_hash.eq(totp::hash_recovery_code(code)))
+        .filter(mfa_recovery_codes::used_at.is_null())
+        .set(mfa_recovery_codes::used_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .await?;
+
+    Ok(used == 1)
+}
+
+/// Stores a fresh set of recovery codes in place of the old ones, returns them in plain text
+async fn replace_recovery_codes(
+    user_id: &Uuid,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<Vec<String>, diesel::result::Error> {
+    use crate::schema::mfa_recovery_codes;
+
+    let codes = totp::generate_recovery_codes();
+
+    let rows: Vec<NewMfaRecoveryCode> = codes
+        .iter()
+        .map(|code| NewMfaRecoveryCode {
+            user_id: *user_id,
+            code_hash: totp::hash_recovery_code(code),
+        })
+        .collect();
+
+    diesel::delete(mfa_recovery_codes::table.filter(mfa_recovery_codes::user_id.eq(user_id)))
+        .execute(conn)
+        .await?;
+
+    diesel::insert_into(mfa_recovery_codes::table)
+        .values(&rows)
+        .execute(conn)
+        .await?;
+
+    Ok(codes)
+}
+
+async fn decode_mfa_token(token: &str, purpose: &str, secret: &str) -> Result<Uuid, AuthError> {
+    let claims = decode_token::<MfaPendingClaims>(token, secret)
+        .await?
+        .claims;
+
+    if claims.purpose != purpose {
+        return Err(AuthError::InvalidToken);
+    }
+
+    Uuid::parse_str(&claims.sub).map_err(|_| AuthError::InvalidToken)
+}
+
+async fn find_user(
+    id: &Uuid,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<User, AppError> {
+    use crate::schema::users;
+
+    users::table
+        .find(id)
+        .select(User::as_select())
+        .first(conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("User not found".to_owned()),
+            e => e.into(),
+        })
+}
+
+pub async fn get_api_keys(
+    State(pool): State<Pool>,
+    claims: AccessTokenClaims,
+) -> Result<Json<Vec<ApiKeyInfo>>, AppError> {
+    use crate::schema::api_keys;
+
+    let user_id = Uuid::parse_str(&claims.sub)?;
+
+    let mut conn = pool.get().await?;
+
+    let keys = api_keys::table
+        .filter(api_keys::user_id.eq(&user_id))
+        .order(api_keys::created_at.desc())
+        .select(ApiKey::as_select())
+        .load(&mut conn)
+        .await?;
+
+    Ok(Json(keys.into_iter().map(ApiKeyInfo::from).collect()))
+}
+
+/// Scopes can only narrow down what the user's role already allows
+pub async fn create_api_key(
+    State(pool): State<Pool>,
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<CreateApiKey>,
+) -> Result<Json<CreatedApiKey>, AppError> {
+    use crate::schema::api_keys;
+
+    reject_api_key(&claims)?;
+
+    if let Some(scope) = payload
+        .scopes
+        .iter()
+        .find(|scope| !claims.has_permission(**scope))
+    {
+        return Err(AppError::Forbidden(format!(
+            "Your role does not grant the {} scope",
+            scope.as_str()
+        )));
+    }
+
+    let user_id = Uuid::parse_str(&claims.sub)?;
+
+    let mut conn = pool.get().await?;
+
+    let key = generate_key();
+
+    let mut scopes: Vec<String> = payload
+        .scopes
+        .iter()
+        .map(|scope| scope.as_str().to_owned())
+        .collect();
+    scopes.sort();
+    scopes.dedup();
+
+    let new_key = NewApiKey {
+        id: Uuid::new_v4(),
+        user_id,
+        name: payload.name,
+        prefix: display_prefix(&key),
+        key_hash: hash_key(&key),
+        scopes,
+        expires_at: payload
+            .expires_in_days
+            .map(|days| (Utc::now() + Duration::days(days)).naive_utc()),
+    };
+
+    let api_key = diesel::insert_into(api_keys::table)
+        .values(&new_key)
+        .returning(ApiKey::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(CreatedApiKey {
+        key,
+        api_key: api_key.into(),
+    }))
+}
+
+pub async fn revoke_api_key(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+    claims: AccessTokenClaims,
+) -> Result<(), AppError> {
+    use crate::schema::api_keys;
+
+    reject_api_key(&claims)?;
+
+    let user_id = Uuid::parse_str(&claims.sub)?;
+
+    let mut conn = pool.get().await?;
+
+    let revoked = diesel::update(
+        api_keys::table
+            .find(&id)
+            .filter(api_keys::user_id.eq(&user_id))
+            .filter(api_keys::revoked_at.is_null()),
+    )
+    .set(api_keys::revoked_at.eq(Utc::now().naive_utc()))
+    .execute(&mut conn)
+    .await?;
+
+    if revoked == 0 {
+        return Err(AppError::NotFound("API key not found".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Keys are managed with a signed in session, so a leaked key cannot mint more keys
+fn reject_api_key(claims: &AccessTokenClaims) -> Result<(), AppError> {
+    if claims.api_key_id.is_some() {
+        return Err(AppError::Forbidden(
+            "API keys cannot be managed with an API key".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Starts an OpenID Connect login (authorization code + PKCE) by redirecting to
+/// the provider, the `state` is also left in a cookie for the callback to match
+pub async fn oidc_authorize(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+) -> Result<([(HeaderName, String); 1], Redirect), AppError> {
+    use crate::schema::oidc_states;
+
+    let settings = OidcSettings::from_config(&config.oidc)?;
+    let provider = oidc::provider(&settings).await?;
+
+    let mut conn = pool.get().await?;
+
+    let now = Utc::now().naive_utc();
+
+    diesel::delete(oidc_states::table.filter(oidc_states::expires_at.lt(now)))
+        .execute(&mut conn)
+        .await?;
 
-    let user_id = Uuid::new_v4();
+    let login_state = NewOidcState {
+        state: oidc::random_token(),
+        code_verifier: oidc::random_token(),
+        nonce: oidc::random_token(),
+        expires_at: now + Duration::minutes(OIDC_STATE_TTL_MINUTES),
+    };
 
-    let res = conn
-        .transaction::<SafeUser, diesel::result::Error, _>(move |mut conn| {
-            Box::pin(async move {
-                let user_data = User {
-                    id: user_id,
-                    email: payload.email,
-                    password_hash: hashed_pass,
-                    hashed_rt: None,
-                    role: "user".to_owned(),
-                };
+    diesel::insert_into(oidc_states::table)
+        .values(&login_state)
+        .execute(&mut conn)
+        .await?;
+
+    let url = oidc::authorization_url(
+        provider,
+        &settings,
+        &login_state.state,
+        &login_state.nonce,
+        &login_state.code_verifier,
+    )?;
 
-                let user = diesel::insert_into(users::table)
-                    .values(&user_data)
-                    .returning(SafeUser::as_returning())
-                    .get_result(&mut conn)
-                    .await?;
+    let cookie = oidc::state_cookie(&settings, &login_state.state, OIDC_STATE_TTL_MINUTES * 60)?;
 
-                let updated_at = Local::now().date_naive();
+    Ok(([(SET_COOKIE, cookie)], Redirect::to(&url)))
+}
 
-                let cart_data = NewCart {
-                    user_id,
-                    updated_at,
-                };
+/// Provider redirect target, signs the user in like `login_user` does
+pub async fn oidc_callback(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    State(keys): State<Arc<JwtKeys>>,
+    headers: HeaderMap,
+    cookies: Option<TypedHeader<Cookie>>,
+    Query(params): Query<OidcCallback>,
+) -> Result<Json<LoginResponse>, AppError> {
+    use crate::schema::oidc_states;
+
+    if let Some(error) = params.error {
+        return Err(AppError::BadRequest(format!(
+            "Identity provider returned {}",
+            error
+        )));
+    }
 
-                let subs_data = UserSubscriptions {
-                    user_id,
-                    channel: "email".to_owned(),
-                    orders_notifications: true,
-                    discount_notifications: true,
-                    newsletter_notifications: true,
-                };
+    let (Some(code), Some(state)) = (params.code, params.state) else {
+        return Err(OidcError::InvalidState.into());
+    };
 
-                let profile_data = Profile {
-                    id: Uuid::new_v4(),
-                    user_id,
-                    first_name: None,
-                    last_name: None,
-                    phone_number: None,
-                    birth_date: None,
-                    language: "en".to_owned(),
-                    currency: "usd".to_owned(),
-                };
+    // A callback url started in another browser would sign this one in to
+    // whatever account the provider returned, like the attacker's
+    let cookie_state = cookies
+        .as_ref()
+        .and_then(|TypedHeader(cookies)| cookies.get(oidc::STATE_COOKIE));
 
-                diesel::insert_into(carts::table)
-                    .values(&cart_data)
-                    .execute(&mut conn)
-                    .await?;
+    if cookie_state != Some(state.as_str()) {
+        return Err(OidcError::InvalidState.into());
+    }
 
-                diesel::insert_into(user_subscriptions::table)
-                    .values(&subs_data)
-                    .execute(&mut conn)
-                    .await?;
+    let settings = OidcSettings::from_config(&config.oidc)?;
+    let provider = oidc::provider(&settings).await?;
 
-                diesel::insert_into(profiles::table)
-                    .values(&profile_data)
-                    .execute(&mut conn)
-                    .await?;
+    let mut conn = pool.get().await?;
 
-                Ok(user)
-            })
-        })
+    // Deleting it makes every state single use
+    let login_state = diesel::delete(oidc_states::table.find(&state))
+        .returning(OidcState::as_returning())
+        .get_result(&mut conn)
         .await
-        .map_err(internal_error)?;
+        .optional()?
+        .filter(|login_state| login_state.expires_at > Utc::now().naive_utc())
+        .ok_or(OidcError::InvalidState)?;
+
+    let claims = oidc::exchange_code(
+        provider,
+        &settings,
+        &code,
+        &login_state.code_verifier,
+        &login_state.nonce,
+    )
+    .await?;
 
-    let event = serde_json::json!({
-        "type": "WelcomeUser",
-        "event": "user_created",
-        "email": res.email,
-    })
-    .to_string();
+    let (user, created) = find_or_create_oidc_user(&claims, &mut conn).await?;
 
-    if let Err(er) = crate::rmq::client::publish_event(QUEUE_NAME, &event).await {
+    if created && let Err(er) = publish_user_created(&config.rmq, &user.email).await {
         eprintln!("Failed to publish event: {:?}", er);
     }
 
-    Ok(Json(res))
-}
-
-pub async fn get_user_by_id(
-    State(pool): State<Pool>,
-    Path(id): Path<Uuid>,
-) -> Result<Json<SafeUser>, (StatusCode, String)> {
-    use axum_shop::schema::users;
-
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    if let Some(challenge) = mfa_challenge(&user, &config.auth, &mut conn).await? {
+        return Ok(Json(LoginResponse::MfaRequired(challenge)));
+    }
 
-    let res = users::table
-        .filter(users::id.eq(&id))
-        .select(SafeUser::as_select())
-        .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+    let tokens = issue_tokens(
+        &user,
+        &Uuid::new_v4(),
+        user_agent(&headers),
+        &config.auth,
+        &keys,
+        &mut conn,
+    )
+    .await?;
 
-    Ok(Json(res))
+    Ok(Json(LoginResponse::Tokens(tokens)))
 }
 
-pub async fn get_user_by_email(
-    State(pool): State<Pool>,
-    Json(payload): Json<UserEmail>,
-) -> Result<Json<SafeUser>, (StatusCode, String)> {
-    use axum_shop::schema::users;
+/// Users are found by the linked identity first. An unknown identity is linked
+/// to the user with the same email, but only when the provider verified it,
+/// otherwise a new customer account is created.
+async fn find_or_create_oidc_user(
+    claims: &IdTokenClaims,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(User, bool), AppError> {
+    use crate::schema::{user_identities, users};
+
+    let linked = user_identities::table
+        .inner_join(users::table)
+        .filter(user_identities::issuer.eq(&claims.iss))
+        .filter(user_identities::subject.eq(&claims.sub))
+        .select(User::as_select())
+        .first(conn)
+        .await
+        .optional()?;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    if let Some(user) = linked {
+        return Ok((user, false));
+    }
 
-    let res = users::table
-        .filter(users::email.eq(&payload.email))
-        .select(SafeUser::as_select())
-        .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+    let email = claims.verified_email().ok_or(OidcError::EmailNotVerified)?;
 
-    Ok(Json(res))
-}
+    // Accounts created here have no usable password until it is reset
+    let password_hash = create_hash(Uuid::new_v4().to_string()).await?;
 
-pub async fn delete_user(
-    State(pool): State<Pool>,
-    Path(id): Path<Uuid>,
-) -> Result<Json<SafeUser>, (StatusCode, String)> {
-    use axum_shop::schema::carts;
-    use axum_shop::schema::users;
+    let identity = NewUserIdentity {
+        user_id: Uuid::nil(),
+        issuer: claims.iss.clone(),
+        subject: claims.sub.clone(),
+    };
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let res = conn
+        .transaction::<(User, bool), diesel::result::Error, _>(move |conn| {
+            Box::pin(async move {
+                let now = Utc::now().naive_utc();
+
+                let existing = users::table
+                    .filter(lower(users::email).eq(&email))
+                    .select(User::as_select())
+                    .first(conn)
+                    .await
+                    .optional()?;
+
+                let (user, created) = match existing {
+                    Some(mut user) => {
+                        if user.email_verified_at.is_none() {
+                            diesel::update(users::table.find(&user.id))
+                                .set(users::email_verified_at.eq(now))
+                                .execute(conn)
+                                .await?;
+
+                            user.email_verified_at = Some(now);
+                        }
+
+                        (user, false)
+                    }
+                    None => {
+                        let user = User {
+                            id: Uuid::new_v4(),
+                            email,
+                            password_hash,
+                            role: "user".to_owned(),
+                            email_verified_at: Some(now),
+                            verification_sent_at: None,
+                            failed_login_attempts: 0,
+                            locked_until: None,
+                            totp_secret: None,
+                            totp_enabled_at: None,
+                            totp_last_step: None,
+                            password_reset_sent_at: None,
+                        };
+
+                        insert_user(&user, conn).await?;
+
+                        (user, true)
+                    }
+                };
 
-    diesel::delete(carts::table.filter(carts::user_id.eq(&id)))
-        .execute(&mut conn)
-        .await
-        .map_err(internal_error)?;
+                diesel::insert_into(user_identities::table)
+                    .values(&NewUserIdentity {
+                        user_id: user.id,
+                        ..identity
+                    })
+                    .execute(conn)
+                    .await?;
 
-    let res = diesel::delete(users::table.find(&id))
-        .returning(SafeUser::as_returning())
-        .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+                Ok((user, created))
+            })
+        })
+        .await?;
 
-    Ok(Json(res))
+    Ok(res)
 }
 
-pub async fn update_user_email_or_password(
+/// Rotates the refresh token. Presenting a token that was already rotated
+/// means it leaked, so the whole session is revoked.
+pub async fn refresh_token(
     State(pool): State<Pool>,
-    Path(id): Path<Uuid>,
-    Json(payload): Json<UpdateUserPayload>,
-) -> Result<Json<SafeUser>, (StatusCode, String)> {
-    use axum_shop::schema::users;
-    let now = Instant::now();
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    State(config): State<Arc<Config>>,
+    State(keys): State<Arc<JwtKeys>>,
+    claims: RefreshTokenClaims,
+    headers: HeaderMap,
+    bearer: TypedHeader<Authorization<Bearer>>,
+) -> Result<Json<Tokens>, AppError> {
+    use crate::schema::{refresh_tokens, users};
 
-    let user = users::table
-        .find(id)
-        .select(User::as_select())
-        .get_result(&mut conn)
+    let mut conn = pool.get().await?;
+
+    let token = bearer.token();
+
+    let id = Uuid::parse_str(&claims.sub)?;
+    let token_id = Uuid::parse_str(&claims.jti)
+        .map_err(|_| AppError::Unauthorized("Invalid or expired refresh token".to_owned()))?;
+
+    let stored = refresh_tokens::table
+        .find(&token_id)
+        .filter(refresh_tokens::user_id.eq(&id))
+        .select(RefreshToken::as_select())
+        .first(&mut conn)
         .await
-        .map_err(internal_error)?;
+        .optional()?
+        .ok_or(AppError::Unauthorized(
+            "Please, use login instead".to_owned(),
+        ))?;
 
-    if payload.email.is_none() && payload.new_password.is_none() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "At least one field to update must be provided".to_owned(),
+    if stored.revoked_at.is_some() || stored.expires_at < Utc::now().naive_utc() {
+        return Err(AppError::Unauthorized(
+            "Invalid or expired refresh token".to_owned(),
         ));
     }
 
-    if payload.new_password.is_some() && payload.current_password.is_none() {
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            "Current password is required to update password".to_owned(),
+    if !validate_hash(token_fingerprint(token), stored.token_hash.clone()).await? {
+        return Err(AppError::Unauthorized(
+            "Invalid or expired refresh token".to_owned(),
         ));
     }
 
-    let mut new_hash: Option<String> = None;
-
-    if let (Some(cur), Some(new)) = (payload.current_password, payload.new_password) {
-        new_hash = Some(create_hash(new).await?);
-
-        if !validate_hash(cur, user.password_hash).await? {
-            return Err((StatusCode::UNAUTHORIZED, "Invalid password".to_owned()));
-        }
-    };
+    // Mark as used only if nobody rotated it in the meantime
+    let rotated = diesel::update(
+        refresh_tokens::table
+            .find(&stored.id)
+            .filter(refresh_tokens::used_at.is_null()),
+    )
+    .set(refresh_tokens::used_at.eq(Utc::now().naive_utc()))
+    .execute(&mut conn)
+    .await?;
 
-    let updated_user = UpdateUser {
-        email: payload.email,
-        password_hash: new_hash,
-    };
+    if rotated == 0 {
+        revoke_session(&stored.user_id, &stored.family_id, &mut conn).await?;
 
-    println!("updated user: {:?}", updated_user);
+        return Err(AppError::Unauthorized(
+            "Refresh token reuse detected, please log in again".to_owned(),
+        ));
+    }
 
-    let res = diesel::update(users::table.find(&id))
-        .set(&updated_user)
-        .returning(SafeUser::as_returning())
+    let user = users::table
+        .find(&id)
+        .select(User::as_select())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
+
+    let tokens = issue_tokens(
+        &user,
+        &stored.family_id,
+        user_agent(&headers),
+        &config.auth,
+        &keys,
+        &mut conn,
+    )
+    .await?;
 
-    println!("Time: {:.2?}", now.elapsed());
-    Ok(Json(res))
+    Ok(Json(tokens))
 }
 
-pub async fn get_all_users(
+pub async fn get_sessions(
     State(pool): State<Pool>,
-) -> Result<Json<Vec<SafeUserWithCart>>, (StatusCode, String)> {
-    use crate::cart::models::SafeCart;
-    use axum_shop::schema::carts;
-    use axum_shop::schema::users;
+    claims: AccessTokenClaims,
+) -> Result<Json<Vec<Session>>, AppError> {
+    use crate::schema::refresh_tokens;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
-    let rows = users::table
-        .inner_join(carts::table)
-        .select((SafeUser::as_select(), SafeCart::as_select()))
-        .load::<(SafeUser, SafeCart)>(&mut conn)
-        .await
-        .map_err(internal_error)?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
+
+    // The latest unused token of every family is the live session
+    let rows = refresh_tokens::table
+        .filter(refresh_tokens::user_id.eq(&user_id))
+        .filter(refresh_tokens::used_at.is_null())
+        .filter(refresh_tokens::revoked_at.is_null())
+        .filter(refresh_tokens::expires_at.gt(Utc::now().naive_utc()))
+        .select(RefreshToken::as_select())
+        .order(refresh_tokens::created_at.desc())
+        .load(&mut conn)
+        .await?;
 
     let res = rows
         .into_iter()
-        .map(|(user, cart)| SafeUserWithCart { user, cart })
+        .map(|token| Session {
+            current: claims.sid.as_deref() == Some(token.family_id.to_string().as_str()),
+            id: token.family_id,
+            user_agent: token.user_agent,
+            last_used_at: token.created_at,
+            expires_at: token.expires_at,
+        })
         .collect();
 
     Ok(Json(res))
 }
 
-pub async fn get_current_user(
+pub async fn delete_session(
     State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
     claims: AccessTokenClaims,
-) -> Result<Json<SafeUserWithCart>, (StatusCode, String)> {
-    use crate::cart::models::SafeCart;
-    use axum_shop::schema::carts;
-    use axum_shop::schema::users;
-
-    let mut conn = pool.get().await.map_err(internal_error)?;
+) -> Result<(), AppError> {
+    let mut conn = pool.get().await?;
 
-    let user_id = Uuid::parse_str(&claims.sub).unwrap();
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
 
-    let (user, cart) = users::table
-        .filter(users::id.eq(&user_id))
-        .inner_join(carts::table)
-        .select((SafeUser::as_select(), SafeCart::as_select()))
-        .get_result::<(SafeUser, SafeCart)>(&mut conn)
-        .await
-        .map_err(internal_error)?;
+    let revoked = revoke_session(&user_id, &id, &mut conn).await?;
 
-    let res = SafeUserWithCart { user, cart };
+    if revoked == 0 {
+        return Err(AppError::NotFound("Session not found".to_owned()));
+    }
 
-    Ok(Json(res))
+    Ok(())
 }
 
-pub async fn login_user(
+/// Signs out every other device
+pub async fn delete_other_sessions(
     State(pool): State<Pool>,
-    Json(payload): Json<LoginUser>,
-) -> Result<Json<Tokens>, (StatusCode, String)> {
-    use axum_shop::schema::users;
-    let now = Instant::now();
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    claims: AccessTokenClaims,
+) -> Result<(), AppError> {
+    use crate::schema::refresh_tokens;
 
-    let user = users::table
-        .filter(users::email.eq(payload.email))
-        .first::<User>(&mut conn)
-        .await
-        .map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
-    if !validate_hash(payload.password, user.password_hash).await? {
-        return Err((StatusCode::UNAUTHORIZED, "Invalid password".to_owned()));
-    }
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
 
-    let (access_token, refresh_token) = create_tokens_pair(
-        Duration::minutes(5),
-        Duration::days(7),
-        &user.id.to_string(),
-        &user.email,
-        &user.role,
+    let current = claims
+        .sid
+        .as_deref()
+        .and_then(|sid| Uuid::parse_str(sid).ok())
+        .unwrap_or_default();
+
+    diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::user_id.eq(&user_id))
+            .filter(refresh_tokens::family_id.ne(&current))
+            .filter(refresh_tokens::revoked_at.is_null()),
     )
+    .set(refresh_tokens::revoked_at.eq(Utc::now().naive_utc()))
+    .execute(&mut conn)
     .await?;
 
-    let refresh_token_hash = create_hash(refresh_token.clone()).await?;
-
-    diesel::update(users::table.filter(users::id.eq(user.id)))
-        .set(users::hashed_rt.eq(&refresh_token_hash))
-        .execute(&mut conn)
-        .await
-        .map_err(internal_error)?;
-
-    let tokens = Tokens {
-        access_token,
-        refresh_token,
-    };
-
-    println!("Time: {:.2?}", now.elapsed());
-
-    Ok(Json(tokens))
+    Ok(())
 }
 
-pub async fn refresh_token(
-    State(pool): State<Pool>,
-    claims: RefreshTokenClaims,
-    bearer: TypedHeader<Authorization<Bearer>>,
-) -> Result<Json<Tokens>, (StatusCode, String)> {
-    use axum_shop::schema::users;
+/// Revokes every token of a session, returns the number of revoked tokens
+pub async fn revoke_session(
+    user_id: &Uuid,
+    family_id: &Uuid,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::refresh_tokens;
+
+    diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::user_id.eq(user_id))
+            .filter(refresh_tokens::family_id.eq(family_id))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set(refresh_tokens::revoked_at.eq(Utc::now().naive_utc()))
+    .execute(conn)
+    .await
+}
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+pub async fn revoke_user_sessions(
+    user_id: &Uuid,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<usize, diesel::result::Error> {
+    use crate::schema::refresh_tokens;
 
-    let token = bearer.token();
+    diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::user_id.eq(user_id))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set(refresh_tokens::revoked_at.eq(Utc::now().naive_utc()))
+    .execute(conn)
+    .await
+}
 
-    let id = Uuid::parse_str(&claims.sub).map_err(internal_error)?;
+/// Creates a token pair for the session and stores the refresh token
+pub async fn issue_tokens(
+    user: &User,
+    family_id: &Uuid,
+    user_agent: Option<String>,
+    auth: &AuthConfig,
+    keys: &Arc<JwtKeys>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<Tokens, AppError> {
+    use crate::schema::refresh_tokens;
 
-    let user = users::table
-        .find(&id)
-        .select(User::as_select())
-        .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
-
-    if let Some(hash) = &user.hashed_rt {
-        validate_hash(token.to_owned(), hash.to_owned()).await?;
-        // match validate_hash(token.to_owned(), hash.to_owned()).await {
-        //     Ok(_) => Ok::<(), (StatusCode, String)>(()),
-        //     Err(_) => {
-        //         diesel::update(users::table.find(&id))
-        //             .set(users::hashed_rt.eq(None::<String>))
-        //             .execute(&mut conn)
-        //             .await
-        //             .map_err(internal_error)?;
-        //
-        //         return Err((
-        //             StatusCode::UNAUTHORIZED,
-        //             "Invalid or expired refresh token".to_owned(),
-        //         ));
-        //     }
-        // }
-    } else {
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            "Please, use login instead".to_owned(),
-        ));
-    };
+    let token_id = Uuid::new_v4();
 
     let (access_token, refresh_token) = create_tokens_pair(
-        Duration::minutes(5),
-        Duration::days(7),
+        auth,
+        keys,
         &user.id.to_string(),
         &user.email,
         &user.role,
+        &token_id,
+        family_id,
     )
     .await?;
 
-    let refresh_token_hash = create_hash(refresh_token.clone()).await?;
+    let token_hash = create_hash(token_fingerprint(&refresh_token)).await?;
 
-    diesel::update(users::table.filter(users::id.eq(user.id)))
-        .set(users::hashed_rt.eq(&refresh_token_hash))
-        .execute(&mut conn)
-        .await
-        .map_err(internal_error)?;
+    let new_token = NewRefreshToken {
+        id: token_id,
+        user_id: user.id,
+        family_id: *family_id,
+        token_hash,
+        user_agent,
+        expires_at: (Utc::now() + Duration::days(auth.refresh_token_ttl_days)).naive_utc(),
+    };
 
-    let tokens = Tokens {
+    diesel::insert_into(refresh_tokens::table)
+        .values(&new_token)
+        .execute(conn)
+        .await?;
+
+    Ok(Tokens {
         access_token,
         refresh_token,
-    };
+    })
+}
 
-    Ok(Json(tokens))
+/// bcrypt only looks at the first 72 bytes, so the signature is hashed
+/// instead of the whole token whose header is the same for every token
+fn token_fingerprint(token: &str) -> String {
+    token.rsplit('.').next().unwrap_or(token).to_owned()
+}
+
+fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.chars().take(255).collect())
 }
 
 async fn create_tokens_pair(
-    access_duration: Duration,
-    refresh_duration: Duration,
+    auth: &AuthConfig,
+    keys: &Arc<JwtKeys>,
     id: &str,
     email: &str,
     role: &str,
-) -> Result<(String, String), (StatusCode, String)> {
-    let access_exprires = Utc::now() + access_duration;
+    token_id: &Uuid,
+    family_id: &Uuid,
+) -> Result<(String, String), AppError> {
+    let access_exprires = Utc::now() + Duration::minutes(auth.access_token_ttl_minutes);
     let access_claims = AccessTokenClaims {
         sub: id.to_owned(),
         email: email.to_owned(),
         role: role.to_owned(),
         exp: access_exprires.timestamp() as usize,
+        sid: Some(family_id.to_string()),
+        api_key_id: None,
+        scopes: None,
     };
 
-    let refresh_expires = Utc::now() + refresh_duration;
+    let refresh_expires = Utc::now() + Duration::days(auth.refresh_token_ttl_days);
     let refresh_claims = RefreshTokenClaims {
         sub: id.to_owned(),
         exp: refresh_expires.timestamp() as usize,
+        jti: token_id.to_string(),
     };
 
-    let at_secret = env::var("AT_SECRET").map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "AT_SECRET not set".to_owned(),
-        )
-    })?;
-    let rt_secret = env::var("RT_SECRET").map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "RT_SECRET not set".to_owned(),
-        )
-    })?;
-
     let (access_token, refresh_token) = tokio::try_join!(
-        encode_token(access_claims, &at_secret),
-        encode_token(refresh_claims, &rt_secret),
+        encode_access_token(access_claims, keys.clone()),
+        encode_token(refresh_claims, &auth.refresh_secret),
     )?;
 
     Ok((access_token, refresh_token))
@@ -452,18 +1863,28 @@ async fn create_tokens_pair(
 impl<S> FromRequestParts<S> for AccessTokenClaims
 where
     S: Send + Sync,
+    Arc<JwtKeys>: FromRef<S>,
 {
     type Rejection = AuthError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // Set by `api_keys::authenticate` for requests with an `X-Api-Key` header,
+        // or by `rbac::load_claims` once the bearer token has been decoded
+        if let Some(claims) = parts.extensions.get::<AccessTokenClaims>() {
+            return Ok(claims.clone());
+        }
+
         let TypedHeader(Authorization(bearer)) = parts
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
             .map_err(|_| AuthError::InvalidToken)?;
 
-        let secret = env::var("AT_SECRET").map_err(|_| AuthError::MissingSecret)?;
+        let keys = Arc::<JwtKeys>::from_ref(state);
+        let token = bearer.token().to_owned();
 
-        let token_data = decode_token(&bearer.token(), &secret).await?;
+        let token_data = tokio::task::spawn_blocking(move || keys.decode::<Self>(&token))
+            .await
+            .map_err(|_| AuthError::FailedTask)??;
 
         println!("Token data: {:?}", token_data);
 
@@ -471,21 +1892,61 @@ where
     }
 }
 
+impl<S> FromRequestParts<S> for MfaEnrollingUser
+where
+    S: Send + Sync,
+    Arc<Config>: FromRef<S>,
+    Arc<JwtKeys>: FromRef<S>,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let TypedHeader(Authorization(bearer)) = parts
+            .extract::<TypedHeader<Authorization<Bearer>>>()
+            .await
+            .map_err(|_| AuthError::InvalidToken)?;
+
+        let config = Arc::<Config>::from_ref(state);
+
+        if let Ok(id) = decode_mfa_token(
+            bearer.token(),
+            MFA_ENROLL_PURPOSE,
+            &config.auth.refresh_secret,
+        )
+        .await
+        {
+            return Ok(Self {
+                id,
+                from_login: true,
+            });
+        }
+
+        let claims = AccessTokenClaims::from_request_parts(parts, state).await?;
+        let id = Uuid::parse_str(&claims.sub).map_err(|_| AuthError::InvalidToken)?;
+
+        Ok(Self {
+            id,
+            from_login: false,
+        })
+    }
+}
+
 impl<S> FromRequestParts<S> for RefreshTokenClaims
 where
     S: Send + Sync,
+    Arc<Config>: FromRef<S>,
 {
     type Rejection = AuthError;
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let TypedHeader(Authorization(bearer)) = parts
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
             .map_err(|_| AuthError::InvalidToken)?;
 
-        let secret = env::var("RT_SECRET").map_err(|_| AuthError::MissingSecret)?;
+        let config = Arc::<Config>::from_ref(state);
 
-        let token_data = decode_token(&bearer.token(), &secret).await?;
+        let token_data = decode_token(bearer.token(), &config.auth.refresh_secret).await?;
 
         println!("Token data: {:?}", token_data);
 
@@ -493,45 +1954,62 @@ where
     }
 }
 
+/// Access tokens are signed with the configured `JwtKeys` and carry its `kid`
+async fn encode_access_token(
+    claims: AccessTokenClaims,
+    keys: Arc<JwtKeys>,
+) -> Result<String, AppError> {
+    let token =
+        tokio::task::spawn_blocking(move || encode(&keys.header(), &claims, keys.encoding_key()))
+            .await??;
+
+    Ok(token)
+}
+
+pub async fn get_jwks(State(keys): State<Arc<JwtKeys>>) -> Json<JwkSet> {
+    Json(keys.jwks().clone())
+}
+
 async fn encode_token<T: Sync + DeserializeOwned + 'static + Serialize + Send>(
     claims: T,
     secret: &str,
-) -> Result<String, (StatusCode, String)> {
+) -> Result<String, AppError> {
     let secret = secret.to_owned();
     // let claims = claims.clone();
 
     let token = tokio::task::spawn_blocking({
         move || {
-            let refresh_token = encode(
+            encode(
                 &Header::default(),
                 &claims,
                 &EncodingKey::from_secret(secret.as_bytes()),
-            );
-
-            refresh_token
+            )
         }
     })
-    .await
-    .map_err(internal_error)?
-    .map_err(internal_error)?;
+    .await??;
 
     Ok(token)
 }
 
-pub async fn logout(
-    State(pool): State<Pool>,
-    claims: AccessTokenClaims,
-) -> Result<(), (StatusCode, String)> {
-    use axum_shop::schema::users;
+/// Revokes the session the access token belongs to
+pub async fn logout(State(pool): State<Pool>, claims: AccessTokenClaims) -> Result<(), AppError> {
+    if claims.api_key_id.is_some() {
+        return Err(AppError::BadRequest(
+            "API keys are revoked with DELETE /me/api-keys/{id}".to_owned(),
+        ));
+    }
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
     let id = Uuid::parse_str(&claims.sub).unwrap();
 
-    diesel::update(users::table.filter(users::id.eq(id)))
-        .set(users::hashed_rt.eq(None::<String>))
-        .execute(&mut conn)
-        .await
-        .map_err(internal_error)?;
+    match claims
+        .sid
+        .as_deref()
+        .and_then(|sid| Uuid::parse_str(sid).ok())
+    {
+        Some(family_id) => revoke_session(&id, &family_id, &mut conn).await,
+        None => revoke_user_sessions(&id, &mut conn).await,
+    }?;
 
     Ok(())
 }
@@ -557,20 +2035,28 @@ async fn decode_token<T: Send + DeserializeOwned + 'static>(
     Ok(data)
 }
 
-async fn create_hash(password: String) -> Result<String, (StatusCode, String)> {
-    let hashed_password = tokio::task::spawn_blocking(move || hash(password, DEFAULT_COST))
-        .await
-        .map_err(internal_error)?
-        .map_err(internal_error)?;
+pub async fn create_hash(password: String) -> Result<String, AppError> {
+    let hashed_password =
+        tokio::task::spawn_blocking(move || hash(password, DEFAULT_COST)).await??;
 
     Ok(hashed_password)
 }
 
-async fn validate_hash(password: String, hash: String) -> Result<bool, (StatusCode, String)> {
-    let is_valid = tokio::task::spawn_blocking(move || verify(password, &hash))
-        .await
-        .map_err(internal_error)?
-        .map_err(internal_error)?;
+/// Hash of a random password, checked against when the login email is unknown
+async fn dummy_hash() -> Result<String, AppError> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    if let Some(hash) = DUMMY_HASH.get() {
+        return Ok(hash.clone());
+    }
+
+    let hash = create_hash(Uuid::new_v4().to_string()).await?;
+
+    Ok(DUMMY_HASH.get_or_init(|| hash).clone())
+}
+
+async fn validate_hash(password: String, hash: String) -> Result<bool, AppError> {
+    let is_valid = tokio::task::spawn_blocking(move || verify(password, &hash)).await??;
 
     Ok(is_valid)
 }
@@ -0,0 +1,336 @@
+use super::models::{AuthError, Jwk, JwkSet};
+use crate::config::AuthConfig;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use der::Decode;
+use der::asn1::UintRef;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use serde::de::DeserializeOwned;
+use spki::{ObjectIdentifier, SubjectPublicKeyInfoRef};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// `rsaEncryption` algorithm of a `SubjectPublicKeyInfo`
+const RSA_ENCRYPTION_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+
+/// `id-Ed25519` algorithm of a `SubjectPublicKeyInfo`
+const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+/// PKCS#1 `RSAPublicKey`, the content of an RSA `SubjectPublicKeyInfo`
+#[derive(der::Sequence)]
+struct RsaPublicKey<'a> {
+    modulus: UintRef<'a>,
+    public_exponent: UintRef<'a>,
+}
+
+/// Keys used for access tokens, selected by `auth.jwt_algorithm`:
+/// - `HS256` (default) signs and verifies with `auth.access_secret`
+/// - `RS256` or `EdDSA` sign with the `auth.jwt_private_key` PEM file under
+///   the `auth.jwt_key_id` kid and verify with every `auth.jwt_public_keys` file
+///
+/// Shared through `AppState` as `Arc<JwtKeys>`.
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    kid: Option<String>,
+    encoding: EncodingKey,
+    decoding: HashMap<String, DecodingKey>,
+    jwks: JwkSet,
+}
+
+impl JwtKeys {
+    /// Reads the key files at startup, so a missing or broken key stops the server
+    /// instead of failing the first login
+    pub fn from_config(config: &AuthConfig) -> Result<Self, String> {
+        let algorithm = config.jwt_algorithm;
+
+        if !matches!(
+            algorithm,
+            Algorithm::HS256 | Algorithm::RS256 | Algorithm::EdDSA
+        ) {
+            return Err(format!("Unsupported JWT algorithm: {:?}", algorithm));
+        }
+
+        if algorithm == Algorithm::HS256 {
+            let secret = config
+                .access_secret
+                .as_deref()
+                .ok_or_else(|| "auth.access_secret not set".to_owned())?;
+
+            return Ok(Self {
+                algorithm,
+                kid: None,
+                encoding: EncodingKey::from_secret(secret.as_bytes()),
+                decoding: HashMap::from([(
+                    String::new(),
+                    DecodingKey::from_secret(secret.as_bytes()),
+                )]),
+                jwks: JwkSet { keys: Vec::new() },
+            });
+        }
+
+        let kid = config
+            .jwt_key_id
+            .clone()
+            .ok_or_else(|| "auth.jwt_key_id not set".to_owned())?;
+        let private_key = read_pem(
+            config
+                .jwt_private_key
+                .as_deref()
+                .ok_or_else(|| "auth.jwt_private_key not set".to_owned())?,
+        )?;
+
+        let encoding = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(&private_key),
+            _ => EncodingKey::from_ed_pem(&private_key),
+        }
+        .map_err(|e| format!("Invalid auth.jwt_private_key: {}", e))?;
+
+        let mut decoding = HashMap::new();
+        let mut jwks = Vec::new();
+
+        for (key_id, path) in &config.jwt_public_keys {
+            let key_id = key_id.clone();
+
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Failed to read public key {}: {}", key_id, e))?;
+
+            let decoding_key = match algorithm {
+                Algorithm::RS256 => DecodingKey::from_rsa_pem(&pem),
+                _ => DecodingKey::from_ed_pem(&pem),
+            }
+            .map_err(|e| format!("Invalid public key {}: {}", key_id, e))?;
+
+            jwks.push(public_jwk(algorithm, &key_id, &pem)?);
+            decoding.insert(key_id, decoding_key);
+        }
+
+        if !decoding.contains_key(&kid) {
+            return Err(
+                "auth.jwt_public_keys must contain the public key of auth.jwt_key_id".to_owned(),
+            );
+        }
+
+        Ok(Self {
+            algorithm,
+            kid: Some(kid),
+            encoding,
+            decoding,
+            jwks: JwkSet { keys: jwks },
+        })
+    }
+
+    pub fn header(&self) -> Header {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+        header
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding
+    }
+
+    /// Picks the verification key by the `kid` header of the token
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<TokenData<T>, AuthError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|_| AuthError::InvalidToken)?;
+
+        if header.alg != self.algorithm {
+            return Err(AuthError::InvalidToken);
+        }
+
+        let key = self
+            .decoding
+            .get(header.kid.as_deref().unwrap_or_default())
+            .ok_or(AuthError::InvalidToken)?;
+
+        jsonwebtoken::decode::<T>(token, key, &Validation::new(self.algorithm))
+            .map_err(|_| AuthError::InvalidToken)
+    }
+
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+fn public_jwk(algorithm: Algorithm, kid: &str, pem: &[u8]) -> Result<Jwk, String> {
+    let (label, der) =
+        der::pem::decode_vec(pem).map_err(|_| format!("Public key {} is not a valid PEM", kid))?;
+
+    match algorithm {
+        Algorithm::RS256 => {
+            let key = rsa_public_key(label, &der)
+                .ok_or_else(|| format!("Public key {} is not an RSA key", kid))?;
+
+            Ok(Jwk {
+                kty: "RSA".to_owned(),
+                kid: kid.to_owned(),
+                alg: "RS256".to_owned(),
+                key_use: "sig".to_owned(),
+                n: Some(URL_SAFE_NO_PAD.encode(key.modulus.as_bytes())),
+                e: Some(URL_SAFE_NO_PAD.encode(key.public_exponent.as_bytes())),
+                crv: None,
+                x: None,
+            })
+        }
+        _ => {
+            let x = ed25519_public_key(label, &der)
+                .ok_or_else(|| format!("Public key {} is not an Ed25519 key", kid))?;
+
+            Ok(Jwk {
+                kty: "OKP".to_owned(),
+                kid: kid.to_owned(),
+                alg: "EdDSA".to_owned(),
+                key_use: "sig".to_owned(),
+                n: None,
+                e: None,
+                crv: Some("Ed25519".to_owned()),
+                x: Some(URL_SAFE_NO_PAD.encode(x)),
+            })
+        }
+    }
+}
+
+/// Accepts a `PUBLIC KEY` (SPKI) or `RSA PUBLIC KEY` (PKCS#1) PEM
+fn rsa_public_key<'a>(label: &str, der: &'a [u8]) -> Option<RsaPublicKey<'a>> {
+    let pkcs1 = match label {
+        "RSA PUBLIC KEY" => der,
+        "PUBLIC KEY" => {
+            let spki = SubjectPublicKeyInfoRef::from_der(der).ok()?;
+
+            if spki.algorithm.oid != RSA_ENCRYPTION_OID {
+                return None;
+            }
+
+            spki.subject_public_key.as_bytes()?
+        }
+        _ => return None,
+    };
+
+    RsaPublicKey::from_der(pkcs1).ok()
+}
+
+/// The raw 32 byte key of a `PUBLIC KEY` (SPKI) PEM
+fn ed25519_public_key<'a>(label: &str, der: &'a [u8]) -> Option<&'a [u8]> {
+    if label != "PUBLIC KEY" {
+        return None;
+    }
+
+    let spki = SubjectPublicKeyInfoRef::from_der(der).ok()?;
+
+    if spki.algorithm.oid != ED25519_OID {
+        return None;
+    }
+
+    spki.subject_public_key
+        .as_bytes()
+        .filter(|key| key.len() == 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    const RSA_N: &str = "mUlUM-AyrYBWCbkTM_VHNv2Go5d8mCKyjb65IgW2qIjcSP_x12rcYMjusox_Gy90ddd7_5ny80E6ieSbFpFBny-Ogs5iZbtsMMmOPxID499USuInDU5nAgTkWL4F_jxF5FFER5P8Ze_M680j7fJ84MeuVs-6G9qznfwThrBLVOUjJ1pfqU2bH755WcWD16KU8NoAA_wa2F69pWsfl33tMX7zZDQnc07mK1Mso92oP1A4fSy2XH4L7qfj-LAGSZNC2EBd5g05NewsjI9glaiVBljq49eJlM8poqmtJd_mZZZNhwO88LvyBpLzFftLWOw3Snno64tJ7-Jto-WA-v1CFQ";
+    const RSA_E: &str = "AQAB";
+    const ED25519_X: &str = "qBAbNC67dt8kWW0Iqd9Pm1fAif5OB9AF-z-jVdXXhm4";
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/auth/testdata")
+            .join(name)
+    }
+
+    fn config(algorithm: Algorithm, private_key: &str, public_key: &str) -> AuthConfig {
+        AuthConfig {
+            jwt_algorithm: algorithm,
+            jwt_key_id: Some("test".to_owned()),
+            jwt_private_key: Some(testdata(private_key)),
+            jwt_public_keys: BTreeMap::from([("test".to_owned(), testdata(public_key))]),
+            ..AuthConfig::default()
+        }
+    }
+
+    fn pem(label: &str, der: &[u8]) -> Vec<u8> {
+        der::pem::encode_string(label, der::pem::LineEnding::LF, der)
+            .unwrap()
+            .into_bytes()
+    }
+
+    #[test]
+    fn rsa_jwks_publishes_modulus_and_exponent() {
+        let keys =
+            JwtKeys::from_config(&config(Algorithm::RS256, "rsa.pem", "rsa.pub.pem")).unwrap();
+        let jwk = &keys.jwks().keys[0];
+
+        assert_eq!(jwk.kty, "RSA");
+        assert_eq!(jwk.kid, "test");
+        assert_eq!(jwk.n.as_deref(), Some(RSA_N));
+        assert_eq!(jwk.e.as_deref(), Some(RSA_E));
+    }
+
+    #[test]
+    fn rsa_jwks_accepts_pkcs1_public_keys() {
+        let pem = std::fs::read(testdata("rsa.pkcs1.pub.pem")).unwrap();
+        let jwk = public_jwk(Algorithm::RS256, "test", &pem).unwrap();
+
+        assert_eq!(jwk.n.as_deref(), Some(RSA_N));
+        assert_eq!(jwk.e.as_deref(), Some(RSA_E));
+    }
+
+    #[test]
+    fn ed25519_jwks_publishes_the_public_key() {
+        let keys =
+            JwtKeys::from_config(&config(Algorithm::EdDSA, "ed25519.pem", "ed25519.pub.pem"))
+                .unwrap();
+        let jwk = &keys.jwks().keys[0];
+
+        assert_eq!(jwk.kty, "OKP");
+        assert_eq!(jwk.crv.as_deref(), Some("Ed25519"));
+        assert_eq!(jwk.x.as_deref(), Some(ED25519_X));
+    }
+
+    #[test]
+    fn signed_tokens_verify_with_the_published_key() {
+        let keys =
+            JwtKeys::from_config(&config(Algorithm::RS256, "rsa.pem", "rsa.pub.pem")).unwrap();
+        let claims = serde_json::json!({ "sub": "user", "exp": usize::MAX });
+
+        let token = jsonwebtoken::encode(&keys.header(), &claims, keys.encoding_key()).unwrap();
+        let decoded = keys.decode::<serde_json::Value>(&token).unwrap();
+
+        assert_eq!(decoded.header.kid.as_deref(), Some("test"));
+        assert_eq!(decoded.claims["sub"], "user");
+    }
+
+    #[test]
+    fn rejects_a_key_of_the_other_type() {
+        let ed25519 = std::fs::read(testdata("ed25519.pub.pem")).unwrap();
+        let rsa = std::fs::read(testdata("rsa.pub.pem")).unwrap();
+
+        assert!(public_jwk(Algorithm::RS256, "test", &ed25519).is_err());
+        assert!(public_jwk(Algorithm::EdDSA, "test", &rsa).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_der_without_panicking() {
+        // SEQUENCE claiming a length that overflows usize
+        let overflowing = [0x30, 0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        // SEQUENCE claiming more content than there is
+        let truncated = [0x30, 0x82, 0x01, 0x0a, 0x02, 0x01];
+
+        for der in [&overflowing[..], &truncated[..], &[]] {
+            for label in ["PUBLIC KEY", "RSA PUBLIC KEY"] {
+                let pem = pem(label, der);
+
+                assert!(public_jwk(Algorithm::RS256, "test", &pem).is_err());
+                assert!(public_jwk(Algorithm::EdDSA, "test", &pem).is_err());
+            }
+        }
+
+        assert!(public_jwk(Algorithm::RS256, "test", b"not a pem").is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use axum::http::HeaderMap;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::{Integer, Timestamp};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::net::{IpAddr, SocketAddr};
+use uuid::Uuid;
+
+/// Failed logins an account gets before it is temporarily locked
+const ACCOUNT_LOCK_THRESHOLD: i32 = 5;
+
+/// Upper bound of the account lock, the backoff doubles up to it
+const ACCOUNT_MAX_LOCK_MINUTES: i64 = 60;
+
+/// Failed logins from a single IP, across all accounts, before it is locked
+const IP_LOCK_THRESHOLD: i32 = 20;
+
+/// How long an IP stays locked
+const IP_LOCK_MINUTES: i64 = 15;
+
+/// The IP counter starts over after this long without a failed attempt
+const IP_WINDOW_MINUTES: i64 = 15;
+
+/// Lock duration for the `attempts`-th failed login: 1, 2, 4... minutes
+/// once the threshold is reached, capped at `ACCOUNT_MAX_LOCK_MINUTES`
+fn account_backoff(attempts: i32) -> Option<Duration> {
+    if attempts < ACCOUNT_LOCK_THRESHOLD {
+        return None;
+    }
+
+    let exponent = (attempts - ACCOUNT_LOCK_THRESHOLD).min(16) as u32;
+    let minutes = 2_i64.pow(exponent).min(ACCOUNT_MAX_LOCK_MINUTES);
+
+    Some(Duration::minutes(minutes))
+}
+
+/// Address the IP limiter counts against. With `client_ip_header` set the
+/// last address of that header is used, the one the proxy added; the peer
+/// address is the fallback when the header is missing or malformed.
+pub fn client_ip(peer: SocketAddr, headers: &HeaderMap, client_ip_header: Option<&str>) -> String {
+    client_ip_header
+        .and_then(|name| headers.get(name))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .unwrap_or(peer.ip())
+        .to_string()
+}
+
+pub fn is_locked(locked_until: Option<NaiveDateTime>) -> bool {
+    locked_until.is_some_and(|until| until > Utc::now().naive_utc())
+}
+
+pub async fn ip_locked(
+    ip: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<bool, diesel::result::Error> {
+    use crate::schema::login_ip_attempts;
+
+    let locked_until = login_ip_attempts::table
+        .find(ip)
+        .select(login_ip_attempts::locked_until)
+        .first::<Option<NaiveDateTime>>(conn)
+        .await
+        .optional()?
+        .flatten();
+
+    Ok(is_locked(locked_until))
+}
+
+/// Counts a failed login against the IP and, when the email matched a user, the account
+pub async fn record_failure(
+    user_id: Option<&Uuid>,
+    ip: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::{login_ip_attempts, users};
+
+    let now = Utc::now().naive_utc();
+
+    if let Some(user_id) = user_id {
+        let attempts = diesel::update(users::table.find(user_id))
+            .set(users::failed_login_attempts.eq(users::failed_login_attempts + 1))
+            .returning(users::failed_login_attempts)
+            .get_result::<i32>(conn)
+            .await?;
+
+        if let Some(backoff) = account_backoff(attempts) {
+            diesel::update(users::table.find(user_id))
+                .set(users::locked_until.eq(now + backoff))
+                .execute(conn)
+                .await?;
+        }
+    }
+
+    let window_start = now - Duration::minutes(IP_WINDOW_MINUTES);
+
+    let attempts = diesel::insert_into(login_ip_attempts::table)
+        .values((
+            login_ip_attempts::ip.eq(ip),
+            login_ip_attempts::failed_attempts.eq(1),
+            login_ip_attempts::last_failed_at.eq(now),
+        ))
+        .on_conflict(login_ip_attempts::ip)
+        .do_update()
+        .set((
+            login_ip_attempts::failed_attempts.eq(sql::<Integer>(
+                "CASE WHEN login_ip_attempts.last_failed_at < ",
+            )
+            .bind::<Timestamp, _>(window_start)
+            .sql(" THEN 1 ELSE login_ip_attempts.failed_attempts + 1 END")),
+            login_ip_attempts::last_failed_at.eq(now),
+        ))
+        .returning(login_ip_attempts::failed_attempts)
+        .get_result::<i32>(conn)
+        .await?;
+
+    if attempts >= IP_LOCK_THRESHOLD {
+        diesel::update(login_ip_attempts::table.find(ip))
+            .set(login_ip_attempts::locked_until.eq(now + Duration::minutes(IP_LOCK_MINUTES)))
+            .execute(conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Resets the account counter after a successful login
+pub async fn record_success(
+    user_id: &Uuid,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), diesel::result::Error> {
+    use crate::schema::users;
+
+    diesel::update(users::table.find(user_id))
+        .filter(users::failed_login_attempts.ne(0))
+        .set((
+            users::failed_login_attempts.eq(0),
+            users::locked_until.eq(None::<NaiveDateTime>),
+        ))
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(forwarded_for: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn client_ip_is_the_peer_without_a_configured_header() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 4000));
+
+        assert_eq!(client_ip(peer, &headers("203.0.113.7"), None), "10.0.0.1");
+    }
+
+    #[test]
+    fn client_ip_takes_the_address_appended_by_the_proxy() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 4000));
+        let header = Some("X-Forwarded-For");
+
+        // The first entry is whatever the client sent, only the last one is trusted
+        assert_eq!(
+            client_ip(peer, &headers("198.51.100.1, 203.0.113.7"), header),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            client_ip(peer, &headers(" 2001:db8::1 "), header),
+            "2001:db8::1"
+        );
+        assert_eq!(client_ip(peer, &headers("unknown"), header), "10.0.0.1");
+        assert_eq!(client_ip(peer, &HeaderMap::new(), header), "10.0.0.1");
+    }
+
+    #[test]
+    fn account_backoff_doubles_up_to_the_cap() {
+        assert_eq!(account_backoff(ACCOUNT_LOCK_THRESHOLD - 1), None);
+        assert_eq!(
+            account_backoff(ACCOUNT_LOCK_THRESHOLD),
+            Some(Duration::minutes(1))
+        );
+        assert_eq!(
+            account_backoff(ACCOUNT_LOCK_THRESHOLD + 2),
+            Some(Duration::minutes(4))
+        );
+        assert_eq!(
+            account_backoff(i32::MAX),
+            Some(Duration::minutes(ACCOUNT_MAX_LOCK_MINUTES))
+        );
+    }
+}
@@ -1,3 +1,9 @@
+pub mod api_keys;
 pub mod handlers;
+pub mod keys;
+pub mod lockout;
 pub mod models;
+pub mod oidc;
+pub mod rbac;
 pub mod routes;
+pub mod totp;
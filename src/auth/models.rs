@@ -1,10 +1,10 @@
-use axum::{
-    Json, RequestPartsExt,
-    extract::FromRequestParts,
-    http::{StatusCode, request::Parts},
-    response::{IntoResponse, Response},
+use super::rbac::Permission;
+use crate::schema::{
+    api_keys, mfa_policies, mfa_recovery_codes, oidc_states, password_reset_tokens, refresh_tokens,
+    user_identities, users,
 };
-use axum_shop::schema::users;
+use crate::utils::AppError;
+use axum::response::{IntoResponse, Response};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use validator::Validate;
@@ -16,8 +16,15 @@ pub struct User {
     pub id: uuid::Uuid,
     pub email: String,
     pub password_hash: String,
-    pub hashed_rt: Option<String>,
     pub role: String,
+    pub email_verified_at: Option<chrono::NaiveDateTime>,
+    pub verification_sent_at: Option<chrono::NaiveDateTime>,
+    pub failed_login_attempts: i32,
+    pub locked_until: Option<chrono::NaiveDateTime>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<chrono::NaiveDateTime>,
+    pub totp_last_step: Option<i64>,
+    pub password_reset_sent_at: Option<chrono::NaiveDateTime>,
 }
 
 #[derive(Insertable, Deserialize, Debug, Validate)]
@@ -30,8 +37,8 @@ pub struct NewUser {
         max = 50,
         message = "Your password should be at least 6 symbols long"
     ))]
-    #[serde(rename = "password")]
-    pub password_hash: String,
+    #[diesel(column_name = password_hash)]
+    pub password: String,
 }
 
 #[derive(Deserialize, Insertable, Validate)]
@@ -47,6 +54,7 @@ pub struct SafeUser {
     pub id: uuid::Uuid,
     pub email: String,
     pub role: String,
+    pub email_verified_at: Option<chrono::NaiveDateTime>,
 }
 
 #[derive(Insertable, Deserialize, Debug, Validate, AsChangeset)]
@@ -72,6 +80,7 @@ pub struct UpdateUserPayload {
 pub struct LoginUser {
     #[validate(email)]
     pub email: String,
+    #[validate(length(min = 1, max = 50))]
     pub password: String,
 }
 
@@ -88,12 +97,23 @@ pub struct AccessTokenClaims {
     pub email: String,
     pub role: String,
     pub exp: usize,
+    /// Session (refresh token family) the token was issued for
+    #[serde(default)]
+    pub sid: Option<String>,
+    /// Set when the request was authenticated with an API key instead of a token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_id: Option<String>,
+    /// Permissions the API key is limited to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Permission>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RefreshTokenClaims {
     pub sub: String,
     pub exp: usize,
+    /// Id of the `refresh_tokens` row
+    pub jti: String,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -101,11 +121,118 @@ pub struct AccessToken {
     pub access_token: String,
 }
 
+/// One rotation of a session. Every refresh marks the row as used and
+/// inserts a new one with the same `family_id`.
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name=refresh_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RefreshToken {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub family_id: uuid::Uuid,
+    pub token_hash: String,
+    pub user_agent: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub used_at: Option<chrono::NaiveDateTime>,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name=refresh_tokens)]
+pub struct NewRefreshToken {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub family_id: uuid::Uuid,
+    pub token_hash: String,
+    pub user_agent: Option<String>,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
 #[derive(Serialize, Debug)]
-pub struct NewRefreshToken<'a> {
-    pub user_id: &'a uuid::Uuid,
-    pub token_hash: &'a String,
+pub struct Session {
+    pub id: uuid::Uuid,
+    pub user_agent: Option<String>,
+    pub last_used_at: chrono::NaiveDateTime,
     pub expires_at: chrono::NaiveDateTime,
+    pub current: bool,
+}
+
+/// Signed with `RT_SECRET`, the `purpose` field keeps refresh tokens from being used here
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmailVerificationClaims {
+    pub sub: String,
+    pub email: String,
+    pub purpose: String,
+    pub exp: usize,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct VerifyEmail {
+    #[validate(length(min = 1))]
+    pub token: String,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name=password_reset_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PasswordResetToken {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub token_hash: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+    pub used_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name=password_reset_tokens)]
+pub struct NewPasswordResetToken {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub token_hash: String,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ForgotPassword {
+    #[validate(email)]
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ResetPassword {
+    #[validate(length(min = 1))]
+    pub token: String,
+    #[validate(length(
+        min = 6,
+        max = 50,
+        message = "Your password should be at least 6 symbols long"
+    ))]
+    pub password: String,
+}
+
+/// Public verification key published at `/.well-known/jwks.json`
+#[derive(Serialize, Debug, Clone)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
 }
 
 #[derive(Serialize, Debug)]
@@ -114,17 +241,221 @@ pub struct Tokens {
     pub refresh_token: String,
 }
 
-#[derive(Debug, Serialize)]
+/// Signed with `RT_SECRET`. `purpose` tells a pending login apart from a
+/// login that has to enroll first because the role requires 2FA.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MfaPendingClaims {
+    pub sub: String,
+    pub purpose: String,
+    pub exp: usize,
+}
+
+/// Returned by login instead of `Tokens` when a second factor is needed
+#[derive(Serialize, Debug)]
+pub struct MfaChallenge {
+    pub mfa_token: String,
+    pub enrollment_required: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(Tokens),
+    MfaRequired(MfaChallenge),
+}
+
+/// Who is setting up 2FA: a signed in user, or one whose login was stopped
+/// by the role policy and holds an enrollment token
+#[derive(Debug)]
+pub struct MfaEnrollingUser {
+    pub id: uuid::Uuid,
+    pub from_login: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MfaEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+/// A TOTP code, or a recovery code where one is accepted
+#[derive(Deserialize, Debug, Validate)]
+pub struct MfaCode {
+    #[validate(length(min = 6, max = 32))]
+    pub code: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct VerifyMfa {
+    #[validate(length(min = 1))]
+    pub mfa_token: String,
+    #[validate(length(min = 6, max = 32))]
+    pub code: String,
+}
+
+/// Recovery codes are only shown once. `tokens` is set when enrollment
+/// finished a login.
+#[derive(Serialize, Debug)]
+pub struct MfaRecoveryCodes {
+    pub recovery_codes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Tokens>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name=mfa_recovery_codes)]
+pub struct NewMfaRecoveryCode {
+    pub user_id: uuid::Uuid,
+    pub code_hash: String,
+}
+
+#[derive(Queryable, Selectable, Serialize, Debug)]
+#[diesel(table_name=mfa_policies)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MfaPolicy {
+    pub role: String,
+    pub required: bool,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct UpdateMfaPolicy {
+    pub required: bool,
+}
+
+/// Only a SHA-256 of the key is stored, `prefix` helps users tell keys apart
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name=api_keys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiKey {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name=api_keys)]
+pub struct NewApiKey {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct CreateApiKey {
+    #[validate(length(min = 1, max = 100))]
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<Permission>,
+    /// Keys without an expiry stay valid until revoked
+    #[validate(range(min = 1, max = 365))]
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiKeyInfo {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub last_used_at: Option<chrono::NaiveDateTime>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            prefix: key.prefix,
+            scopes: key.scopes,
+            created_at: key.created_at,
+            last_used_at: key.last_used_at,
+            expires_at: key.expires_at,
+            revoked_at: key.revoked_at,
+        }
+    }
+}
+
+/// PKCE verifier and nonce of a login started with `oidc_authorize`, keyed by `state`
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name=oidc_states)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OidcState {
+    pub state: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name=oidc_states)]
+pub struct NewOidcState {
+    pub state: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub expires_at: chrono::NaiveDateTime,
+}
+
+/// External identity (`iss` + `sub` of the ID token) linked to a user
+#[derive(Insertable, Debug)]
+#[diesel(table_name=user_identities)]
+pub struct NewUserIdentity {
+    pub user_id: uuid::Uuid,
+    pub issuer: String,
+    pub subject: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// The plain key is only returned once, on creation
+#[derive(Serialize, Debug)]
+pub struct CreatedApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKeyInfo,
+}
+
+#[derive(Debug, Serialize, thiserror::Error)]
 pub enum AuthError {
+    #[error("Wrong credentials")]
     WrongCredentials,
+    #[error("Missing credentials")]
     MissingCredentials,
+    #[error("Token creation error")]
     TokenCreation,
+    #[error("Invalid token")]
     InvalidToken,
+    #[error("Task has failed")]
     FailedTask,
+    #[error("Token secret must be set")]
     MissingSecret,
+    #[error("Insufficient permissions")]
+    Forbidden,
+    #[error("Too many failed login attempts, try again later")]
+    TooManyAttempts,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum UserRole {
     User,
@@ -132,20 +463,60 @@ pub enum UserRole {
     Admin,
 }
 
+impl UserRole {
+    /// Value stored in `users.role` and in the access token
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::User => "user",
+            UserRole::Seller => "seller",
+            UserRole::Admin => "admin",
+        }
+    }
+
+    /// Two-factor authentication is offered to staff accounts only
+    pub fn supports_mfa(&self) -> bool {
+        matches!(self, UserRole::Admin | UserRole::Seller)
+    }
+}
+
+impl std::str::FromStr for UserRole {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(UserRole::User),
+            "seller" => Ok(UserRole::Seller),
+            "admin" => Ok(UserRole::Admin),
+            _ => Err(AuthError::Forbidden),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct UpdateUserRole {
+    pub role: UserRole,
+}
+
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AuthError::MissingSecret => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Token secret must be set",
-            ),
-            AuthError::FailedTask => (StatusCode::INTERNAL_SERVER_ERROR, "Task has failed"),
-            AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
-            AuthError::MissingCredentials => (StatusCode::BAD_REQUEST, "Missing credentials"),
-            AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error"),
-            AuthError::InvalidToken => (StatusCode::BAD_REQUEST, "Invalid token"),
-        };
-
-        (status, error_message).into_response()
+        AppError::from(self).into_response()
+    }
+}
+
+impl From<AuthError> for AppError {
+    fn from(err: AuthError) -> Self {
+        let message = err.to_string();
+
+        match err {
+            AuthError::WrongCredentials => AppError::Unauthorized(message),
+            AuthError::MissingCredentials | AuthError::InvalidToken => {
+                AppError::BadRequest(message)
+            }
+            AuthError::Forbidden => AppError::Forbidden(message),
+            AuthError::TooManyAttempts => AppError::TooManyRequests(message),
+            AuthError::TokenCreation | AuthError::FailedTask | AuthError::MissingSecret => {
+                AppError::internal(err)
+            }
+        }
     }
 }
@@ -0,0 +1,500 @@
+use crate::config::OidcConfig;
+use crate::utils::AppError;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use reqwest::header::ACCEPT;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use url::Url;
+use uuid::Uuid;
+
+/// Timeout for every request to the identity provider
+const PROVIDER_TIMEOUT_SECONDS: u64 = 10;
+
+static PROVIDER: OnceCell<ProviderMetadata> = OnceCell::const_new();
+
+static HTTP_CLIENT: OnceCell<reqwest::Client> = OnceCell::const_new();
+
+#[derive(Debug, thiserror::Error)]
+pub enum OidcError {
+    #[error("OpenID Connect login is not configured")]
+    NotConfigured,
+    #[error("Identity provider error: {0}")]
+    Provider(String),
+    #[error("Invalid or expired login state")]
+    InvalidState,
+    #[error("Invalid ID token")]
+    InvalidIdToken,
+    #[error("The identity provider has not verified your email")]
+    EmailNotVerified,
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+impl From<OidcError> for AppError {
+    fn from(err: OidcError) -> Self {
+        let message = err.to_string();
+
+        match err {
+            OidcError::NotConfigured => AppError::ServiceUnavailable(message),
+            OidcError::Provider(_) => AppError::BadGateway(message),
+            OidcError::InvalidState => AppError::BadRequest(message),
+            OidcError::InvalidIdToken => AppError::Unauthorized(message),
+            OidcError::EmailNotVerified => AppError::Forbidden(message),
+            OidcError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+/// Built from `OidcConfig`, the redirect url must point at
+/// `/api/auth/oidc/callback`. A plain `http` issuer works, so a local mock
+/// provider can be used in development.
+pub struct OidcSettings {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+}
+
+impl OidcSettings {
+    pub fn from_config(config: &OidcConfig) -> Result<Self, OidcError> {
+        let value = |value: &Option<String>| value.clone().ok_or(OidcError::NotConfigured);
+
+        Ok(Self {
+            issuer: value(&config.issuer)?.trim_end_matches('/').to_owned(),
+            client_id: value(&config.client_id)?,
+            client_secret: config.client_secret.clone(),
+            redirect_url: value(&config.redirect_url)?,
+        })
+    }
+}
+
+/// The parts of the discovery document the login flow needs
+#[derive(Deserialize, Debug)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub email: Option<String>,
+    /// Some providers send it as a string
+    #[serde(default)]
+    pub email_verified: Option<serde_json::Value>,
+    pub nonce: Option<String>,
+}
+
+impl IdTokenClaims {
+    /// The email, only when the provider vouches for it
+    pub fn verified_email(&self) -> Option<String> {
+        let verified = match &self.email_verified {
+            Some(serde_json::Value::Bool(verified)) => *verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        };
+
+        self.email
+            .as_ref()
+            .filter(|_| verified)
+            .map(|email| email.trim().to_lowercase())
+    }
+}
+
+/// Discovery document of `OIDC_ISSUER`, fetched once
+pub async fn provider(settings: &OidcSettings) -> Result<&'static ProviderMetadata, OidcError> {
+    PROVIDER
+        .get_or_try_init(|| async {
+            let url = format!("{}/.well-known/openid-configuration", settings.issuer);
+            let metadata: ProviderMetadata = get_json(&url).await?;
+
+            if metadata.issuer.trim_end_matches('/') != settings.issuer {
+                return Err(OidcError::Provider(format!(
+                    "issuer mismatch, discovery returned {}",
+                    metadata.issuer
+                )));
+            }
+
+            Ok(metadata)
+        })
+        .await
+}
+
+/// Cookie binding a login attempt to the browser that started it
+pub const STATE_COOKIE: &str = "oidc_state";
+
+/// `Set-Cookie` value carrying the login `state`, only sent to the callback.
+/// `SameSite=Lax` still lets the top level redirect from the provider carry it.
+pub fn state_cookie(
+    settings: &OidcSettings,
+    state: &str,
+    max_age_seconds: i64,
+) -> Result<String, OidcError> {
+    let url = Url::parse(&settings.redirect_url).map_err(|e| OidcError::Provider(e.to_string()))?;
+    let secure = if url.scheme() == "https" {
+        "; Secure"
+    } else {
+        ""
+    };
+
+    Ok(format!(
+        "{}={}; Max-Age={}; Path={}; HttpOnly; SameSite=Lax{}",
+        STATE_COOKIE,
+        state,
+        max_age_seconds,
+        url.path(),
+        secure
+    ))
+}
+
+/// Random `state`, `nonce` and PKCE verifier for one login attempt
+pub fn random_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// S256 code challenge of a PKCE verifier
+pub fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+pub fn authorization_url(
+    provider: &ProviderMetadata,
+    settings: &OidcSettings,
+    state: &str,
+    nonce: &str,
+    code_verifier: &str,
+) -> Result<String, OidcError> {
+    let mut url = Url::parse(&provider.authorization_endpoint)
+        .map_err(|e| OidcError::Provider(e.to_string()))?;
+
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &settings.client_id)
+        .append_pair("redirect_uri", &settings.redirect_url)
+        .append_pair("scope", "openid email profile")
+        .append_pair("state", state)
+        .append_pair("nonce", nonce)
+        .append_pair("code_challenge", &code_challenge(code_verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    Ok(url.into())
+}
+
+/// Exchanges the authorization code and returns the verified ID token claims
+pub async fn exchange_code(
+    provider: &ProviderMetadata,
+    settings: &OidcSettings,
+    code: &str,
+    code_verifier: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", settings.redirect_url.as_str()),
+        ("client_id", settings.client_id.as_str()),
+        ("code_verifier", code_verifier),
+    ];
+
+    if let Some(secret) = &settings.client_secret {
+        form.push(("client_secret", secret));
+    }
+
+    let tokens: TokenResponse = post_form_json(&provider.token_endpoint, &form).await?;
+    let jwks: JwkSet = get_json(&provider.jwks_uri).await?;
+
+    let claims = verify_id_token(&tokens.id_token, &jwks, provider, settings)?;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(OidcError::InvalidIdToken);
+    }
+
+    Ok(claims)
+}
+
+fn verify_id_token(
+    token: &str,
+    jwks: &JwkSet,
+    provider: &ProviderMetadata,
+    settings: &OidcSettings,
+) -> Result<IdTokenClaims, OidcError> {
+    let header = decode_header(token).map_err(|_| OidcError::InvalidIdToken)?;
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or(OidcError::InvalidIdToken)?;
+
+    // The header is not trusted to pick the algorithm, the provider key does
+    let algorithm = key_algorithm(jwk).ok_or(OidcError::InvalidIdToken)?;
+
+    if header.alg != algorithm {
+        return Err(OidcError::InvalidIdToken);
+    }
+
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| OidcError::InvalidIdToken)?;
+
+    let mut validation = Validation::new(algorithm);
+    validation.set_audience(&[&settings.client_id]);
+    validation.set_issuer(&[&provider.issuer]);
+
+    let data =
+        decode::<IdTokenClaims>(token, &key, &validation).map_err(|_| OidcError::InvalidIdToken)?;
+
+    Ok(data.claims)
+}
+
+/// Algorithm an ID token signed with `jwk` must use, from the key `alg` or
+/// else its type. Only RS256 and ES256 keys are accepted.
+fn key_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    match (&jwk.common.key_algorithm, &jwk.algorithm) {
+        (Some(KeyAlgorithm::RS256), AlgorithmParameters::RSA(_)) => Some(Algorithm::RS256),
+        (Some(KeyAlgorithm::ES256), AlgorithmParameters::EllipticCurve(params))
+            if params.curve == EllipticCurve::P256 =>
+        {
+            Some(Algorithm::ES256)
+        }
+        (None, AlgorithmParameters::RSA(_)) => Some(Algorithm::RS256),
+        (None, AlgorithmParameters::EllipticCurve(params))
+            if params.curve == EllipticCurve::P256 =>
+        {
+            Some(Algorithm::ES256)
+        }
+        _ => None,
+    }
+}
+
+/// Shared client for the provider calls, keeps connections to it alive
+async fn http_client() -> Result<&'static reqwest::Client, OidcError> {
+    HTTP_CLIENT
+        .get_or_try_init(|| async {
+            reqwest::Client::builder()
+                .timeout(Duration::from_secs(PROVIDER_TIMEOUT_SECONDS))
+                .user_agent("axum-shop")
+                .build()
+                .map_err(|e| OidcError::Provider(e.to_string()))
+        })
+        .await
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, OidcError> {
+    let request = http_client().await?.get(url);
+
+    send(request).await
+}
+
+async fn post_form_json<T: DeserializeOwned>(
+    url: &str,
+    form: &[(&str, &str)],
+) -> Result<T, OidcError> {
+    let request = http_client().await?.post(url).form(form);
+
+    send(request).await
+}
+
+async fn send<T: DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T, OidcError> {
+    let response = request
+        .header(ACCEPT, "application/json")
+        .send()
+        .await
+        .map_err(|e| OidcError::Provider(e.to_string()))?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        let url = response.url().clone();
+        let body = response.text().await.unwrap_or_default();
+
+        return Err(OidcError::Provider(format!(
+            "{} returned {}: {}",
+            url, status, body
+        )));
+    }
+
+    response
+        .json()
+        .await
+        .map_err(|e| OidcError::Provider(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use std::path::Path;
+
+    const ISSUER: &str = "https://id.example.com";
+    const CLIENT_ID: &str = "shop";
+    /// Modulus of `testdata/rsa.pem`
+    const RSA_N: &str = "mUlUM-AyrYBWCbkTM_VHNv2Go5d8mCKyjb65IgW2qIjcSP_x12rcYMjusox_Gy90ddd7_5ny80E6ieSbFpFBny-Ogs5iZbtsMMmOPxID499USuInDU5nAgTkWL4F_jxF5FFER5P8Ze_M680j7fJ84MeuVs-6G9qznfwThrBLVOUjJ1pfqU2bH755WcWD16KU8NoAA_wa2F69pWsfl33tMX7zZDQnc07mK1Mso92oP1A4fSy2XH4L7qfj-LAGSZNC2EBd5g05NewsjI9glaiVBljq49eJlM8poqmtJd_mZZZNhwO88LvyBpLzFftLWOw3Snno64tJ7-Jto-WA-v1CFQ";
+
+    fn provider() -> ProviderMetadata {
+        ProviderMetadata {
+            issuer: ISSUER.to_owned(),
+            authorization_endpoint: format!("{}/authorize", ISSUER),
+            token_endpoint: format!("{}/token", ISSUER),
+            jwks_uri: format!("{}/jwks", ISSUER),
+        }
+    }
+
+    fn settings() -> OidcSettings {
+        OidcSettings {
+            issuer: ISSUER.to_owned(),
+            client_id: CLIENT_ID.to_owned(),
+            client_secret: None,
+            redirect_url: "http://localhost/api/auth/oidc/callback".to_owned(),
+        }
+    }
+
+    fn jwk(value: serde_json::Value) -> Jwk {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn rsa_jwks(alg: Option<&str>) -> JwkSet {
+        let mut key = serde_json::json!({ "kty": "RSA", "kid": "rsa", "n": RSA_N, "e": "AQAB" });
+
+        if let Some(alg) = alg {
+            key["alg"] = alg.into();
+        }
+
+        JwkSet {
+            keys: vec![jwk(key)],
+        }
+    }
+
+    fn id_token(header: Header, key: &EncodingKey) -> String {
+        let claims = serde_json::json!({
+            "iss": ISSUER,
+            "aud": CLIENT_ID,
+            "sub": "provider-user",
+            "exp": usize::MAX,
+            "email": "jane@example.com",
+            "email_verified": true,
+        });
+
+        encode(&header, &claims, key).unwrap()
+    }
+
+    fn rsa_signing_key() -> EncodingKey {
+        let pem =
+            std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("src/auth/testdata/rsa.pem"))
+                .unwrap();
+
+        EncodingKey::from_rsa_pem(&pem).unwrap()
+    }
+
+    fn header(alg: Algorithm) -> Header {
+        Header {
+            kid: Some("rsa".to_owned()),
+            ..Header::new(alg)
+        }
+    }
+
+    #[test]
+    fn key_algorithm_allows_only_rs256_and_es256_keys() {
+        let rsa = serde_json::json!({ "kty": "RSA", "n": RSA_N, "e": "AQAB" });
+        let p256 = serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": "MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4",
+            "y": "4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM",
+        });
+        let p384 = serde_json::json!({
+            "kty": "EC",
+            "crv": "P-384",
+            "x": "iA7lWQLzVrKGEFjfGMfMHfTEZ2KnLezBlMhOWN1aX0k7ElPxKH-DQSVuTJXxmmSE",
+            "y": "wCBrIBWkuJEtn2ZnzI6wK-RkLq8UTf2hlnLBIS7bkGB3jhKIKQGfIKwKSRrLqf6W",
+        });
+        let okp = serde_json::json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "x": "qBAbNC67dt8kWW0Iqd9Pm1fAif5OB9AF-z-jVdXXhm4",
+        });
+
+        let with_alg = |key: &serde_json::Value, alg: &str| {
+            let mut key = key.clone();
+            key["alg"] = alg.into();
+            jwk(key)
+        };
+
+        assert_eq!(key_algorithm(&jwk(rsa.clone())), Some(Algorithm::RS256));
+        assert_eq!(
+            key_algorithm(&with_alg(&rsa, "RS256")),
+            Some(Algorithm::RS256)
+        );
+        assert_eq!(key_algorithm(&jwk(p256.clone())), Some(Algorithm::ES256));
+        assert_eq!(
+            key_algorithm(&with_alg(&p256, "ES256")),
+            Some(Algorithm::ES256)
+        );
+
+        assert_eq!(key_algorithm(&with_alg(&rsa, "RS512")), None);
+        assert_eq!(key_algorithm(&with_alg(&rsa, "ES256")), None);
+        assert_eq!(key_algorithm(&jwk(p384)), None);
+        assert_eq!(key_algorithm(&jwk(okp)), None);
+    }
+
+    #[test]
+    fn verifies_id_token_signed_with_the_provider_key() {
+        let token = id_token(header(Algorithm::RS256), &rsa_signing_key());
+
+        let claims = verify_id_token(&token, &rsa_jwks(None), &provider(), &settings()).unwrap();
+
+        assert_eq!(claims.sub, "provider-user");
+        assert_eq!(claims.verified_email().as_deref(), Some("jane@example.com"));
+    }
+
+    #[test]
+    fn rejects_id_token_with_another_algorithm_than_the_key() {
+        let rsa = rsa_signing_key();
+
+        // Same RSA key, but an algorithm the provider key does not advertise
+        let rs512 = id_token(header(Algorithm::RS512), &rsa);
+        // The public modulus used as an HMAC secret
+        let hs256 = id_token(
+            header(Algorithm::HS256),
+            &EncodingKey::from_secret(RSA_N.as_bytes()),
+        );
+
+        for jwks in [rsa_jwks(None), rsa_jwks(Some("RS256"))] {
+            for token in [&rs512, &hs256] {
+                assert!(matches!(
+                    verify_id_token(token, &jwks, &provider(), &settings()),
+                    Err(OidcError::InvalidIdToken)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_id_token_for_another_audience_or_issuer() {
+        let token = id_token(header(Algorithm::RS256), &rsa_signing_key());
+        let jwks = rsa_jwks(None);
+
+        let other_client = OidcSettings {
+            client_id: "other".to_owned(),
+            ..settings()
+        };
+        let other_issuer = ProviderMetadata {
+            issuer: "https://evil.example.com".to_owned(),
+            ..provider()
+        };
+
+        assert!(verify_id_token(&token, &jwks, &provider(), &other_client).is_err());
+        assert!(verify_id_token(&token, &jwks, &other_issuer, &settings()).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use super::models::{AccessTokenClaims, AuthError, UserRole};
+use crate::utils::AppError;
+use crate::utils::types::AppState;
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Actions guarded by a role check, also used as API key scopes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub enum Permission {
+    ManageProducts,
+    ManageStock,
+    ManageCategories,
+    ManageDiscounts,
+    ManageCoupons,
+    ManageOrders,
+    ManageUsers,
+    ManageExchangeRates,
+}
+
+impl Permission {
+    /// Value stored in `api_keys.scopes`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::ManageProducts => "manage_products",
+            Permission::ManageStock => "manage_stock",
+            Permission::ManageCategories => "manage_categories",
+            Permission::ManageDiscounts => "manage_discounts",
+            Permission::ManageCoupons => "manage_coupons",
+            Permission::ManageOrders => "manage_orders",
+            Permission::ManageUsers => "manage_users",
+            Permission::ManageExchangeRates => "manage_exchange_rates",
+        }
+    }
+}
+
+impl std::str::FromStr for Permission {
+    type Err = AuthError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manage_products" => Ok(Permission::ManageProducts),
+            "manage_stock" => Ok(Permission::ManageStock),
+            "manage_categories" => Ok(Permission::ManageCategories),
+            "manage_discounts" => Ok(Permission::ManageDiscounts),
+            "manage_coupons" => Ok(Permission::ManageCoupons),
+            "manage_orders" => Ok(Permission::ManageOrders),
+            "manage_users" => Ok(Permission::ManageUsers),
+            "manage_exchange_rates" => Ok(Permission::ManageExchangeRates),
+            _ => Err(AuthError::Forbidden),
+        }
+    }
+}
+
+impl UserRole {
+    /// The permission matrix, every role not listed here gets a 403
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        use Permission::*;
+
+        match self {
+            UserRole::Admin => true,
+            UserRole::Seller => {
+                matches!(permission, ManageProducts | ManageStock | ManageDiscounts)
+            }
+            UserRole::User => false,
+        }
+    }
+}
+
+impl AccessTokenClaims {
+    /// Unknown roles have no permissions. API keys are further limited to their scopes.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        let scoped = self
+            .scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.contains(&permission));
+
+        scoped
+            && self
+                .role
+                .parse::<UserRole>()
+                .is_ok_and(|role| role.has_permission(permission))
+    }
+
+    /// Ownership policy for user data: the owner or a user manager
+    pub fn can_access(&self, owner_id: &Uuid) -> bool {
+        self.sub == owner_id.to_string() || self.has_permission(Permission::ManageUsers)
+    }
+
+    pub fn authorize_owner(&self, owner_id: &Uuid) -> Result<(), AppError> {
+        if !self.can_access(owner_id) {
+            return Err(AppError::Forbidden(
+                "You cannot access this resource".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Route layer rejecting requests whose access token role lacks the permission,
+/// used with `middleware::from_fn_with_state(RequireRole(..), RequireRole::middleware)`
+#[derive(Debug, Clone, Copy)]
+pub struct RequireRole(pub Permission);
+
+impl RequireRole {
+    /// Reads the claims left by `load_claims`, the layer state holds no keys to decode them
+    pub async fn middleware(
+        State(RequireRole(permission)): State<RequireRole>,
+        req: Request,
+        next: Next,
+    ) -> Result<Response, AuthError> {
+        let claims = req
+            .extensions()
+            .get::<AccessTokenClaims>()
+            .ok_or(AuthError::InvalidToken)?;
+
+        if !claims.has_permission(permission) {
+            return Err(AuthError::Forbidden);
+        }
+
+        Ok(next.run(req).await)
+    }
+}
+
+/// Decodes the bearer access token into the request extensions ahead of the
+/// route layers. Requests without a valid token pass through and are rejected
+/// by whatever needs the claims.
+pub async fn load_claims(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+
+    if let Ok(claims) = AccessTokenClaims::from_request_parts(&mut parts, &state).await {
+        parts.extensions.insert(claims);
+    }
+
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(sub: &Uuid, role: &str, scopes: Option<Vec<Permission>>) -> AccessTokenClaims {
+        AccessTokenClaims {
+            sub: sub.to_string(),
+            email: "jane@example.com".to_owned(),
+            role: role.to_owned(),
+            exp: 0,
+            sid: None,
+            api_key_id: None,
+            scopes,
+        }
+    }
+
+    #[test]
+    fn owner_can_access_own_data() {
+        let owner = Uuid::new_v4();
+        let claims = claims(&owner, "user", None);
+
+        assert!(claims.can_access(&owner));
+        assert!(claims.authorize_owner(&owner).is_ok());
+    }
+
+    #[test]
+    fn other_user_without_manage_users_is_forbidden() {
+        let owner = Uuid::new_v4();
+
+        for role in ["user", "seller", "unknown"] {
+            let claims = claims(&Uuid::new_v4(), role, None);
+
+            assert!(!claims.can_access(&owner), "role {}", role);
+            assert!(matches!(
+                claims.authorize_owner(&owner),
+                Err(AppError::Forbidden(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn user_manager_can_access_other_users_data() {
+        let owner = Uuid::new_v4();
+        let claims = claims(&Uuid::new_v4(), "admin", None);
+
+        assert!(claims.can_access(&owner));
+        assert!(claims.authorize_owner(&owner).is_ok());
+    }
+
+    #[test]
+    fn api_key_needs_manage_users_scope_for_other_users_data() {
+        let owner = Uuid::new_v4();
+        let admin = Uuid::new_v4();
+
+        let scoped = claims(&admin, "admin", Some(vec![Permission::ManageOrders]));
+        assert!(matches!(
+            scoped.authorize_owner(&owner),
+            Err(AppError::Forbidden(_))
+        ));
+        assert!(scoped.authorize_owner(&admin).is_ok());
+
+        let manage_users = claims(&admin, "admin", Some(vec![Permission::ManageUsers]));
+        assert!(manage_users.authorize_owner(&owner).is_ok());
+    }
+}
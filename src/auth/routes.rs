@@ -1,25 +1,65 @@
 use axum::{
-    Router,
-    routing::{get, patch, post},
+    Router, middleware,
+    routing::{delete, get, patch, post, put},
 };
 
 use super::handlers;
-use crate::utils::types::Pool;
+use super::rbac::{Permission, RequireRole};
+use crate::utils::types::AppState;
 
-pub fn get_routes() -> Router<Pool> {
-    Router::new()
-        .route(
-            "/users",
-            get(handlers::get_all_users).post(handlers::create_user),
-        )
+pub fn get_routes() -> Router<AppState> {
+    let public = Router::new()
+        .route("/users", post(handlers::create_user))
         .route("/users/me", get(handlers::get_current_user))
         .route(
             "/users/{id}",
-            patch(handlers::update_user_email_or_password)
-                .get(handlers::get_user_by_id)
-                .delete(handlers::delete_user),
+            patch(handlers::update_user_email_or_password),
         )
         .route("/auth/login", post(handlers::login_user))
         .route("/auth/logout", post(handlers::logout))
         .route("/auth/refresh", post(handlers::refresh_token))
+        .route("/auth/oidc/authorize", get(handlers::oidc_authorize))
+        .route("/auth/oidc/callback", get(handlers::oidc_callback))
+        .route("/auth/verify-email", post(handlers::verify_email))
+        .route(
+            "/auth/verify-email/resend",
+            post(handlers::resend_verification_email),
+        )
+        .route("/auth/forgot-password", post(handlers::forgot_password))
+        .route("/auth/reset-password", post(handlers::reset_password))
+        .route("/auth/mfa", delete(handlers::disable_mfa))
+        .route("/auth/mfa/enroll", post(handlers::enroll_mfa))
+        .route("/auth/mfa/confirm", post(handlers::confirm_mfa))
+        .route("/auth/mfa/verify", post(handlers::verify_mfa))
+        .route(
+            "/auth/mfa/recovery-codes",
+            post(handlers::regenerate_recovery_codes),
+        )
+        .route(
+            "/me/sessions",
+            get(handlers::get_sessions).delete(handlers::delete_other_sessions),
+        )
+        .route("/me/sessions/{id}", delete(handlers::delete_session))
+        .route(
+            "/me/api-keys",
+            get(handlers::get_api_keys).post(handlers::create_api_key),
+        )
+        .route("/me/api-keys/{id}", delete(handlers::revoke_api_key));
+
+    let manage_users = Router::new()
+        .route("/users", get(handlers::get_all_users))
+        .route(
+            "/users/{id}",
+            get(handlers::get_user_by_id).delete(handlers::delete_user),
+        )
+        .route("/users/{id}/role", patch(handlers::update_user_role))
+        .route("/users/{id}/unlock", post(handlers::unlock_user))
+        .route("/auth/mfa/policies", get(handlers::get_mfa_policies))
+        .route("/auth/mfa/policies/{role}", put(handlers::set_mfa_policy))
+        .route_layer(middleware::from_fn_with_state(
+            RequireRole(Permission::ManageUsers),
+            RequireRole::middleware,
+        ));
+
+    public.merge(manage_users)
 }
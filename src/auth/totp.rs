@@ -0,0 +1,197 @@
+use chrono::Utc;
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Digits in a code, the default every authenticator app expects
+const DIGITS: u32 = 6;
+
+/// Seconds a code is valid for
+const PERIOD: i64 = 30;
+
+/// Steps accepted on each side of the current one, covers clock drift
+const SKEW: i64 = 1;
+
+/// Recovery codes handed out on enrollment
+pub const RECOVERY_CODES: usize = 10;
+
+/// Everything but the RFC 3986 unreserved characters is escaped in the key URI
+const URI_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Base32 secret of 20 bytes, the key size RFC 4226 recommends for HMAC-SHA1
+pub fn generate_secret() -> String {
+    let bytes: Vec<u8> = Uuid::new_v4()
+        .as_bytes()
+        .iter()
+        .chain(Uuid::new_v4().as_bytes())
+        .copied()
+        .take(20)
+        .collect();
+
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// Key URI scanned by authenticator apps, the issuer is `auth.totp_issuer`
+pub fn otpauth_uri(secret: &str, account: &str, issuer: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        utf8_percent_encode(issuer, URI_COMPONENT),
+        utf8_percent_encode(account, URI_COMPONENT),
+        secret,
+        utf8_percent_encode(issuer, URI_COMPONENT),
+        DIGITS,
+        PERIOD
+    )
+}
+
+/// Returns the time step the code belongs to. Steps up to `last_step` are
+/// rejected so a code cannot be used twice.
+pub fn verify(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let code: u32 = code.parse().ok()?;
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+
+    verify_at(&key, code, last_step, Utc::now().timestamp() / PERIOD)
+}
+
+fn verify_at(key: &[u8], code: u32, last_step: Option<i64>, current: i64) -> Option<i64> {
+    (current - SKEW..=current + SKEW)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| code_at(key, *step) == code)
+}
+
+/// RFC 6238 code for a time step
+fn code_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&(step as u64).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    binary % 10_u32.pow(DIGITS)
+}
+
+/// Codes look like `1a2b3-c4d5e`
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let random = Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &random[..5], &random[5..10])
+        })
+        .collect()
+}
+
+/// Recovery codes are random, so an unsalted SHA-256 is enough and lets
+/// them be looked up by hash. Dashes, spaces and case are ignored.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    HEXLOWER.encode(&Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Seed of the RFC 6238 Appendix B SHA-1 vectors
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn code_at_matches_rfc_6238_vectors() {
+        // (time, 8 digit code), only the last `DIGITS` digits are compared
+        let vectors: [(i64, u32); 6] = [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ];
+
+        for (time, code) in vectors {
+            assert_eq!(
+                code_at(RFC_KEY, time / PERIOD),
+                code % 10_u32.pow(DIGITS),
+                "time {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn verify_accepts_one_step_of_skew() {
+        let current = 1234567890 / PERIOD;
+
+        for step in [current - 1, current, current + 1] {
+            let code = code_at(RFC_KEY, step);
+            assert_eq!(verify_at(RFC_KEY, code, None, current), Some(step));
+        }
+
+        for step in [current - 2, current + 2] {
+            let code = code_at(RFC_KEY, step);
+            assert_eq!(verify_at(RFC_KEY, code, None, current), None);
+        }
+    }
+
+    #[test]
+    fn verify_rejects_replayed_steps() {
+        let current = 1234567890 / PERIOD;
+        let code = code_at(RFC_KEY, current);
+
+        assert_eq!(verify_at(RFC_KEY, code, Some(current), current), None);
+        assert_eq!(verify_at(RFC_KEY, code, Some(current + 1), current), None);
+        assert_eq!(
+            verify_at(RFC_KEY, code, Some(current - 1), current),
+            Some(current)
+        );
+
+        let previous = code_at(RFC_KEY, current - 1);
+        assert_eq!(
+            verify_at(RFC_KEY, previous, Some(current - 1), current),
+            None
+        );
+    }
+
+    #[test]
+    fn verify_rejects_malformed_codes() {
+        let secret = BASE32_NOPAD.encode(RFC_KEY);
+
+        for code in ["", "12345", "1234567", "12a456", "-12345"] {
+            assert_eq!(verify(&secret, code, None), None, "code {:?}", code);
+        }
+
+        assert_eq!(verify("not base32!", "123456", None), None);
+    }
+
+    #[test]
+    fn otpauth_uri_escapes_issuer_and_account() {
+        let uri = otpauth_uri("JBSWY3DP", "jane+shop@example.com", "Rust Shop");
+
+        assert_eq!(
+            uri,
+            "otpauth://totp/Rust%20Shop:jane%2Bshop%40example.com?secret=JBSWY3DP&issuer=Rust%20Shop&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
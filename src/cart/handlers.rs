@@ -1,13 +1,14 @@
-use super::models::{Cart, CartWithProducts, ProductCarts, ProductsToCart};
-use crate::auth::models::User;
+use super::models::{Cart, CartLine, CartWithProducts, ProductCarts, ProductsToCart};
+use crate::coupon::handlers::{coupon_savings, find_coupon_by_code};
+use crate::coupon::models::{AppliedCoupon, Coupon, CouponCode, CouponError};
+use crate::currency::models::{ConvertPrices, DisplayCurrency};
+use crate::discount::pricing::{PriceResolver, get_product_categories};
+use crate::product::models::ProductWithQty;
+use crate::utils::ValidatedJson;
 use crate::utils::types::Pool;
-use crate::{auth::models::AccessTokenClaims, utils::internal_error};
-use axum::{
-    extract::{Json, Path, State},
-    http::StatusCode,
-};
-use axum_validated_extractors::ValidatedJson;
-use diesel::row;
+use crate::utils::{Money, money::BASE_CURRENCY};
+use crate::{auth::models::AccessTokenClaims, utils::AppError};
+use axum::extract::{Json, State};
 use diesel::{dsl::sql, prelude::*};
 use diesel_async::pooled_connection::AsyncDieselConnectionManager;
 use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
@@ -15,10 +16,10 @@ use uuid::Uuid;
 
 pub async fn get_all_cart(
     State(pool): State<Pool>,
-) -> Result<Json<Vec<CartWithProducts>>, (StatusCode, String)> {
-    use axum_shop::schema::{cart_products, carts, products};
+) -> Result<Json<Vec<CartWithProducts>>, AppError> {
+    use crate::schema::{cart_products, carts, products};
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let rows = carts::table
         .left_join(cart_products::table.on(carts::id.eq(cart_products::cart_id)))
@@ -31,7 +32,7 @@ pub async fn get_all_cart(
                 json_build_object(
                     'id', products.id,
                     'title', products.title,
-                    'price', products.price,
+                    'price', products.price::text,
                     'description', products.description,
                     'image', products.image,
                     'quantity', cart_products.quantity
@@ -43,32 +44,48 @@ pub async fn get_all_cart(
         ))
         .group_by(carts::id)
         .load::<(Cart, serde_json::Value)>(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
-    let res = rows
+    let carts: Vec<(Cart, Vec<ProductWithQty>)> = rows
         .into_iter()
         .map(|(cart, products_json)| {
             let products = serde_json::from_value(products_json).unwrap_or_default();
-            CartWithProducts { cart, products }
+            (cart, products)
         })
         .collect();
 
+    let product_ids: Vec<i32> = carts
+        .iter()
+        .flat_map(|(_, products)| products.iter().map(|prod| prod.id))
+        .collect();
+
+    let resolver = PriceResolver::load(&mut conn).await?;
+    let categories = get_product_categories(&product_ids, &mut conn).await?;
+
+    let mut res = Vec::with_capacity(carts.len());
+
+    for (cart, products) in carts {
+        let cart = build_cart(cart, products, &resolver, &categories, &mut conn).await?;
+
+        res.push(cart);
+    }
+
     Ok(Json(res))
 }
 
 pub async fn add_products_to_cart(
     State(pool): State<Pool>,
     claims: AccessTokenClaims,
-    Json(payload): Json<ProductsToCart>,
-) -> Result<Json<CartWithProducts>, (StatusCode, String)> {
-    use axum_shop::schema::{cart_products, carts, products, users};
+    currency: DisplayCurrency,
+    ValidatedJson(payload): ValidatedJson<ProductsToCart>,
+) -> Result<Json<CartWithProducts>, AppError> {
+    use crate::schema::{cart_products, carts, products};
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let user_id = Uuid::parse_str(&claims.sub).unwrap();
 
-    let res = conn
+    let mut res = conn
         .transaction::<CartWithProducts, diesel::result::Error, _>(move |mut conn| {
             Box::pin(async move {
                 let cart = carts::table
@@ -93,6 +110,27 @@ pub async fn add_products_to_cart(
                     prods_qty.insert(prod.product_id, prod.quantity);
                 }
 
+                // `None` for products whose stock is not tracked
+                let available: std::collections::HashMap<i32, Option<i32>> = products::table
+                    .filter(products::id.eq_any(&ids))
+                    .select((
+                        products::id,
+                        products::stock - products::reserved.nullable(),
+                    ))
+                    .load::<(i32, Option<i32>)>(&mut conn)
+                    .await?
+                    .into_iter()
+                    .collect();
+
+                for item in payload.items.iter() {
+                    let in_cart = prods_qty.get(&item.product_id).unwrap_or(&0);
+                    let in_stock = available.get(&item.product_id).unwrap_or(&Some(0));
+
+                    if in_stock.is_some_and(|in_stock| in_cart + item.quantity > in_stock) {
+                        return Err(diesel::result::Error::RollbackTransaction);
+                    }
+                }
+
                 for item in payload.items.iter() {
                     let product = ProductCarts {
                         cart_id: cart.id,
@@ -130,13 +168,20 @@ pub async fn add_products_to_cart(
                     .get_result(&mut conn)
                     .await?;
 
-                let updated_cart = get_cart_with_products(&cart.id, &mut conn).await?;
+                let updated_cart = get_cart_with_products(&cart.id, conn).await?;
 
                 Ok(updated_cart)
             })
         })
         .await
-        .map_err(internal_error)?;
+        .map_err(|e| match e {
+            diesel::result::Error::RollbackTransaction => {
+                AppError::Conflict("Requested quantity exceeds available stock".to_owned())
+            }
+            e => e.into(),
+        })?;
+
+    res.convert_prices(&currency);
 
     Ok(Json(res))
 }
@@ -144,23 +189,17 @@ pub async fn add_products_to_cart(
 pub async fn remove_product_from_cart(
     State(pool): State<Pool>,
     claims: AccessTokenClaims,
-    Json(payload): Json<ProductsToCart>,
-) -> Result<Json<CartWithProducts>, (StatusCode, String)> {
-    use axum_shop::schema::{cart_products, carts, products, users};
+    currency: DisplayCurrency,
+    ValidatedJson(payload): ValidatedJson<ProductsToCart>,
+) -> Result<Json<CartWithProducts>, AppError> {
+    use crate::schema::{cart_products, carts};
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
-
-    if payload.items.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Product ids cannot be empty!".to_owned(),
-        ));
-    }
+    let mut conn = pool.get().await?;
 
     let user_id = Uuid::parse_str(&claims.sub).unwrap();
 
-    let res = conn
-        .transaction::<CartWithProducts, diesel::result::Error, _>(move |mut conn| {
+    let mut res = conn
+        .transaction::<CartWithProducts, AppError, _>(move |mut conn| {
             Box::pin(async move {
                 let cart = carts::table
                     .filter(carts::user_id.eq(&user_id))
@@ -185,14 +224,18 @@ pub async fn remove_product_from_cart(
                 }
 
                 for item in payload.items.iter() {
-                    if !prods_qty.contains_key(&item.product_id) {
-                        return Err(diesel::result::Error::RollbackTransaction);
-                    }
-
-                    let cur_qty = prods_qty.get(&item.product_id).unwrap();
+                    let Some(cur_qty) = prods_qty.get(&item.product_id) else {
+                        return Err(AppError::NotFound(format!(
+                            "Product {} is not in the cart",
+                            item.product_id
+                        )));
+                    };
 
                     if &item.quantity > cur_qty {
-                        return Err(diesel::result::Error::RollbackTransaction);
+                        return Err(AppError::BadRequest(format!(
+                            "Cannot remove more than {} of product {}",
+                            cur_qty, item.product_id
+                        )));
                     } else if &item.quantity == cur_qty {
                         diesel::delete(
                             cart_products::table.filter(
@@ -226,22 +269,100 @@ pub async fn remove_product_from_cart(
                     .get_result(&mut conn)
                     .await?;
 
-                let updated_cart = get_cart_with_products(&cart.id, &mut conn).await?;
+                let updated_cart = get_cart_with_products(&cart.id, conn).await?;
 
                 Ok(updated_cart)
             })
         })
-        .await
-        .map_err(internal_error)?;
+        .await?;
+
+    res.convert_prices(&currency);
 
     Ok(Json(res))
 }
 
-async fn get_cart_with_products(
+pub async fn apply_coupon(
+    State(pool): State<Pool>,
+    claims: AccessTokenClaims,
+    currency: DisplayCurrency,
+    ValidatedJson(payload): ValidatedJson<CouponCode>,
+) -> Result<Json<CartWithProducts>, AppError> {
+    use crate::schema::carts;
+
+    let mut conn = pool.get().await?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
+
+    let coupon = find_coupon_by_code(&payload.code, &mut conn).await?;
+
+    let cart = carts::table
+        .filter(carts::user_id.eq(&user_id))
+        .select(Cart::as_select())
+        .get_result(&mut conn)
+        .await?;
+
+    let cart_with_products = get_cart_with_products(&cart.id, &mut conn).await?;
+
+    let product_ids: Vec<i32> = cart_with_products
+        .products
+        .iter()
+        .map(|line| line.product.id)
+        .collect();
+
+    let categories = get_product_categories(&product_ids, &mut conn).await?;
+
+    coupon_savings(
+        &coupon,
+        &user_id,
+        &cart_with_products.products,
+        &categories,
+        &mut conn,
+    )
+    .await?;
+
+    diesel::update(carts::table.find(&cart.id))
+        .set(carts::coupon_id.eq(coupon.id))
+        .execute(&mut conn)
+        .await?;
+
+    let mut res = get_cart_with_products(&cart.id, &mut conn).await?;
+
+    res.convert_prices(&currency);
+
+    Ok(Json(res))
+}
+
+pub async fn remove_coupon(
+    State(pool): State<Pool>,
+    claims: AccessTokenClaims,
+    currency: DisplayCurrency,
+) -> Result<Json<CartWithProducts>, AppError> {
+    use crate::schema::carts;
+
+    let mut conn = pool.get().await?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
+
+    let cart = diesel::update(carts::table.filter(carts::user_id.eq(&user_id)))
+        .set(carts::coupon_id.eq(None::<i32>))
+        .returning(Cart::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    let mut res = get_cart_with_products(&cart.id, &mut conn).await?;
+
+    res.convert_prices(&currency);
+
+    Ok(Json(res))
+}
+
+pub async fn get_cart_with_products(
     cart_id: &i32,
     conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
 ) -> std::result::Result<CartWithProducts, diesel::result::Error> {
-    use axum_shop::schema::{cart_products, carts, products, users};
+    use crate::schema::{cart_products, carts, products};
 
     let (cart, products) = carts::table
         .find(cart_id)
@@ -255,7 +376,7 @@ async fn get_cart_with_products(
                 json_build_object(
                     'id', products.id,
                     'title', products.title,
-                    'price', products.price,
+                    'price', products.price::text,
                     'description', products.description,
                     'image', products.image,
                     'quantity', cart_products.quantity
@@ -270,10 +391,88 @@ async fn get_cart_with_products(
         .get_result::<(Cart, serde_json::Value)>(conn)
         .await?;
 
-    let updated_cart = CartWithProducts {
-        cart,
-        products: serde_json::from_value(products).unwrap_or_default(),
-    };
+    let products: Vec<ProductWithQty> = serde_json::from_value(products).unwrap_or_default();
+    let product_ids: Vec<i32> = products.iter().map(|prod| prod.id).collect();
+
+    let resolver = PriceResolver::load(conn).await?;
+    let categories = get_product_categories(&product_ids, conn).await?;
+
+    let updated_cart = build_cart(cart, products, &resolver, &categories, conn).await?;
 
     Ok(updated_cart)
 }
+
+async fn build_cart(
+    cart: Cart,
+    products: Vec<ProductWithQty>,
+    resolver: &PriceResolver,
+    categories: &std::collections::HashMap<i32, Vec<i32>>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> std::result::Result<CartWithProducts, diesel::result::Error> {
+    use crate::schema::coupons;
+
+    let lines: Vec<CartLine> = products
+        .into_iter()
+        .map(|product| {
+            let category_ids = categories
+                .get(&product.id)
+                .map(Vec::as_slice)
+                .unwrap_or(&[]);
+            let pricing = resolver.price_for(product.id, category_ids, &product.price);
+            let line_total = &pricing.discounted_price * product.quantity;
+
+            CartLine {
+                product,
+                pricing,
+                line_total,
+            }
+        })
+        .collect();
+
+    let subtotal: Money = lines
+        .iter()
+        .map(|line| &line.pricing.original_price * line.product.quantity)
+        .sum();
+    let mut total: Money = lines.iter().map(|line| line.line_total.clone()).sum();
+
+    let coupon = match cart.coupon_id {
+        Some(coupon_id) => {
+            let coupon = coupons::table
+                .find(coupon_id)
+                .select(Coupon::as_select())
+                .get_result(conn)
+                .await?;
+
+            let applied =
+                match coupon_savings(&coupon, &cart.user_id, &lines, categories, conn).await {
+                    Ok(savings) => AppliedCoupon {
+                        code: coupon.code,
+                        discount_id: coupon.discount_id,
+                        savings,
+                        error: None,
+                    },
+                    Err(CouponError::Database(e)) => return Err(e),
+                    Err(e) => AppliedCoupon {
+                        code: coupon.code,
+                        discount_id: coupon.discount_id,
+                        savings: Money::zero(),
+                        error: Some(e.to_string()),
+                    },
+                };
+
+            total = total.saturating_sub(&applied.savings);
+
+            Some(applied)
+        }
+        None => None,
+    };
+
+    Ok(CartWithProducts {
+        cart,
+        products: lines,
+        coupon,
+        subtotal: subtotal.round(),
+        total: total.round(),
+        currency: BASE_CURRENCY.to_owned(),
+    })
+}
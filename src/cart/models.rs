@@ -1,8 +1,10 @@
-use axum_shop::schema::{cart_products, carts};
+use crate::schema::{cart_products, carts};
+use crate::utils::Money;
 use chrono::NaiveDate;
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use validator::Validate;
 
 #[derive(Queryable, Selectable, Identifiable, Serialize, Debug, Deserialize, Default, Clone)]
 #[diesel(table_name=carts)]
@@ -12,6 +14,7 @@ pub struct Cart {
     pub id: i32,
     pub user_id: Uuid,
     pub updated_at: NaiveDate,
+    pub coupon_id: Option<i32>,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -28,9 +31,10 @@ pub struct SafeCart {
     pub updated_at: NaiveDate,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Validate)]
 pub struct ProductWithQuantity {
     pub product_id: i32,
+    #[validate(range(min = 1, message = "Quantity must be at least 1"))]
     pub quantity: i32,
 }
 
@@ -42,17 +46,31 @@ pub struct ProductWithQuantity {
 pub struct ProductCarts {
     pub product_id: i32,
     pub cart_id: i32,
-    pub quantity: i32
+    pub quantity: i32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CartLine {
+    #[serde(flatten)]
+    pub product: crate::product::models::ProductWithQty,
+    #[serde(flatten)]
+    pub pricing: crate::discount::pricing::Pricing,
+    pub line_total: Money,
 }
 
 #[derive(Serialize, Debug)]
 pub struct CartWithProducts {
     #[serde(flatten)]
     pub cart: Cart,
-    pub products: Vec<crate::product::models::ProductWithQty>,
+    pub products: Vec<CartLine>,
+    pub coupon: Option<crate::coupon::models::AppliedCoupon>,
+    pub subtotal: Money,
+    pub total: Money,
+    pub currency: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Validate)]
 pub struct ProductsToCart {
+    #[validate(length(min = 1, message = "At least one item is required"), nested)]
     pub items: Vec<ProductWithQuantity>,
 }
@@ -1,13 +1,29 @@
-use axum::{Router, routing::get};
+use axum::{
+    Router, middleware,
+    routing::{get, post},
+};
 
 use super::handlers;
-use crate::utils::types::Pool;
-
-pub fn get_routes() -> Router<Pool> {
-    Router::new().route(
-        "/carts",
-        get(handlers::get_all_cart)
-            .post(handlers::add_products_to_cart)
-            .delete(handlers::remove_product_from_cart),
-    )
+use crate::auth::rbac::{Permission, RequireRole};
+use crate::utils::types::AppState;
+
+pub fn get_routes() -> Router<AppState> {
+    let public = Router::new()
+        .route(
+            "/carts",
+            post(handlers::add_products_to_cart).delete(handlers::remove_product_from_cart),
+        )
+        .route(
+            "/carts/coupon",
+            post(handlers::apply_coupon).delete(handlers::remove_coupon),
+        );
+
+    let manage_orders = Router::new()
+        .route("/carts", get(handlers::get_all_cart))
+        .route_layer(middleware::from_fn_with_state(
+            RequireRole(Permission::ManageOrders),
+            RequireRole::middleware,
+        ));
+
+    public.merge(manage_orders)
 }
@@ -1,41 +1,34 @@
 use super::models::{Category, NewCategory};
-use crate::utils::internal_error;
+use crate::schema;
 use crate::utils::types::Pool;
-use axum::{
-    extract::{Json, Path, State},
-    http::StatusCode,
-};
-use axum_shop::schema;
-use diesel::{prelude::*, query_builder::BoxedSelectStatement};
+use crate::utils::{AppError, ValidatedJson};
+use axum::extract::{Json, Path, State};
+use diesel::prelude::*;
 use diesel_async::RunQueryDsl;
 use schema::categories;
 
 pub async fn create_category(
     State(pool): State<Pool>,
-    Json(payload): Json<NewCategory>,
-) -> Result<Json<Category>, (StatusCode, String)> {
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    ValidatedJson(payload): ValidatedJson<NewCategory>,
+) -> Result<Json<Category>, AppError> {
+    let mut conn = pool.get().await?;
 
     let res = diesel::insert_into(categories::table)
         .values(&payload)
         .returning(Category::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
 
-pub async fn get_categories(
-    State(pool): State<Pool>,
-) -> Result<Json<Vec<Category>>, (StatusCode, String)> {
-    let mut conn = pool.get().await.map_err(internal_error)?;
+pub async fn get_categories(State(pool): State<Pool>) -> Result<Json<Vec<Category>>, AppError> {
+    let mut conn = pool.get().await?;
 
     let res = categories::table
         .select(Category::as_select())
         .load(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -43,19 +36,15 @@ pub async fn get_categories(
 pub async fn update_category(
     State(pool): State<Pool>,
     Path(id): Path<i32>,
-    Json(payload): Json<NewCategory>,
-) -> Result<Json<Category>, (StatusCode, String)> {
-    if payload.title.trim().is_empty() {
-        return Err((StatusCode::BAD_REQUEST, "Title cannot be empty".to_string()));
-    }
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    ValidatedJson(payload): ValidatedJson<NewCategory>,
+) -> Result<Json<Category>, AppError> {
+    let mut conn = pool.get().await?;
 
     let res = diesel::update(categories::table.find(id))
         .set(categories::title.eq(payload.title))
         .returning(Category::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -63,15 +52,14 @@ pub async fn update_category(
 pub async fn get_category_by_id(
     State(pool): State<Pool>,
     Path(id): Path<i32>,
-) -> Result<Json<Category>, (StatusCode, String)> {
-    let mut conn = pool.get().await.map_err(internal_error)?;
+) -> Result<Json<Category>, AppError> {
+    let mut conn = pool.get().await?;
 
     let res = categories::table
         .find(id)
         .select(Category::as_select())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
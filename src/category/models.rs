@@ -1,8 +1,10 @@
-use axum_shop::schema::{categories, product_categories};
+use crate::schema::{categories, product_categories};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
+use validator::Validate;
 
 use crate::product::models::Product;
+use crate::utils::validation;
 
 #[derive(Queryable, Selectable, Debug, PartialEq, Identifiable, Serialize, Deserialize)]
 #[diesel(table_name=categories)]
@@ -12,9 +14,13 @@ pub struct Category {
     pub title: String,
 }
 
-#[derive(Insertable, Deserialize)]
+#[derive(Insertable, Deserialize, Validate)]
 #[diesel(table_name = categories)]
 pub struct NewCategory {
+    #[validate(
+        custom(function = "validation::not_blank"),
+        length(max = 100, message = "Category title must be at most 100 symbols long")
+    )]
     pub title: String,
 }
 
@@ -1,19 +1,24 @@
 use axum::{
-    Router,
-    routing::{delete, get, patch, post},
+    Router, middleware,
+    routing::{get, patch, post},
 };
 
 use super::handlers;
-use crate::utils::types::Pool;
+use crate::auth::rbac::{Permission, RequireRole};
+use crate::utils::types::AppState;
 
-pub fn get_routes() -> Router<Pool> {
-    Router::new()
-        .route(
-            "/categories",
-            get(handlers::get_categories).post(handlers::create_category),
-        )
-        .route(
-            "/categories/{id}",
-            patch(handlers::update_category).get(handlers::get_category_by_id),
-        )
+pub fn get_routes() -> Router<AppState> {
+    let public = Router::new()
+        .route("/categories", get(handlers::get_categories))
+        .route("/categories/{id}", get(handlers::get_category_by_id));
+
+    let manage_categories = Router::new()
+        .route("/categories", post(handlers::create_category))
+        .route("/categories/{id}", patch(handlers::update_category))
+        .route_layer(middleware::from_fn_with_state(
+            RequireRole(Permission::ManageCategories),
+            RequireRole::middleware,
+        ));
+
+    public.merge(manage_categories)
 }
@@ -0,0 +1,441 @@
+use jsonwebtoken::Algorithm;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Read when present, `CONFIG_FILE` points at another file which then must exist
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Settings of the shop, loaded once at startup and shared through `AppState`.
+///
+/// Layered as defaults, then the TOML file, then environment variables, so
+/// each environment only sets what differs. See `config.example.toml`.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub rmq: RmqConfig,
+    pub order: OrderConfig,
+    pub auth: AuthConfig,
+    pub oidc: OidcConfig,
+    pub smtp: SmtpConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Used unless a listener is passed by `listenfd`
+    pub bind_address: SocketAddr,
+    /// Frontend address used in links sent by email
+    pub app_url: String,
+    /// Where product images are stored, served under `/api/uploads`
+    pub uploads_dir: PathBuf,
+    /// Only lets verified accounts check out
+    pub require_verified_email: bool,
+    /// Header the reverse proxy puts the client address in, like `X-Forwarded-For`.
+    /// Unset, the peer address is used, which behind a proxy is the proxy itself.
+    pub client_ip_header: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            app_url: "http://127.0.0.1:3000".to_owned(),
+            uploads_dir: PathBuf::from("uploads"),
+            require_verified_email: false,
+            client_ip_header: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    /// Applies pending migrations before serving, same as `--migrate`
+    pub run_migrations: bool,
+}
+
+/// Events are not published and consumed without a url
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RmqConfig {
+    pub url: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrderConfig {
+    /// How long a pending order keeps its stock reserved before it is cancelled
+    pub reservation_ttl_minutes: i64,
+}
+
+impl Default for OrderConfig {
+    fn default() -> Self {
+        Self {
+            reservation_ttl_minutes: 30,
+        }
+    }
+}
+
+/// Access tokens are signed with `access_secret` for `HS256`, or with the
+/// `jwt_private_key` PEM file under the `jwt_key_id` kid for `RS256` and
+/// `EdDSA`. Keep the previous public key in `jwt_public_keys` while rotating.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub jwt_algorithm: Algorithm,
+    pub access_secret: Option<String>,
+    /// Signs refresh, email verification and 2FA tokens
+    pub refresh_secret: String,
+    pub jwt_key_id: Option<String>,
+    pub jwt_private_key: Option<PathBuf>,
+    /// Public key PEM files by kid, published at `/.well-known/jwks.json`
+    pub jwt_public_keys: BTreeMap<String, PathBuf>,
+    pub access_token_ttl_minutes: i64,
+    pub refresh_token_ttl_days: i64,
+    /// Shown by authenticator apps next to the account
+    pub totp_issuer: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            jwt_algorithm: Algorithm::HS256,
+            access_secret: None,
+            refresh_secret: String::new(),
+            jwt_key_id: None,
+            jwt_private_key: None,
+            jwt_public_keys: BTreeMap::new(),
+            access_token_ttl_minutes: 5,
+            refresh_token_ttl_days: 7,
+            totp_issuer: "axum-shop".to_owned(),
+        }
+    }
+}
+
+/// OpenID Connect login is off unless the issuer, client id and redirect url
+/// are set. `client_secret` is optional, public clients rely on PKCE alone.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    pub issuer: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub redirect_url: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub relay: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub reply_to: String,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            relay: "smtp.gmail.com".to_owned(),
+            username: None,
+            password: None,
+            from: "example@mail.com".to_owned(),
+            reply_to: "no-reply@rust.shop".to_owned(),
+        }
+    }
+}
+
+impl Config {
+    /// Fails with every invalid setting listed, so a bad deploy stops before serving
+    pub fn load() -> Result<Self, String> {
+        let mut config = Self::from_file()?;
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file() -> Result<Self, String> {
+        let (path, required) = match env::var("CONFIG_FILE") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+
+        toml::from_str(&contents).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+    }
+
+    /// Keeps the variable names the shop has always read
+    fn apply_env(&mut self) -> Result<(), String> {
+        let mut env = EnvLayer::default();
+
+        env.value("BIND_ADDRESS", &mut self.server.bind_address);
+        env.value("APP_URL", &mut self.server.app_url);
+        env.value("UPLOADS_DIR", &mut self.server.uploads_dir);
+        env.flag(
+            "REQUIRE_VERIFIED_EMAIL",
+            &mut self.server.require_verified_email,
+        );
+        env.optional("CLIENT_IP_HEADER", &mut self.server.client_ip_header);
+
+        env.value("DATABASE_URL", &mut self.database.url);
+        env.flag("RUN_MIGRATIONS", &mut self.database.run_migrations);
+
+        env.optional("RMQ_URL", &mut self.rmq.url);
+
+        env.value(
+            "RESERVATION_TTL_MINUTES",
+            &mut self.order.reservation_ttl_minutes,
+        );
+
+        env.value("JWT_ALGORITHM", &mut self.auth.jwt_algorithm);
+        env.optional("AT_SECRET", &mut self.auth.access_secret);
+        env.value("RT_SECRET", &mut self.auth.refresh_secret);
+        env.optional("JWT_KEY_ID", &mut self.auth.jwt_key_id);
+        env.optional("JWT_PRIVATE_KEY", &mut self.auth.jwt_private_key);
+        env.key_files("JWT_PUBLIC_KEYS", &mut self.auth.jwt_public_keys);
+        env.value(
+            "ACCESS_TOKEN_TTL_MINUTES",
+            &mut self.auth.access_token_ttl_minutes,
+        );
+        env.value(
+            "REFRESH_TOKEN_TTL_DAYS",
+            &mut self.auth.refresh_token_ttl_days,
+        );
+        env.value("TOTP_ISSUER", &mut self.auth.totp_issuer);
+
+        env.optional("OIDC_ISSUER", &mut self.oidc.issuer);
+        env.optional("OIDC_CLIENT_ID", &mut self.oidc.client_id);
+        env.optional("OIDC_CLIENT_SECRET", &mut self.oidc.client_secret);
+        env.optional("OIDC_REDIRECT_URL", &mut self.oidc.redirect_url);
+
+        env.value("SMTP_RELAY", &mut self.smtp.relay);
+        env.optional("SMTP_USERNAME", &mut self.smtp.username);
+        env.optional("SMTP_PASSWORD", &mut self.smtp.password);
+        env.value("SMTP_FROM", &mut self.smtp.from);
+        env.value("SMTP_REPLY_TO", &mut self.smtp.reply_to);
+
+        env.finish()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, message: &str| {
+            if !ok {
+                errors.push(message.to_owned());
+            }
+        };
+
+        check(
+            self.server.app_url.starts_with("http://")
+                || self.server.app_url.starts_with("https://"),
+            "server.app_url (APP_URL) must be an http or https url",
+        );
+
+        check(
+            self.server
+                .client_ip_header
+                .as_deref()
+                .is_none_or(|name| axum::http::HeaderName::from_bytes(name.as_bytes()).is_ok()),
+            "server.client_ip_header (CLIENT_IP_HEADER) must be a valid header name",
+        );
+
+        check(
+            !self.database.url.trim().is_empty(),
+            "database.url (DATABASE_URL) must be set",
+        );
+
+        check(
+            self.rmq
+                .url
+                .as_deref()
+                .is_none_or(|url| url.starts_with("amqp://") || url.starts_with("amqps://")),
+            "rmq.url (RMQ_URL) must be an amqp or amqps url",
+        );
+
+        check(
+            self.order.reservation_ttl_minutes > 0,
+            "order.reservation_ttl_minutes (RESERVATION_TTL_MINUTES) must be positive",
+        );
+
+        check(
+            !self.auth.refresh_secret.is_empty(),
+            "auth.refresh_secret (RT_SECRET) must be set",
+        );
+
+        let auth = &self.auth;
+
+        match auth.jwt_algorithm {
+            Algorithm::HS256 => check(
+                auth.access_secret
+                    .as_deref()
+                    .is_some_and(|secret| !secret.is_empty()),
+                "auth.access_secret (AT_SECRET) must be set for HS256",
+            ),
+            Algorithm::RS256 | Algorithm::EdDSA => {
+                check(
+                    auth.jwt_private_key.is_some(),
+                    "auth.jwt_private_key (JWT_PRIVATE_KEY) must be set for RS256 and EdDSA",
+                );
+                check(
+                    auth.jwt_key_id
+                        .as_ref()
+                        .is_some_and(|kid| auth.jwt_public_keys.contains_key(kid)),
+                    "auth.jwt_public_keys (JWT_PUBLIC_KEYS) must contain the public key of auth.jwt_key_id (JWT_KEY_ID)",
+                );
+            }
+            _ => check(
+                false,
+                "auth.jwt_algorithm (JWT_ALGORITHM) must be HS256, RS256 or EdDSA",
+            ),
+        }
+
+        check(
+            auth.access_token_ttl_minutes > 0,
+            "auth.access_token_ttl_minutes (ACCESS_TOKEN_TTL_MINUTES) must be positive",
+        );
+        check(
+            auth.refresh_token_ttl_days > 0,
+            "auth.refresh_token_ttl_days (REFRESH_TOKEN_TTL_DAYS) must be positive",
+        );
+        check(
+            !auth.totp_issuer.trim().is_empty(),
+            "auth.totp_issuer (TOTP_ISSUER) cannot be empty",
+        );
+
+        let oidc = &self.oidc;
+        let oidc_set = [&oidc.issuer, &oidc.client_id, &oidc.redirect_url]
+            .iter()
+            .filter(|value| value.is_some())
+            .count();
+
+        check(
+            oidc_set == 0 || oidc_set == 3,
+            "oidc.issuer, oidc.client_id and oidc.redirect_url must be set together",
+        );
+
+        let smtp = &self.smtp;
+
+        check(
+            !smtp.relay.trim().is_empty(),
+            "smtp.relay (SMTP_RELAY) cannot be empty",
+        );
+        check(
+            smtp.username.is_some() == smtp.password.is_some(),
+            "smtp.username (SMTP_USERNAME) and smtp.password (SMTP_PASSWORD) must be set together",
+        );
+        check(
+            smtp.from.parse::<lettre::Address>().is_ok(),
+            "smtp.from (SMTP_FROM) must be an email address",
+        );
+        check(
+            smtp.reply_to.parse::<lettre::Address>().is_ok(),
+            "smtp.reply_to (SMTP_REPLY_TO) must be an email address",
+        );
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        Err(format!(
+            "Invalid configuration:\n  - {}",
+            errors.join("\n  - ")
+        ))
+    }
+}
+
+/// Collects parse errors instead of stopping at the first bad variable
+#[derive(Default)]
+struct EnvLayer {
+    errors: Vec<String>,
+}
+
+impl EnvLayer {
+    fn value<T>(&mut self, var: &str, target: &mut T)
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if let Ok(value) = env::var(var) {
+            match value.parse() {
+                Ok(value) => *target = value,
+                Err(e) => self.errors.push(format!("{}: {}", var, e)),
+            }
+        }
+    }
+
+    fn optional<T>(&mut self, var: &str, target: &mut Option<T>)
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if let Ok(value) = env::var(var) {
+            match value.parse() {
+                Ok(value) => *target = Some(value),
+                Err(e) => self.errors.push(format!("{}: {}", var, e)),
+            }
+        }
+    }
+
+    fn flag(&mut self, var: &str, target: &mut bool) {
+        match env::var(var).as_deref() {
+            Ok("true" | "1") => *target = true,
+            Ok("false" | "0") => *target = false,
+            Ok(other) => self
+                .errors
+                .push(format!("{}: expected true or false, got {}", var, other)),
+            Err(_) => {}
+        }
+    }
+
+    /// Comma separated `kid=path` pairs
+    fn key_files(&mut self, var: &str, target: &mut BTreeMap<String, PathBuf>) {
+        let Ok(value) = env::var(var) else {
+            return;
+        };
+
+        let mut keys = BTreeMap::new();
+
+        for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+            match entry.split_once('=') {
+                Some((kid, path)) => {
+                    keys.insert(kid.trim().to_owned(), PathBuf::from(path.trim()));
+                }
+                None => {
+                    self.errors
+                        .push(format!("{}: expected `kid=path`, got {}", var, entry));
+                    return;
+                }
+            }
+        }
+
+        *target = keys;
+    }
+
+    fn finish(self) -> Result<(), String> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+
+        Err(format!(
+            "Invalid environment variables:\n  - {}",
+            self.errors.join("\n  - ")
+        ))
+    }
+}
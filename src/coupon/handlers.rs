@@ -0,0 +1,166 @@
+use super::models::{Coupon, CouponError, NewCoupon, UpdateCoupon};
+use crate::cart::models::CartLine;
+use crate::discount::pricing::CouponDiscount;
+use crate::utils::{AppError, Money, ValidatedJson, types::Pool};
+use axum::extract::{Json, Path, State};
+use diesel::prelude::*;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub async fn create_coupon(
+    State(pool): State<Pool>,
+    ValidatedJson(mut payload): ValidatedJson<NewCoupon>,
+) -> Result<Json<Coupon>, AppError> {
+    use crate::schema::coupons;
+
+    let mut conn = pool.get().await?;
+
+    payload.code = payload.code.trim().to_uppercase();
+
+    let res = diesel::insert_into(coupons::table)
+        .values(&payload)
+        .returning(Coupon::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+pub async fn get_coupons(State(pool): State<Pool>) -> Result<Json<Vec<Coupon>>, AppError> {
+    use crate::schema::coupons;
+
+    let mut conn = pool.get().await?;
+
+    let res = coupons::table
+        .select(Coupon::as_select())
+        .order(coupons::id.asc())
+        .load(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+pub async fn update_coupon(
+    State(pool): State<Pool>,
+    Path(id): Path<i32>,
+    ValidatedJson(payload): ValidatedJson<UpdateCoupon>,
+) -> Result<Json<Coupon>, AppError> {
+    use crate::schema::coupons;
+
+    let mut conn = pool.get().await?;
+
+    let res = diesel::update(coupons::table.find(id))
+        .set(&payload)
+        .returning(Coupon::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+pub async fn delete_coupon(
+    State(pool): State<Pool>,
+    Path(id): Path<i32>,
+) -> Result<Json<Coupon>, AppError> {
+    use crate::schema::coupons;
+
+    let mut conn = pool.get().await?;
+
+    let res = diesel::delete(coupons::table.find(id))
+        .returning(Coupon::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+pub async fn find_coupon_by_code(
+    code: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<Coupon, CouponError> {
+    use crate::schema::coupons;
+
+    coupons::table
+        .filter(coupons::code.eq(code.trim().to_uppercase()))
+        .select(Coupon::as_select())
+        .get_result(conn)
+        .await
+        .optional()?
+        .ok_or(CouponError::NotFound)
+}
+
+/// Checks every rule of the coupon against the user's cart lines and returns
+/// how much the coupon takes off the cart total.
+pub async fn coupon_savings(
+    coupon: &Coupon,
+    user_id: &Uuid,
+    lines: &[CartLine],
+    categories: &HashMap<i32, Vec<i32>>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<Money, CouponError> {
+    use crate::schema::coupon_redemptions;
+
+    if !coupon.is_active {
+        return Err(CouponError::Inactive);
+    }
+
+    if let Some(expires_at) = coupon.expires_at
+        && expires_at < chrono::Utc::now().naive_utc()
+    {
+        return Err(CouponError::Expired);
+    }
+
+    if let Some(limit) = coupon.usage_limit {
+        let used = coupon_redemptions::table
+            .filter(coupon_redemptions::coupon_id.eq(coupon.id))
+            .count()
+            .get_result::<i64>(conn)
+            .await?;
+
+        if used >= limit as i64 {
+            return Err(CouponError::UsageLimitReached);
+        }
+    }
+
+    if let Some(limit) = coupon.per_user_limit {
+        let used = coupon_redemptions::table
+            .filter(coupon_redemptions::coupon_id.eq(coupon.id))
+            .filter(coupon_redemptions::user_id.eq(user_id))
+            .count()
+            .get_result::<i64>(conn)
+            .await?;
+
+        if used >= limit as i64 {
+            return Err(CouponError::UserLimitReached);
+        }
+    }
+
+    let subtotal: Money = lines.iter().map(|line| line.line_total.clone()).sum();
+
+    if let Some(min) = &coupon.min_subtotal
+        && &subtotal < min
+    {
+        return Err(CouponError::MinSubtotal(min.clone()));
+    }
+
+    let Some(discount) = CouponDiscount::load(coupon.discount_id, conn).await? else {
+        return Err(CouponError::Inactive);
+    };
+
+    let savings = discount.savings(lines.iter().map(|line| {
+        let category_ids = categories
+            .get(&line.product.id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+
+        (line.product.id, category_ids, &line.line_total)
+    }));
+
+    if savings.is_zero() {
+        return Err(CouponError::NotApplicable);
+    }
+
+    Ok(savings)
+}
@@ -0,0 +1,107 @@
+use crate::schema::{coupon_redemptions, coupons};
+use crate::utils::{AppError, Money, validation};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Serialize, Queryable, Selectable, Identifiable)]
+#[diesel(table_name = coupons)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Coupon {
+    pub id: i32,
+    pub code: String,
+    pub discount_id: i32,
+    pub usage_limit: Option<i32>,
+    pub per_user_limit: Option<i32>,
+    pub min_subtotal: Option<Money>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub is_active: bool,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Insertable, Validate)]
+#[diesel(table_name = coupons)]
+pub struct NewCoupon {
+    #[validate(custom(function = "validation::not_blank"), length(max = 30))]
+    pub code: String,
+    pub discount_id: i32,
+    #[validate(range(min = 1))]
+    pub usage_limit: Option<i32>,
+    #[validate(range(min = 1))]
+    pub per_user_limit: Option<i32>,
+    #[validate(custom(function = "validation::non_negative_money"))]
+    pub min_subtotal: Option<Money>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub is_active: bool,
+}
+
+#[derive(Debug, Deserialize, AsChangeset, Validate)]
+#[diesel(table_name = coupons)]
+pub struct UpdateCoupon {
+    #[validate(range(min = 1))]
+    pub usage_limit: Option<i32>,
+    #[validate(range(min = 1))]
+    pub per_user_limit: Option<i32>,
+    #[validate(custom(function = "validation::non_negative_money"))]
+    pub min_subtotal: Option<Money>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub is_active: Option<bool>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = coupon_redemptions)]
+pub struct NewCouponRedemption {
+    pub coupon_id: i32,
+    pub user_id: Uuid,
+    pub order_id: Uuid,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct CouponCode {
+    #[validate(custom(function = "validation::not_blank"), length(max = 30))]
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppliedCoupon {
+    pub code: String,
+    pub discount_id: i32,
+    pub savings: Money,
+    /// Why the coupon currently has no effect on the cart
+    pub error: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CouponError {
+    #[error("Coupon code does not exist")]
+    NotFound,
+    #[error("Coupon is not active")]
+    Inactive,
+    #[error("Coupon has expired")]
+    Expired,
+    #[error("Coupon usage limit has been reached")]
+    UsageLimitReached,
+    #[error("You have already used this coupon")]
+    UserLimitReached,
+    #[error("Cart subtotal must be at least {0} to use this coupon")]
+    MinSubtotal(Money),
+    #[error("Coupon does not apply to any product in the cart")]
+    NotApplicable,
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+}
+
+impl From<CouponError> for AppError {
+    fn from(err: CouponError) -> Self {
+        match err {
+            CouponError::NotFound => AppError::NotFound(err.to_string()),
+            CouponError::UsageLimitReached | CouponError::UserLimitReached => {
+                AppError::Conflict(err.to_string())
+            }
+            CouponError::Database(e) => AppError::Database(e),
+            _ => AppError::BadRequest(err.to_string()),
+        }
+    }
+}
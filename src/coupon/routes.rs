@@ -0,0 +1,24 @@
+use axum::{
+    Router, middleware,
+    routing::{get, patch},
+};
+
+use super::handlers;
+use crate::auth::rbac::{Permission, RequireRole};
+use crate::utils::types::AppState;
+
+pub fn get_routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/coupons",
+            get(handlers::get_coupons).post(handlers::create_coupon),
+        )
+        .route(
+            "/coupons/{id}",
+            patch(handlers::update_coupon).delete(handlers::delete_coupon),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            RequireRole(Permission::ManageCoupons),
+            RequireRole::middleware,
+        ))
+}
@@ -0,0 +1,258 @@
+use super::models::{
+    CurrencyParams, DisplayCurrency, ExchangeRate, NewExchangeRate, UpdateExchangeRate,
+};
+use crate::auth::keys::JwtKeys;
+use crate::auth::models::AccessTokenClaims;
+use crate::utils::{AppError, ValidatedJson, money::BASE_CURRENCY, types::Pool};
+use axum::{
+    RequestPartsExt,
+    extract::{FromRef, FromRequestParts, Json, Multipart, Path, Query, State},
+    http::request::Parts,
+};
+use bigdecimal::{BigDecimal, Zero};
+use diesel::prelude::*;
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub async fn get_exchange_rates(
+    State(pool): State<Pool>,
+) -> Result<Json<Vec<ExchangeRate>>, AppError> {
+    use crate::schema::exchange_rates;
+
+    let mut conn = pool.get().await?;
+
+    let res = exchange_rates::table
+        .select(ExchangeRate::as_select())
+        .order(exchange_rates::currency.asc())
+        .load(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+pub async fn set_exchange_rate(
+    State(pool): State<Pool>,
+    Path(currency): Path<String>,
+    ValidatedJson(payload): ValidatedJson<UpdateExchangeRate>,
+) -> Result<Json<ExchangeRate>, AppError> {
+    use crate::schema::exchange_rates;
+
+    let rate = parse_rate_entry(&currency, payload.rate)?;
+
+    let mut conn = pool.get().await?;
+
+    let res = diesel::insert_into(exchange_rates::table)
+        .values(&rate)
+        .on_conflict(exchange_rates::currency)
+        .do_update()
+        .set(&rate)
+        .returning(ExchangeRate::as_returning())
+        .get_result(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+/// Imports rates from an uploaded CSV file with `currency,rate` lines.
+/// Empty lines, `#` comments and a header line are skipped. Either every
+/// rate is stored or none is.
+pub async fn import_exchange_rates(
+    State(pool): State<Pool>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<ExchangeRate>>, AppError> {
+    use crate::schema::exchange_rates;
+
+    let mut rates: Vec<NewExchangeRate> = Vec::new();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(e.to_string()))?
+    {
+        let text = field
+            .text()
+            .await
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((currency, rate)) = line.split_once(',') else {
+                return Err(AppError::BadRequest(format!(
+                    "Line {}: expected `currency,rate`",
+                    line_no + 1
+                )));
+            };
+
+            let Ok(rate) = BigDecimal::from_str(rate.trim()) else {
+                if line_no == 0 {
+                    // header line
+                    continue;
+                }
+
+                return Err(AppError::BadRequest(format!(
+                    "Line {}: invalid rate",
+                    line_no + 1
+                )));
+            };
+
+            let rate = parse_rate_entry(currency, rate)
+                .map_err(|e| AppError::BadRequest(format!("Line {}: {}", line_no + 1, e)))?;
+
+            rates.push(rate);
+        }
+    }
+
+    if rates.is_empty() {
+        return Err(AppError::BadRequest(
+            "No exchange rates were provided".to_owned(),
+        ));
+    }
+
+    let mut conn = pool.get().await?;
+
+    let res = conn
+        .transaction::<Vec<ExchangeRate>, diesel::result::Error, _>(move |mut conn| {
+            Box::pin(async move {
+                let mut res = Vec::with_capacity(rates.len());
+
+                for rate in rates.iter() {
+                    let saved = diesel::insert_into(exchange_rates::table)
+                        .values(rate)
+                        .on_conflict(exchange_rates::currency)
+                        .do_update()
+                        .set(rate)
+                        .returning(ExchangeRate::as_returning())
+                        .get_result(&mut conn)
+                        .await?;
+
+                    res.push(saved);
+                }
+
+                Ok(res)
+            })
+        })
+        .await?;
+
+    Ok(Json(res))
+}
+
+fn parse_rate_entry(currency: &str, rate: BigDecimal) -> Result<NewExchangeRate, AppError> {
+    let currency = normalize_currency(currency)?;
+
+    if currency == BASE_CURRENCY {
+        return Err(AppError::BadRequest(
+            "Base currency rate is always 1".to_owned(),
+        ));
+    }
+
+    if rate <= BigDecimal::zero() {
+        return Err(AppError::BadRequest(
+            "Rate should be greater than 0".to_owned(),
+        ));
+    }
+
+    Ok(NewExchangeRate {
+        currency,
+        rate,
+        updated_at: chrono::Utc::now().naive_utc(),
+    })
+}
+
+fn normalize_currency(currency: &str) -> Result<String, AppError> {
+    let currency = currency.trim().to_lowercase();
+
+    if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid currency code: {}",
+            currency
+        )));
+    }
+
+    Ok(currency)
+}
+
+pub async fn load_display_currency(
+    currency: &str,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<DisplayCurrency, AppError> {
+    use crate::schema::exchange_rates;
+
+    let code = normalize_currency(currency)?;
+
+    if code == BASE_CURRENCY {
+        return Ok(DisplayCurrency::default());
+    }
+
+    let rate = exchange_rates::table
+        .find(&code)
+        .select(exchange_rates::rate)
+        .first::<BigDecimal>(conn)
+        .await
+        .optional()?
+        .ok_or(AppError::BadRequest(format!(
+            "Unsupported currency: {}",
+            code
+        )))?;
+
+    Ok(DisplayCurrency { code, rate })
+}
+
+/// Resolves the display currency from the `currency` query param,
+/// falling back to the signed in user's profile and then to the base currency
+impl<S> FromRequestParts<S> for DisplayCurrency
+where
+    Pool: FromRef<S>,
+    Arc<JwtKeys>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        use crate::schema::profiles;
+
+        let Query(params) = parts
+            .extract::<Query<CurrencyParams>>()
+            .await
+            .map_err(|e| AppError::BadRequest(e.to_string()))?;
+
+        let pool = Pool::from_ref(state);
+        let mut conn = pool.get().await?;
+
+        if let Some(currency) = params.currency {
+            return load_display_currency(&currency, &mut conn).await;
+        }
+
+        let Ok(claims) = parts
+            .extract_with_state::<AccessTokenClaims, _>(state)
+            .await
+        else {
+            return Ok(DisplayCurrency::default());
+        };
+
+        let Ok(user_id) = uuid::Uuid::parse_str(&claims.sub) else {
+            return Ok(DisplayCurrency::default());
+        };
+
+        let currency = profiles::table
+            .filter(profiles::user_id.eq(user_id))
+            .select(profiles::currency)
+            .first::<String>(&mut conn)
+            .await
+            .optional()?;
+
+        match currency {
+            // A profile currency without a rate should not break browsing
+            Some(currency) => Ok(load_display_currency(&currency, &mut conn)
+                .await
+                .unwrap_or_default()),
+            None => Ok(DisplayCurrency::default()),
+        }
+    }
+}
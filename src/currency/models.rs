@@ -0,0 +1,158 @@
+use crate::cart::models::CartWithProducts;
+use crate::discount::pricing::Pricing;
+use crate::order::models::OrderWithItems;
+use crate::product::models::{ProductWithCategories, ProductWithCategoriesResponse};
+use crate::schema::exchange_rates;
+use crate::search::models::{SearchHit, SearchResponse};
+use crate::utils::{Money, money::BASE_CURRENCY, validation};
+use bigdecimal::{BigDecimal, One};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+#[derive(Debug, Serialize, Queryable, Selectable, Identifiable)]
+#[diesel(table_name = exchange_rates)]
+#[diesel(primary_key(currency))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ExchangeRate {
+    pub currency: String,
+    /// Units of `currency` for one unit of the base currency
+    pub rate: BigDecimal,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable, AsChangeset)]
+#[diesel(table_name = exchange_rates)]
+pub struct NewExchangeRate {
+    pub currency: String,
+    pub rate: BigDecimal,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UpdateExchangeRate {
+    #[validate(custom(function = "validation::positive_decimal"))]
+    pub rate: BigDecimal,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CurrencyParams {
+    pub currency: Option<String>,
+}
+
+/// Currency the caller wants to see prices in, resolved from the `currency`
+/// query param or the caller's profile. Prices are still charged in the base currency.
+#[derive(Debug, Clone)]
+pub struct DisplayCurrency {
+    pub code: String,
+    pub rate: BigDecimal,
+}
+
+impl Default for DisplayCurrency {
+    fn default() -> Self {
+        DisplayCurrency {
+            code: BASE_CURRENCY.to_owned(),
+            rate: BigDecimal::one(),
+        }
+    }
+}
+
+impl DisplayCurrency {
+    pub fn is_base(&self) -> bool {
+        self.code == BASE_CURRENCY
+    }
+
+    pub fn convert(&self, money: &mut Money) {
+        if !self.is_base() {
+            *money = Money::new(money.amount() * &self.rate).round();
+        }
+    }
+
+    /// Converts an amount given in this currency back to the base currency
+    pub fn to_base(&self, money: &Money) -> Money {
+        if self.is_base() {
+            return money.clone();
+        }
+
+        Money::new(money.amount() / &self.rate)
+    }
+}
+
+/// Response types whose prices can be rendered in a `DisplayCurrency`
+pub trait ConvertPrices {
+    fn convert_prices(&mut self, currency: &DisplayCurrency);
+}
+
+impl<T: ConvertPrices> ConvertPrices for Vec<T> {
+    fn convert_prices(&mut self, currency: &DisplayCurrency) {
+        for item in self.iter_mut() {
+            item.convert_prices(currency);
+        }
+    }
+}
+
+impl ConvertPrices for Pricing {
+    fn convert_prices(&mut self, currency: &DisplayCurrency) {
+        currency.convert(&mut self.original_price);
+        currency.convert(&mut self.discounted_price);
+    }
+}
+
+impl ConvertPrices for ProductWithCategories {
+    fn convert_prices(&mut self, currency: &DisplayCurrency) {
+        currency.convert(&mut self.product.price);
+        self.product.currency = currency.code.clone();
+        self.pricing.convert_prices(currency);
+    }
+}
+
+impl ConvertPrices for ProductWithCategoriesResponse {
+    fn convert_prices(&mut self, currency: &DisplayCurrency) {
+        self.products.convert_prices(currency);
+    }
+}
+
+impl ConvertPrices for CartWithProducts {
+    fn convert_prices(&mut self, currency: &DisplayCurrency) {
+        for line in self.products.iter_mut() {
+            currency.convert(&mut line.product.price);
+            currency.convert(&mut line.line_total);
+            line.pricing.convert_prices(currency);
+        }
+
+        if let Some(coupon) = self.coupon.as_mut() {
+            currency.convert(&mut coupon.savings);
+        }
+
+        currency.convert(&mut self.subtotal);
+        currency.convert(&mut self.total);
+        self.currency = currency.code.clone();
+    }
+}
+
+impl ConvertPrices for OrderWithItems {
+    fn convert_prices(&mut self, currency: &DisplayCurrency) {
+        for item in self.items.iter_mut() {
+            currency.convert(&mut item.price);
+            currency.convert(&mut item.original_price);
+        }
+
+        currency.convert(&mut self.order.total);
+        self.order.currency = currency.code.clone();
+    }
+}
+
+impl ConvertPrices for SearchHit {
+    fn convert_prices(&mut self, currency: &DisplayCurrency) {
+        currency.convert(&mut self.product.price);
+        self.product.currency = currency.code.clone();
+        self.pricing.convert_prices(currency);
+    }
+}
+
+impl ConvertPrices for SearchResponse {
+    fn convert_prices(&mut self, currency: &DisplayCurrency) {
+        self.results.convert_prices(currency);
+    }
+}
@@ -0,0 +1,28 @@
+use axum::{
+    Router, middleware,
+    routing::{get, post, put},
+};
+
+use super::handlers;
+use crate::auth::rbac::{Permission, RequireRole};
+use crate::utils::types::AppState;
+
+pub fn get_routes() -> Router<AppState> {
+    let public = Router::new().route("/exchange-rates", get(handlers::get_exchange_rates));
+
+    let manage_rates = Router::new()
+        .route(
+            "/exchange-rates/import",
+            post(handlers::import_exchange_rates),
+        )
+        .route(
+            "/exchange-rates/{currency}",
+            put(handlers::set_exchange_rate),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            RequireRole(Permission::ManageExchangeRates),
+            RequireRole::middleware,
+        ));
+
+    public.merge(manage_rates)
+}
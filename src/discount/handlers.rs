@@ -1,24 +1,34 @@
 use super::models::{
-    Discount, DiscountProduct, DiscountType, DiscountWithProducts, DiscountWithProductsResponse,
-    NewDiscount, ProductsForDiscount, UpdateDiscount,
-};
-use crate::utils::{internal_error, types::Pool};
-use axum::{
-    extract::{Json, Path, State},
-    http::StatusCode,
+    CategoriesForDiscount, Discount, DiscountCategory, DiscountProduct, DiscountWithProducts,
+    DiscountWithProductsResponse, NewDiscount, ProductsForDiscount, UpdateDiscount,
+    validate_percentage,
 };
+use crate::config::{Config, RmqConfig};
+use crate::utils::{AppError, ValidatedJson, types::Pool};
+use axum::extract::{Json, Path, State};
+use bigdecimal::BigDecimal;
 use diesel::{dsl::sql, prelude::*};
 use diesel_async::pooled_connection::AsyncDieselConnectionManager;
 use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use std::sync::Arc;
+use validator::ValidationErrors;
 
 const QUEUE_NAME: &str = "notifications";
 
+const DISCOUNT_CATEGORIES_JSON: &str = "COALESCE(
+    (SELECT json_agg(categories.* ORDER BY categories.id)
+     FROM discount_categories
+     JOIN categories ON categories.id = discount_categories.category_id
+     WHERE discount_categories.discount_id = discounts.id),
+    '[]'
+)";
+
 pub async fn get_all_discounts(
     State(pool): State<Pool>,
-) -> Result<Json<DiscountWithProductsResponse>, (StatusCode, String)> {
-    use axum_shop::schema::{discount_products, discounts, products};
+) -> Result<Json<DiscountWithProductsResponse>, AppError> {
+    use crate::schema::{discount_products, discounts, products};
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let rows = discounts::table
         .left_join(discount_products::table.on(discounts::id.eq(discount_products::discount_id)))
@@ -28,18 +38,23 @@ pub async fn get_all_discounts(
             sql::<diesel::sql_types::Json>(
                 "COALESCE(json_agg(products.* ORDER BY products.id), '[]')",
             ),
+            sql::<diesel::sql_types::Json>(DISCOUNT_CATEGORIES_JSON),
         ))
         .group_by(discounts::id)
-        .load::<(Discount, serde_json::Value)>(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .load::<(Discount, serde_json::Value, serde_json::Value)>(&mut conn)
+        .await?;
 
     let discounts_with_products: Vec<DiscountWithProducts> = rows
         .into_iter()
-        .map(|(discount, prod_json)| {
+        .map(|(discount, prod_json, categories_json)| {
             let products = serde_json::from_value(prod_json).unwrap_or_default();
+            let categories = serde_json::from_value(categories_json).unwrap_or_default();
 
-            DiscountWithProducts { discount, products }
+            DiscountWithProducts {
+                discount,
+                products,
+                categories,
+            }
         })
         .collect();
 
@@ -52,21 +67,12 @@ pub async fn get_all_discounts(
 
 pub async fn create_discount(
     State(pool): State<Pool>,
-    Json(mut payload): Json<NewDiscount>,
-) -> Result<Json<Discount>, (StatusCode, String)> {
-    use axum_shop::schema::discounts;
+    State(config): State<Arc<Config>>,
+    ValidatedJson(mut payload): ValidatedJson<NewDiscount>,
+) -> Result<Json<Discount>, AppError> {
+    use crate::schema::discounts;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
-
-    if let Err(e) = payload.validate_dates() {
-        return Err((StatusCode::BAD_REQUEST, format!("{}", e)));
-    }
-
-    let discount_type = payload.discount_type.to_lowercase();
-
-    if !matches!(discount_type.as_str(), "fixed" | "percentage") {
-        return Err((StatusCode::BAD_REQUEST, "Wrong discount_type".to_owned()));
-    }
+    let mut conn = pool.get().await?;
 
     payload.discount_type = payload.discount_type.to_lowercase();
 
@@ -74,36 +80,42 @@ pub async fn create_discount(
         .values(&payload)
         .returning(Discount::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
+    if let Err(er) = publish_discount_created(&config.rmq, &res).await {
+        eprintln!("Failed to publish event: {:?}", er);
+    }
+
+    Ok(Json(res))
+}
+
+pub async fn publish_discount_created(
+    rmq: &RmqConfig,
+    discount: &Discount,
+) -> Result<(), AppError> {
     let event = serde_json::json!({
         "type": "Discount",
         "event": "discount_created",
-        "id": res.id,
-        "title": res.title,
-        "amount": res.amount,
-        "start_date": res.start_date,
-        "end_date": res.end_date,
-        "discount_type": res.discount_type
+        "id": discount.id,
+        "title": discount.title,
+        "amount": discount.amount,
+        "start_date": discount.start_date,
+        "end_date": discount.end_date,
+        "discount_type": discount.discount_type
     })
     .to_string();
 
-    if let Err(er) = crate::rmq::client::publish_event(QUEUE_NAME, &event).await {
-        eprintln!("Failed to publish event: {:?}", er);
-    }
-
-    Ok(Json(res))
+    crate::rmq::client::publish_event(rmq, QUEUE_NAME, &event).await
 }
 
 pub async fn add_discount_products(
     State(pool): State<Pool>,
     Path(id): Path<i32>,
-    Json(payload): Json<ProductsForDiscount>,
-) -> Result<Json<DiscountWithProducts>, (StatusCode, String)> {
-    use axum_shop::schema::{discount_products, discounts, products};
+    ValidatedJson(payload): ValidatedJson<ProductsForDiscount>,
+) -> Result<Json<DiscountWithProducts>, AppError> {
+    use crate::schema::{discount_products, discounts, products};
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let prods: Vec<_> = payload
         .product_id
@@ -122,7 +134,7 @@ pub async fn add_discount_products(
                     .execute(&mut conn)
                     .await?;
 
-                let (discount, products_json) = discounts::table
+                let (discount, products_json, categories_json) = discounts::table
                     .find(&id)
                     .left_join(
                         discount_products::table
@@ -134,21 +146,22 @@ pub async fn add_discount_products(
                         sql::<diesel::sql_types::Json>(
                             "COALESCE(json_agg(products.* ORDER BY products.id), '[]')",
                         ),
+                        sql::<diesel::sql_types::Json>(DISCOUNT_CATEGORIES_JSON),
                     ))
                     .group_by(discounts::id)
-                    .get_result::<(Discount, serde_json::Value)>(&mut conn)
+                    .get_result::<(Discount, serde_json::Value, serde_json::Value)>(&mut conn)
                     .await?;
 
                 let res = DiscountWithProducts {
                     discount,
                     products: serde_json::from_value(products_json).unwrap_or_default(),
+                    categories: serde_json::from_value(categories_json).unwrap_or_default(),
                 };
 
                 Ok(res)
             })
         })
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -156,18 +169,11 @@ pub async fn add_discount_products(
 pub async fn remove_products_from_discount(
     State(pool): State<Pool>,
     Path(id): Path<i32>,
-    Json(payload): Json<ProductsForDiscount>,
-) -> Result<Json<DiscountWithProducts>, (StatusCode, String)> {
-    use axum_shop::schema::{discount_products, discounts, products};
+    ValidatedJson(payload): ValidatedJson<ProductsForDiscount>,
+) -> Result<Json<DiscountWithProducts>, AppError> {
+    use crate::schema::discount_products;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
-
-    if payload.product_id.is_empty() {
-        return Err((
-            StatusCode::NOT_FOUND,
-            "Products list cannot be empty".to_owned(),
-        ));
-    }
+    let mut conn = pool.get().await?;
 
     let ids: Vec<&i32> = payload.product_id.iter().collect();
 
@@ -177,12 +183,10 @@ pub async fn remove_products_from_discount(
             .filter(discount_products::product_id.eq_any(&ids)),
     )
     .execute(&mut conn)
-    .await
-    .map_err(internal_error)?;
+    .await?;
 
-    if &deleted_count < &ids.len() {
-        return Err((
-            StatusCode::NOT_FOUND,
+    if deleted_count < ids.len() {
+        return Err(AppError::NotFound(
             "Failed to remove products from discount".to_owned(),
         ));
     }
@@ -192,21 +196,99 @@ pub async fn remove_products_from_discount(
     Ok(Json(res))
 }
 
-pub async fn update_discount(
+pub async fn add_discount_categories(
+    State(pool): State<Pool>,
+    Path(id): Path<i32>,
+    ValidatedJson(payload): ValidatedJson<CategoriesForDiscount>,
+) -> Result<Json<DiscountWithProducts>, AppError> {
+    use crate::schema::discount_categories;
+
+    let mut conn = pool.get().await?;
+
+    let categories: Vec<_> = payload
+        .category_id
+        .iter()
+        .map(|category_id| DiscountCategory {
+            discount_id: id,
+            category_id: *category_id,
+        })
+        .collect();
+
+    diesel::insert_into(discount_categories::table)
+        .values(&categories)
+        .execute(&mut conn)
+        .await?;
+
+    let res = get_discount_with_products(&id, &mut conn).await?;
+
+    Ok(Json(res))
+}
+
+pub async fn remove_categories_from_discount(
     State(pool): State<Pool>,
     Path(id): Path<i32>,
-    Json(payload): Json<UpdateDiscount>,
-) -> Result<Json<DiscountWithProducts>, (StatusCode, String)> {
-    use axum_shop::schema::discounts;
+    ValidatedJson(payload): ValidatedJson<CategoriesForDiscount>,
+) -> Result<Json<DiscountWithProducts>, AppError> {
+    use crate::schema::discount_categories;
+
+    let mut conn = pool.get().await?;
+
+    let ids: Vec<&i32> = payload.category_id.iter().collect();
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let deleted_count = diesel::delete(
+        discount_categories::table
+            .filter(discount_categories::discount_id.eq(&id))
+            .filter(discount_categories::category_id.eq_any(&ids)),
+    )
+    .execute(&mut conn)
+    .await?;
+
+    if deleted_count < ids.len() {
+        return Err(AppError::NotFound(
+            "Failed to remove categories from discount".to_owned(),
+        ));
+    }
+
+    let res = get_discount_with_products(&id, &mut conn).await?;
+
+    Ok(Json(res))
+}
+
+pub async fn update_discount(
+    State(pool): State<Pool>,
+    Path(id): Path<i32>,
+    ValidatedJson(mut payload): ValidatedJson<UpdateDiscount>,
+) -> Result<Json<DiscountWithProducts>, AppError> {
+    use crate::schema::discounts;
+
+    let mut conn = pool.get().await?;
+
+    payload.discount_type = payload.discount_type.map(|t| t.to_lowercase());
+
+    // Only one half of the pair is sent, check it against the stored other half
+    if payload.discount_type.is_some() != payload.amount.is_some() {
+        let (discount_type, amount) = discounts::table
+            .find(&id)
+            .select((discounts::discount_type, discounts::amount))
+            .get_result::<(String, BigDecimal)>(&mut conn)
+            .await?;
+
+        validate_percentage(
+            payload.discount_type.as_deref().unwrap_or(&discount_type),
+            payload.amount.as_ref().unwrap_or(&amount),
+        )
+        .map_err(|e| {
+            let mut errors = ValidationErrors::new();
+            errors.add("amount", e);
+            errors
+        })?;
+    }
 
     diesel::update(discounts::table.find(&id))
         .set(&payload)
         .returning(Discount::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     let discount = get_discount_with_products(&id, &mut conn).await?;
 
@@ -216,10 +298,10 @@ pub async fn update_discount(
 async fn get_discount_with_products(
     discount_id: &i32,
     conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
-) -> std::result::Result<DiscountWithProducts, (StatusCode, String)> {
-    use axum_shop::schema::{discount_products, discounts, products};
+) -> std::result::Result<DiscountWithProducts, AppError> {
+    use crate::schema::{discount_products, discounts, products};
 
-    let (discount, products_json) = discounts::table
+    let (discount, products_json, categories_json) = discounts::table
         .find(discount_id)
         .left_join(discount_products::table.on(discounts::id.eq(discount_products::discount_id)))
         .left_join(products::table.on(discount_products::product_id.eq(products::id)))
@@ -228,15 +310,16 @@ async fn get_discount_with_products(
             sql::<diesel::sql_types::Json>(
                 "COALESCE(json_agg(products.* ORDER BY products.id), '[]')",
             ),
+            sql::<diesel::sql_types::Json>(DISCOUNT_CATEGORIES_JSON),
         ))
         .group_by(discounts::id)
-        .get_result::<(Discount, serde_json::Value)>(conn)
-        .await
-        .map_err(internal_error)?;
+        .get_result::<(Discount, serde_json::Value, serde_json::Value)>(conn)
+        .await?;
 
     let res = DiscountWithProducts {
         discount,
         products: serde_json::from_value(products_json).unwrap_or_default(),
+        categories: serde_json::from_value(categories_json).unwrap_or_default(),
     };
 
     Ok(res)
@@ -245,16 +328,15 @@ async fn get_discount_with_products(
 pub async fn delete_discount(
     State(pool): State<Pool>,
     Path(id): Path<i32>,
-) -> Result<Json<Discount>, (StatusCode, String)> {
-    use axum_shop::schema::discounts;
+) -> Result<Json<Discount>, AppError> {
+    use crate::schema::discounts;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let res = diesel::delete(discounts::table.filter(discounts::id.eq(&id)))
         .returning(Discount::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -1,10 +1,14 @@
+use crate::category::models::Category;
 use crate::product::models::Product;
-use axum_shop::schema::{discount_products, discounts};
+use crate::schema::{discount_categories, discount_products, discounts};
+use crate::utils::validation;
+use bigdecimal::BigDecimal;
 use diesel::deserialize::FromSqlRow;
 use diesel::sql_types::Text;
 use diesel::{expression::AsExpression, prelude::*};
 use serde::{Deserialize, Serialize};
-use validator::ValidationError;
+use std::borrow::Cow;
+use validator::{Validate, ValidationError};
 
 #[derive(Debug, Serialize, Identifiable, Queryable, Selectable)]
 #[diesel(table_name=discounts)]
@@ -20,11 +24,15 @@ pub struct Discount {
     pub applies_to_all: bool,
 }
 
-#[derive(Debug, Deserialize, Insertable)]
+#[derive(Debug, Deserialize, Insertable, Validate)]
 #[diesel(table_name=discounts)]
+#[validate(schema(function = "validate_new_discount"))]
 pub struct NewDiscount {
+    #[validate(custom(function = "validation::not_blank"), length(max = 30))]
     pub title: String,
+    #[validate(custom(function = "validate_discount_type"))]
     pub discount_type: String,
+    #[validate(custom(function = "validation::positive_decimal"))]
     pub amount: bigdecimal::BigDecimal,
     pub start_date: chrono::NaiveDateTime,
     pub end_date: chrono::NaiveDateTime,
@@ -32,11 +40,15 @@ pub struct NewDiscount {
     pub applies_to_all: bool,
 }
 
-#[derive(Debug, Insertable, AsChangeset, Deserialize)]
+#[derive(Debug, Insertable, AsChangeset, Deserialize, Validate)]
 #[diesel(table_name=discounts)]
+#[validate(schema(function = "validate_update_discount"))]
 pub struct UpdateDiscount {
+    #[validate(custom(function = "validation::not_blank"), length(max = 30))]
     pub title: Option<String>,
+    #[validate(custom(function = "validate_discount_type"))]
     pub discount_type: Option<String>,
+    #[validate(custom(function = "validation::positive_decimal"))]
     pub amount: Option<bigdecimal::BigDecimal>,
     // pub start_date: Option<chrono::NaiveDateTime>,
     // pub end_date: Option<chrono::NaiveDateTime>,
@@ -62,16 +74,34 @@ pub struct DiscountProduct {
     pub product_id: i32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Queryable, Associations, Identifiable, Insertable)]
+#[diesel(table_name=discount_categories)]
+#[diesel(belongs_to(Discount))]
+#[diesel(belongs_to(Category))]
+#[diesel(primary_key(discount_id, category_id))]
+pub struct DiscountCategory {
+    pub discount_id: i32,
+    pub category_id: i32,
+}
+
+#[derive(Debug, Deserialize, Validate)]
 pub struct ProductsForDiscount {
+    #[validate(length(min = 1, message = "At least one product is required"))]
     pub product_id: Vec<i32>,
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub struct CategoriesForDiscount {
+    #[validate(length(min = 1, message = "At least one category is required"))]
+    pub category_id: Vec<i32>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct DiscountWithProducts {
     #[serde(flatten)]
     pub discount: Discount,
     pub products: Vec<crate::product::models::Product>,
+    pub categories: Vec<Category>,
 }
 
 #[derive(Debug, Serialize)]
@@ -79,11 +109,43 @@ pub struct DiscountWithProductsResponse {
     pub discounts: Vec<DiscountWithProducts>,
 }
 
-impl NewDiscount {
-    pub fn validate_dates(&self) -> Result<(), String> {
-        if self.end_date <= self.start_date {
-            return Err("End date must be after start date".to_string());
-        }
-        Ok(())
+fn validate_discount_type(discount_type: &str) -> Result<(), ValidationError> {
+    if !matches!(
+        discount_type.to_lowercase().as_str(),
+        "fixed" | "percentage"
+    ) {
+        return Err(ValidationError::new("discount_type")
+            .with_message(Cow::Borrowed("Must be either fixed or percentage")));
+    }
+
+    Ok(())
+}
+
+/// A percentage discount cannot take off more than the whole price
+pub fn validate_percentage(
+    discount_type: &str,
+    amount: &BigDecimal,
+) -> Result<(), ValidationError> {
+    if discount_type.eq_ignore_ascii_case("percentage") && amount > &BigDecimal::from(100) {
+        return Err(ValidationError::new("percentage")
+            .with_message(Cow::Borrowed("A percentage discount cannot exceed 100")));
+    }
+
+    Ok(())
+}
+
+fn validate_new_discount(discount: &NewDiscount) -> Result<(), ValidationError> {
+    if discount.end_date <= discount.start_date {
+        return Err(ValidationError::new("dates")
+            .with_message(Cow::Borrowed("End date must be after start date")));
+    }
+
+    validate_percentage(&discount.discount_type, &discount.amount)
+}
+
+fn validate_update_discount(discount: &UpdateDiscount) -> Result<(), ValidationError> {
+    match (&discount.discount_type, &discount.amount) {
+        (Some(discount_type), Some(amount)) => validate_percentage(discount_type, amount),
+        _ => Ok(()),
     }
 }
@@ -0,0 +1,244 @@
+use super::models::Discount;
+use crate::utils::Money;
+use bigdecimal::{BigDecimal, Zero};
+use diesel::{dsl::now, prelude::*};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Pricing {
+    pub original_price: Money,
+    pub discounted_price: Money,
+    pub discount_id: Option<i32>,
+}
+
+#[derive(Debug)]
+struct ActiveDiscount {
+    discount: Discount,
+    product_ids: HashSet<i32>,
+    category_ids: HashSet<i32>,
+}
+
+impl ActiveDiscount {
+    fn applies_to(&self, product_id: i32, category_ids: &[i32]) -> bool {
+        self.discount.applies_to_all
+            || self.product_ids.contains(&product_id)
+            || category_ids.iter().any(|id| self.category_ids.contains(id))
+    }
+
+    fn apply(&self, price: &Money) -> Money {
+        let amount = &self.discount.amount;
+
+        match self.discount.discount_type.as_str() {
+            "percentage" => price.percent_off(amount),
+            "fixed" => price.saturating_sub(&Money::new(amount.clone())).round(),
+            _ => price.clone(),
+        }
+    }
+}
+
+/// Resolves the best currently active discount for products.
+/// Load it once per request and reuse it for every line.
+#[derive(Debug, Default)]
+pub struct PriceResolver {
+    discounts: Vec<ActiveDiscount>,
+}
+
+impl PriceResolver {
+    pub async fn load(
+        conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+    ) -> Result<Self, diesel::result::Error> {
+        use crate::schema::{coupons, discounts};
+
+        // Discounts behind a coupon code are only applied when the code is redeemed
+        let active = discounts::table
+            .filter(discounts::is_active.eq(true))
+            .filter(discounts::start_date.le(now))
+            .filter(discounts::end_date.ge(now))
+            .filter(discounts::id.ne_all(coupons::table.select(coupons::discount_id)))
+            .select(Discount::as_select())
+            .load(conn)
+            .await?;
+
+        let discounts = with_targets(active, conn).await?;
+
+        Ok(Self { discounts })
+    }
+
+    pub fn price_for(&self, product_id: i32, category_ids: &[i32], price: &Money) -> Pricing {
+        let best = self
+            .discounts
+            .iter()
+            .filter(|discount| discount.applies_to(product_id, category_ids))
+            .map(|discount| (discount.discount.id, discount.apply(price)))
+            .min_by(|a, b| a.1.cmp(&b.1));
+
+        match best {
+            Some((discount_id, discounted_price)) if &discounted_price < price => Pricing {
+                original_price: price.clone(),
+                discounted_price,
+                discount_id: Some(discount_id),
+            },
+            _ => Pricing {
+                original_price: price.clone(),
+                discounted_price: price.clone(),
+                discount_id: None,
+            },
+        }
+    }
+
+    /// What the active discounts lower the price of, to count discounted
+    /// products in SQL without loading them
+    pub fn targets(&self) -> DiscountTargets {
+        let mut targets = DiscountTargets::default();
+
+        for discount in self.discounts.iter() {
+            if discount.discount.amount <= BigDecimal::zero() {
+                continue;
+            }
+
+            targets.all_products |= discount.discount.applies_to_all;
+            targets
+                .product_ids
+                .extend(discount.product_ids.iter().copied());
+            targets
+                .category_ids
+                .extend(discount.category_ids.iter().copied());
+        }
+
+        targets
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DiscountTargets {
+    pub all_products: bool,
+    pub product_ids: Vec<i32>,
+    pub category_ids: Vec<i32>,
+}
+
+/// Discount attached to a coupon code, applied on top of the automatic discounts.
+/// Percentage coupons reduce every eligible line, fixed coupons take the amount
+/// off the eligible total once.
+#[derive(Debug)]
+pub struct CouponDiscount {
+    discount: ActiveDiscount,
+}
+
+impl CouponDiscount {
+    /// Returns `None` when the discount behind the coupon is not currently active
+    pub async fn load(
+        discount_id: i32,
+        conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+    ) -> Result<Option<Self>, diesel::result::Error> {
+        use crate::schema::discounts;
+
+        let active = discounts::table
+            .find(discount_id)
+            .filter(discounts::is_active.eq(true))
+            .filter(discounts::start_date.le(now))
+            .filter(discounts::end_date.ge(now))
+            .select(Discount::as_select())
+            .load(conn)
+            .await?;
+
+        let discount = with_targets(active, conn).await?.pop();
+
+        Ok(discount.map(|discount| Self { discount }))
+    }
+
+    pub fn discount_id(&self) -> i32 {
+        self.discount.discount.id
+    }
+
+    /// `lines` are `(product_id, category_ids, line_total)` of the cart
+    pub fn savings<'a>(&self, lines: impl Iterator<Item = (i32, &'a [i32], &'a Money)>) -> Money {
+        let eligible: Money = lines
+            .filter(|(product_id, category_ids, _)| {
+                self.discount.applies_to(*product_id, category_ids)
+            })
+            .map(|(_, _, line_total)| line_total.clone())
+            .sum();
+
+        let discounted = self.discount.apply(&eligible);
+
+        eligible - discounted
+    }
+}
+
+async fn with_targets(
+    active: Vec<Discount>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<Vec<ActiveDiscount>, diesel::result::Error> {
+    use crate::schema::{discount_categories, discount_products};
+
+    if active.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let ids: Vec<i32> = active.iter().map(|discount| discount.id).collect();
+
+    let product_rows = discount_products::table
+        .filter(discount_products::discount_id.eq_any(&ids))
+        .select((
+            discount_products::discount_id,
+            discount_products::product_id,
+        ))
+        .load::<(i32, i32)>(conn)
+        .await?;
+
+    let category_rows = discount_categories::table
+        .filter(discount_categories::discount_id.eq_any(&ids))
+        .select((
+            discount_categories::discount_id,
+            discount_categories::category_id,
+        ))
+        .load::<(i32, i32)>(conn)
+        .await?;
+
+    let discounts = active
+        .into_iter()
+        .map(|discount| ActiveDiscount {
+            product_ids: product_rows
+                .iter()
+                .filter(|(discount_id, _)| *discount_id == discount.id)
+                .map(|(_, product_id)| *product_id)
+                .collect(),
+            category_ids: category_rows
+                .iter()
+                .filter(|(discount_id, _)| *discount_id == discount.id)
+                .map(|(_, category_id)| *category_id)
+                .collect(),
+            discount,
+        })
+        .collect();
+
+    Ok(discounts)
+}
+
+/// Category ids of every given product, used for category-level discounts
+pub async fn get_product_categories(
+    product_ids: &[i32],
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<HashMap<i32, Vec<i32>>, diesel::result::Error> {
+    use crate::schema::product_categories;
+
+    let rows = product_categories::table
+        .filter(product_categories::product_id.eq_any(product_ids))
+        .select((
+            product_categories::product_id,
+            product_categories::category_id,
+        ))
+        .load::<(i32, i32)>(conn)
+        .await?;
+
+    let mut res: HashMap<i32, Vec<i32>> = HashMap::new();
+
+    for (product_id, category_id) in rows {
+        res.entry(product_id).or_default().push(category_id);
+    }
+
+    Ok(res)
+}
@@ -1,12 +1,13 @@
 use axum::{
-    Router,
-    routing::{delete, get, patch, post},
+    Router, middleware,
+    routing::{get, patch, post},
 };
 
 use super::handlers;
-use crate::utils::types::Pool;
+use crate::auth::rbac::{Permission, RequireRole};
+use crate::utils::types::AppState;
 
-pub fn get_routes() -> Router<Pool> {
+pub fn get_routes() -> Router<AppState> {
     Router::new()
         .route(
             "/discounts",
@@ -20,4 +21,13 @@ pub fn get_routes() -> Router<Pool> {
             "/discounts/{id}/products",
             post(handlers::add_discount_products).delete(handlers::remove_products_from_discount),
         )
+        .route(
+            "/discounts/{id}/categories",
+            post(handlers::add_discount_categories)
+                .delete(handlers::remove_categories_from_discount),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            RequireRole(Permission::ManageDiscounts),
+            RequireRole::middleware,
+        ))
 }
@@ -2,7 +2,24 @@ use diesel::prelude::*;
 use dotenvy::dotenv;
 use std::env;
 
+pub mod admin;
+pub mod auth;
+pub mod cart;
+pub mod category;
+pub mod config;
+pub mod coupon;
+pub mod currency;
+pub mod discount;
+pub mod migrations;
+pub mod notification;
+pub mod order;
+pub mod pool;
+pub mod product;
+pub mod rmq;
 pub mod schema;
+pub mod search;
+pub mod user;
+pub mod utils;
 
 pub fn establish_connection() -> PgConnection {
     dotenv().ok();
@@ -1,35 +1,67 @@
-#![allow(unused)]
-mod auth;
-mod cart;
-mod category;
-mod discount;
-mod notification;
-mod pool;
-mod product;
-mod rmq;
-mod user;
-mod utils;
-
 use axum::{
     Router,
     middleware::{self},
 };
 use listenfd::ListenFd;
 use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower_http::services::ServeDir;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::{pool::get_pool, rmq::client};
+use axum_shop::{
+    auth::{self, keys::JwtKeys},
+    cart, category,
+    config::Config,
+    coupon, currency, discount, migrations, order,
+    pool::get_pool,
+    product,
+    rmq::client,
+    search, user, utils,
+    utils::types::AppState,
+};
 
 #[tokio::main]
-async fn main() -> Result<(), String> {
+async fn main() {
     dotenv::dotenv().ok();
 
-    std::fs::create_dir_all("uploads")
+    if let Err(e) = run().await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), String> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let config = Config::load()?;
+
+    if args.first().is_some_and(|arg| arg == "migrate") {
+        return migrations::command(&args[1..], &config.database.url).await;
+    }
+
+    if let Some(arg) = args.iter().find(|arg| *arg != "--migrate") {
+        return Err(format!("Unknown argument: {}", arg));
+    }
+
+    let keys = JwtKeys::from_config(&config.auth)?;
+
+    std::fs::create_dir_all(&config.server.uploads_dir)
         .map_err(|e| format!("Failed to create a directory: {}", e))?;
 
-    let pool = get_pool().await?;
+    if migrations::migrate_on_startup(&args, config.database.run_migrations) {
+        for version in migrations::run_pending(&config.database.url).await? {
+            println!("Applied migration {}", version);
+        }
+    }
+
+    let pool = get_pool(&config.database.url).await?;
+    let state = AppState {
+        pool: pool.clone(),
+        config: Arc::new(config),
+        keys: Arc::new(keys),
+    };
 
     tracing_subscriber::registry()
         .with(
@@ -41,18 +73,37 @@ async fn main() -> Result<(), String> {
         .init();
 
     let routes = Router::new()
-        .nest_service("/uploads", ServeDir::new("uploads"))
+        .nest_service("/uploads", ServeDir::new(&state.config.server.uploads_dir))
         .merge(product::routes::get_routes())
         .merge(category::routes::get_routes())
         .merge(auth::routes::get_routes())
         .merge(cart::routes::get_routes())
         .merge(discount::routes::get_routes())
         .merge(user::routes::get_routes())
-        .layer(middleware::from_fn(utils::print_req_res))
-        .with_state(pool.clone());
+        .merge(order::routes::get_routes())
+        .merge(coupon::routes::get_routes())
+        .merge(currency::routes::get_routes())
+        .merge(search::routes::get_routes())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::rbac::load_claims,
+        ))
+        .layer(middleware::from_fn_with_state(
+            pool.clone(),
+            auth::api_keys::authenticate,
+        ))
+        .layer(middleware::from_fn(utils::print_req_res));
 
-    let app = Router::new().nest("/api", routes);
-    let app = app.fallback(utils::handler_404);
+    let app = Router::new()
+        .route(
+            "/.well-known/jwks.json",
+            axum::routing::get(auth::handlers::get_jwks),
+        )
+        .nest("/api", routes)
+        .with_state(state.clone());
+    let app = app
+        .fallback(utils::handler_404)
+        .layer(middleware::from_fn(utils::request_id));
     let mut listenfd = ListenFd::from_env();
 
     let listener = match listenfd.take_tcp_listener(0).unwrap() {
@@ -62,14 +113,27 @@ async fn main() -> Result<(), String> {
             TcpListener::from_std(listener).unwrap()
         }
         // otherwise fall back to local listening
-        None => TcpListener::bind("127.0.0.1:3000").await.unwrap(),
+        None => TcpListener::bind(state.config.server.bind_address)
+            .await
+            .map_err(|e| format!("Failed to bind {}: {}", state.config.server.bind_address, e))?,
     };
 
-    client::spawn_consumer("notifications", "discount_consumer", pool.clone());
-    client::spawn_consumer("user", "user_consumer", pool.clone());
+    client::spawn_consumer(
+        "notifications",
+        "discount_consumer",
+        pool.clone(),
+        state.config.clone(),
+    );
+    client::spawn_consumer("user", "user_consumer", pool.clone(), state.config.clone());
+    order::handlers::spawn_reservation_expiry(pool.clone(), state.config.clone());
 
     println!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 
     Ok(())
 }
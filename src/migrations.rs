@@ -0,0 +1,145 @@
+use diesel::migration::MigrationSource;
+use diesel::pg::{Pg, PgConnection};
+use diesel::prelude::*;
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
+
+/// Every migration in `migrations/`, compiled into the binary
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+const USAGE: &str = "usage: axum-shop migrate <list | run | revert [steps]>";
+
+/// Pending migrations are applied before serving when started with `--migrate`
+/// or with `database.run_migrations` set
+pub fn migrate_on_startup(args: &[String], run_migrations: bool) -> bool {
+    run_migrations || args.iter().any(|arg| arg == "--migrate")
+}
+
+/// Handles `migrate list`, `migrate run` and `migrate revert [steps]`
+pub async fn command(args: &[String], db_url: &str) -> Result<(), String> {
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["list"] => {
+            for (name, applied) in list(db_url).await? {
+                println!("[{}] {}", if applied { "X" } else { " " }, name);
+            }
+        }
+        ["run"] => {
+            let applied = run_pending(db_url).await?;
+
+            if applied.is_empty() {
+                println!("No pending migrations");
+            }
+
+            for version in applied {
+                println!("Applied {}", version);
+            }
+        }
+        ["revert"] => revert_and_print(db_url, 1).await?,
+        ["revert", steps] => {
+            let steps = steps
+                .parse()
+                .map_err(|_| format!("Invalid number of steps: {}", steps))?;
+
+            revert_and_print(db_url, steps).await?
+        }
+        _ => return Err(USAGE.to_owned()),
+    }
+
+    Ok(())
+}
+
+async fn revert_and_print(db_url: &str, steps: usize) -> Result<(), String> {
+    for version in revert(db_url, steps).await? {
+        println!("Reverted {}", version);
+    }
+
+    Ok(())
+}
+
+/// Migration names in order, with whether they are applied
+pub async fn list(db_url: &str) -> Result<Vec<(String, bool)>, String> {
+    with_connection(db_url, |conn| {
+        let applied: Vec<String> = conn
+            .applied_migrations()
+            .map_err(|e| format!("Failed to read applied migrations: {}", e))?
+            .iter()
+            .map(|version| version.to_string())
+            .collect();
+
+        let migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+            .map_err(|e| format!("Failed to read migrations: {}", e))?;
+
+        let res = migrations
+            .iter()
+            .map(|migration| {
+                let name = migration.name();
+                let applied = applied.contains(&name.version().to_string());
+
+                (name.to_string(), applied)
+            })
+            .collect();
+
+        Ok(res)
+    })
+    .await
+}
+
+/// Returns the versions that were applied
+pub async fn run_pending(db_url: &str) -> Result<Vec<String>, String> {
+    with_connection(db_url, |conn| {
+        let versions = conn
+            .run_pending_migrations(MIGRATIONS)
+            .map_err(|e| format!("Failed to run migrations: {}", e))?;
+
+        Ok(versions.iter().map(|version| version.to_string()).collect())
+    })
+    .await
+}
+
+/// Reverts the last `steps` applied migrations, returns their versions
+pub async fn revert(db_url: &str, steps: usize) -> Result<Vec<String>, String> {
+    with_connection(db_url, move |conn| {
+        let mut reverted = Vec::new();
+
+        for _ in 0..steps {
+            if conn
+                .applied_migrations()
+                .map_err(|e| format!("Failed to read applied migrations: {}", e))?
+                .is_empty()
+            {
+                break;
+            }
+
+            let version = conn
+                .revert_last_migration(MIGRATIONS)
+                .map_err(|e| format!("Failed to revert migration: {}", e))?;
+
+            reverted.push(version.to_string());
+        }
+
+        Ok(reverted)
+    })
+    .await
+}
+
+/// Migrations run on a blocking connection, outside of the async pool
+async fn with_connection<T, F>(db_url: &str, f: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut PgConnection) -> Result<T, String> + Send + 'static,
+{
+    let db_url = db_url.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = PgConnection::establish(&db_url)
+            .map_err(|e| format!("Failed to connect to the database: {}", e))?;
+
+        f(&mut conn)
+    })
+    .await
+    .map_err(|e| format!("Migration task failed: {}", e))?
+}
@@ -1,24 +1,24 @@
-use super::models::{DiscountNotification, Notification};
+use super::models::Notification;
 use lettre::message::Mailbox;
 use lettre::message::header::ContentType;
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::{Message, SmtpTransport, Transport};
-use std::env;
+use std::sync::Arc;
 use tera::{Context, Tera};
 
-use crate::utils::{internal_error, types::Pool};
-use axum::{
-    extract::{Json, Path, State},
-    http::StatusCode,
-};
-use diesel::{dsl::sql, prelude::*};
-use diesel_async::pooled_connection::AsyncDieselConnectionManager;
-use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use crate::config::{Config, SmtpConfig};
+use crate::utils::types::Pool;
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
 
 const NOTIFICATION_TEMPLATES_PATH: &str = "src/templates/**/*";
 
-pub async fn send_email(notification: Notification, pool: Pool) -> Result<(), String> {
-    use axum_shop::schema::users;
+pub async fn send_email(
+    notification: Notification,
+    pool: Pool,
+    config: Arc<Config>,
+) -> Result<(), String> {
+    use crate::schema::users;
 
     let mut conn = pool
         .get()
@@ -27,7 +27,7 @@ pub async fn send_email(notification: Notification, pool: Pool) -> Result<(), St
 
     match notification {
         Notification::Discount(data) => {
-            let users: Vec<String> = users::table
+            let _users: Vec<String> = users::table
                 .select(users::email)
                 .load(&mut conn)
                 .await
@@ -48,6 +48,7 @@ pub async fn send_email(notification: Notification, pool: Pool) -> Result<(), St
             // }
 
             build_email(
+                &config.smtp,
                 "kenny3850",
                 "kenny3850@gmail.com",
                 "Check out our new discounts",
@@ -56,17 +57,53 @@ pub async fn send_email(notification: Notification, pool: Pool) -> Result<(), St
             .await?;
         }
         Notification::WelcomeUser(data) => {
-            let html_body = render_html(&data, "welcome")?;
+            let _html_body = render_html(&data, "welcome")?;
 
             // build_email(&data.email, &data.email, "Welcome to Rust shop!", html_body).await?;
         }
-        _ => return Err("Failed to send an email".to_owned()),
+        Notification::OrderStatusChanged(data) => {
+            let html_body = render_html(&data, "order_status")?;
+
+            build_email(
+                &config.smtp,
+                &data.email,
+                &data.email,
+                "Your order has been updated",
+                html_body,
+            )
+            .await?;
+        }
+        Notification::VerifyEmail(data) => {
+            let html_body = render_html(&data, "verify_email")?;
+
+            build_email(
+                &config.smtp,
+                &data.email,
+                &data.email,
+                "Confirm your email address",
+                html_body,
+            )
+            .await?;
+        }
+        Notification::PasswordReset(data) => {
+            let html_body = render_html(&data, "password_reset")?;
+
+            build_email(
+                &config.smtp,
+                &data.email,
+                &data.email,
+                "Reset your password",
+                html_body,
+            )
+            .await?;
+        }
     }
 
     Ok(())
 }
 
 async fn build_email(
+    smtp: &SmtpConfig,
     receiver_name: &str,
     receiver_email: &str,
     subject: &str,
@@ -75,13 +112,13 @@ async fn build_email(
     let email = Message::builder()
         .from(Mailbox::new(
             Some("Rust shop".to_owned()),
-            "example@mail.com"
+            smtp.from
                 .parse()
                 .map_err(|e| format!("Failed to parse sender email: {}", e))?,
         ))
         .reply_to(Mailbox::new(
             Some("no-reply".to_owned()),
-            "no-reply@rust.shop"
+            smtp.reply_to
                 .parse()
                 .map_err(|e| format!("Failed to parse reply to email: {}", e))?,
         ))
@@ -96,12 +133,13 @@ async fn build_email(
         .body(body)
         .map_err(|e| format!("Failed to build a message: {}", e))?;
 
-    let creds = Credentials::new(
-        env::var("SMTP_USERNAME").map_err(|e| format!("smtp username must be set: {}", e))?,
-        env::var("SMTP_PASSWORD").map_err(|e| format!("smtp password must be set: {}", e))?,
-    );
+    let (Some(username), Some(password)) = (&smtp.username, &smtp.password) else {
+        return Err("smtp username and password must be set".to_owned());
+    };
+
+    let creds = Credentials::new(username.clone(), password.clone());
 
-    let mailer = SmtpTransport::relay("smtp.gmail.com")
+    let mailer = SmtpTransport::relay(&smtp.relay)
         .map_err(|e| format!("Wrong smtp transport: {}", e))?
         .credentials(creds)
         .build();
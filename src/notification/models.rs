@@ -1,4 +1,4 @@
-use axum_shop::schema::user_subscriptions;
+use crate::schema::user_subscriptions;
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -20,11 +20,63 @@ pub struct WelcomeNotification {
     pub email: String,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OrderStatusNotification {
+    pub event: String,
+    pub email: String,
+    pub order_id: uuid::Uuid,
+    pub from_status: crate::order::models::OrderStatus,
+    pub to_status: crate::order::models::OrderStatus,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct VerifyEmailNotification {
+    pub event: String,
+    pub email: String,
+    pub token: String,
+    pub verify_url: String,
+}
+
+/// Keeps the token, also part of `verify_url`, out of logs
+impl std::fmt::Debug for VerifyEmailNotification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VerifyEmailNotification")
+            .field("event", &self.event)
+            .field("email", &self.email)
+            .field("token", &"[redacted]")
+            .field("verify_url", &"[redacted]")
+            .finish()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasswordResetNotification {
+    pub event: String,
+    pub email: String,
+    pub reset_url: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Notification {
     Discount(DiscountNotification),
     WelcomeUser(WelcomeNotification),
+    OrderStatusChanged(OrderStatusNotification),
+    VerifyEmail(VerifyEmailNotification),
+    PasswordReset(PasswordResetNotification),
+}
+
+impl Notification {
+    /// Safe to log, unlike the payload which may carry a token or reset link
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Notification::Discount(_) => "Discount",
+            Notification::WelcomeUser(_) => "WelcomeUser",
+            Notification::OrderStatusChanged(_) => "OrderStatusChanged",
+            Notification::VerifyEmail(_) => "VerifyEmail",
+            Notification::PasswordReset(_) => "PasswordReset",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Queryable, Selectable, Insertable)]
@@ -0,0 +1,549 @@
+use super::models::{
+    NewOrder, NewOrderItem, NewOrderStatusHistory, Order, OrderItem, OrderStatus,
+    OrderStatusHistory, OrderWithItems, UpdateOrderStatus,
+};
+use crate::auth::models::AccessTokenClaims;
+use crate::auth::rbac::Permission;
+use crate::cart::models::Cart;
+use crate::config::{Config, RmqConfig};
+use crate::coupon::models::NewCouponRedemption;
+use crate::currency::models::{ConvertPrices, DisplayCurrency};
+use crate::product::handlers::{move_order_stock, reserve_stock};
+use crate::product::models::StockReason;
+use crate::utils::{AppError, ValidatedJson, types::Pool};
+use axum::extract::{Json, Path, State};
+use diesel::{dsl::sql, prelude::*};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use std::sync::Arc;
+use uuid::Uuid;
+
+const QUEUE_NAME: &str = "user";
+
+enum CheckoutError {
+    EmptyCart,
+    OutOfStock(String),
+    InvalidCoupon(String),
+    Database(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for CheckoutError {
+    fn from(err: diesel::result::Error) -> Self {
+        CheckoutError::Database(err)
+    }
+}
+
+const ORDER_ITEMS_JSON: &str = "COALESCE(
+    json_agg(order_items.* ORDER BY order_items.id) FILTER (WHERE order_items.id IS NOT NULL),
+    '[]'
+)";
+
+pub async fn checkout(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    claims: AccessTokenClaims,
+    currency: DisplayCurrency,
+) -> Result<Json<OrderWithItems>, AppError> {
+    use crate::schema::{
+        cart_products, carts, coupon_redemptions, coupons, order_items, order_status_history,
+        orders,
+    };
+
+    let mut conn = pool.get().await?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
+
+    if config.server.require_verified_email {
+        use crate::schema::users;
+
+        let verified = users::table
+            .find(&user_id)
+            .select(users::email_verified_at.is_not_null())
+            .get_result::<bool>(&mut conn)
+            .await?;
+
+        if !verified {
+            return Err(AppError::Forbidden(
+                "Please verify your email before checking out".to_owned(),
+            ));
+        }
+    }
+
+    let mut res = conn
+        .transaction::<OrderWithItems, CheckoutError, _>(move |mut conn| {
+            Box::pin(async move {
+                let cart = carts::table
+                    .filter(carts::user_id.eq(&user_id))
+                    .select(Cart::as_select())
+                    .for_update()
+                    .get_result(&mut conn)
+                    .await?;
+
+                // Lock the coupon so concurrent checkouts cannot exceed its usage limits
+                if let Some(coupon_id) = cart.coupon_id {
+                    coupons::table
+                        .find(coupon_id)
+                        .select(coupons::id)
+                        .for_update()
+                        .execute(&mut conn)
+                        .await?;
+                }
+
+                let cart_with_products =
+                    crate::cart::handlers::get_cart_with_products(&cart.id, conn).await?;
+
+                if cart_with_products.products.is_empty() {
+                    return Err(CheckoutError::EmptyCart);
+                }
+
+                if let Some(error) = cart_with_products
+                    .coupon
+                    .as_ref()
+                    .and_then(|coupon| coupon.error.clone())
+                {
+                    return Err(CheckoutError::InvalidCoupon(error));
+                }
+
+                let order_data = NewOrder {
+                    id: Uuid::new_v4(),
+                    user_id,
+                    total: cart_with_products.total,
+                    coupon_id: cart.coupon_id,
+                };
+
+                let order = diesel::insert_into(orders::table)
+                    .values(&order_data)
+                    .returning(Order::as_returning())
+                    .get_result(&mut conn)
+                    .await?;
+
+                let items: Vec<NewOrderItem> = cart_with_products
+                    .products
+                    .into_iter()
+                    .map(|line| NewOrderItem {
+                        order_id: order.id,
+                        product_id: Some(line.product.id),
+                        title: line.product.title,
+                        price: line.pricing.discounted_price,
+                        quantity: line.product.quantity,
+                        original_price: line.pricing.original_price,
+                        discount_id: line.pricing.discount_id,
+                    })
+                    .collect();
+
+                let items = diesel::insert_into(order_items::table)
+                    .values(&items)
+                    .returning(OrderItem::as_returning())
+                    .get_results(&mut conn)
+                    .await?;
+
+                for item in items.iter() {
+                    let Some(product_id) = item.product_id else {
+                        continue;
+                    };
+
+                    if !reserve_stock(product_id, item.quantity, &order.id, Some(user_id), conn)
+                        .await?
+                    {
+                        return Err(CheckoutError::OutOfStock(item.title.clone()));
+                    }
+                }
+
+                let history = NewOrderStatusHistory {
+                    order_id: order.id,
+                    from_status: None,
+                    to_status: OrderStatus::Pending,
+                    changed_by: Some(user_id),
+                };
+
+                diesel::insert_into(order_status_history::table)
+                    .values(&history)
+                    .execute(&mut conn)
+                    .await?;
+
+                if let Some(coupon_id) = cart.coupon_id {
+                    let redemption = NewCouponRedemption {
+                        coupon_id,
+                        user_id,
+                        order_id: order.id,
+                    };
+
+                    diesel::insert_into(coupon_redemptions::table)
+                        .values(&redemption)
+                        .execute(&mut conn)
+                        .await?;
+                }
+
+                diesel::delete(cart_products::table.filter(cart_products::cart_id.eq(&cart.id)))
+                    .execute(&mut conn)
+                    .await?;
+
+                let updated_at = chrono::Local::now().date_naive();
+
+                diesel::update(carts::table.find(&cart.id))
+                    .set((
+                        carts::updated_at.eq(&updated_at),
+                        carts::coupon_id.eq(None::<i32>),
+                    ))
+                    .execute(&mut conn)
+                    .await?;
+
+                Ok(OrderWithItems { order, items })
+            })
+        })
+        .await
+        .map_err(|e| match e {
+            CheckoutError::EmptyCart => AppError::BadRequest("Cart is empty".to_owned()),
+            CheckoutError::OutOfStock(title) => {
+                AppError::Conflict(format!("Not enough stock for {}", title))
+            }
+            CheckoutError::InvalidCoupon(error) => AppError::Conflict(error),
+            CheckoutError::Database(e) => e.into(),
+        })?;
+
+    res.convert_prices(&currency);
+
+    Ok(Json(res))
+}
+
+pub async fn get_current_user_orders(
+    State(pool): State<Pool>,
+    claims: AccessTokenClaims,
+    currency: DisplayCurrency,
+) -> Result<Json<Vec<OrderWithItems>>, AppError> {
+    use crate::schema::{order_items, orders};
+
+    let mut conn = pool.get().await?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
+
+    let rows = orders::table
+        .filter(orders::user_id.eq(&user_id))
+        .left_join(order_items::table.on(orders::id.eq(order_items::order_id)))
+        .select((
+            Order::as_select(),
+            sql::<diesel::sql_types::Json>(ORDER_ITEMS_JSON),
+        ))
+        .group_by(orders::id)
+        .order(orders::created_at.desc())
+        .load::<(Order, serde_json::Value)>(&mut conn)
+        .await?;
+
+    let mut res: Vec<OrderWithItems> = rows
+        .into_iter()
+        .map(|(order, items_json)| {
+            let items = serde_json::from_value(items_json).unwrap_or_default();
+            OrderWithItems { order, items }
+        })
+        .collect();
+
+    res.convert_prices(&currency);
+
+    Ok(Json(res))
+}
+
+pub async fn get_order_by_id(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+    claims: AccessTokenClaims,
+    currency: DisplayCurrency,
+) -> Result<Json<OrderWithItems>, AppError> {
+    let mut conn = pool.get().await?;
+
+    let mut order = get_order_with_items(&id, &mut conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("Order not found".to_owned()),
+            e => e.into(),
+        })?;
+
+    if order.order.user_id.to_string() != claims.sub
+        && !claims.has_permission(Permission::ManageOrders)
+    {
+        return Err(AppError::NotFound("Order not found".to_owned()));
+    }
+
+    order.convert_prices(&currency);
+
+    Ok(Json(order))
+}
+
+pub async fn update_order_status(
+    State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
+    Path(id): Path<Uuid>,
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<UpdateOrderStatus>,
+) -> Result<Json<Order>, AppError> {
+    use crate::schema::orders;
+
+    let mut conn = pool.get().await?;
+
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
+
+    let order = orders::table
+        .find(&id)
+        .select(Order::as_select())
+        .get_result(&mut conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("Order not found".to_owned()),
+            e => e.into(),
+        })?;
+
+    let is_owner = order.user_id == user_id;
+    let is_admin = claims.has_permission(Permission::ManageOrders);
+
+    if !is_admin && !is_owner {
+        return Err(AppError::NotFound("Order not found".to_owned()));
+    }
+
+    // Customers may only cancel their own orders, every other transition is done by an admin
+    if !is_admin && payload.status != OrderStatus::Cancelled {
+        return Err(AppError::Forbidden(
+            "Only admins can change order status".to_owned(),
+        ));
+    }
+
+    let from = order.status;
+    let to = payload.status;
+
+    if !from.can_transition_to(&to) {
+        return Err(AppError::Conflict(format!(
+            "Cannot change order status from {} to {}",
+            from, to
+        )));
+    }
+
+    let res = conn
+        .transaction::<Order, diesel::result::Error, _>(move |conn| {
+            Box::pin(async move { change_order_status(&id, from, to, Some(user_id), conn).await })
+        })
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => {
+                AppError::Conflict("Order status has been changed by another request".to_owned())
+            }
+            e => e.into(),
+        })?;
+
+    if let Err(er) = publish_status_change(&config.rmq, &res, from, &mut conn).await {
+        eprintln!("Failed to publish event: {:?}", er);
+    }
+
+    Ok(Json(res))
+}
+
+pub async fn get_order_history(
+    State(pool): State<Pool>,
+    Path(id): Path<Uuid>,
+    claims: AccessTokenClaims,
+) -> Result<Json<Vec<OrderStatusHistory>>, AppError> {
+    use crate::schema::{order_status_history, orders};
+
+    let mut conn = pool.get().await?;
+
+    let order = orders::table
+        .find(&id)
+        .select(Order::as_select())
+        .get_result(&mut conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("Order not found".to_owned()),
+            e => e.into(),
+        })?;
+
+    if order.user_id.to_string() != claims.sub && !claims.has_permission(Permission::ManageOrders) {
+        return Err(AppError::NotFound("Order not found".to_owned()));
+    }
+
+    let res = order_status_history::table
+        .filter(order_status_history::order_id.eq(&id))
+        .select(OrderStatusHistory::as_select())
+        .order(order_status_history::id.asc())
+        .load(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+/// Moves an order from `from` to `to`, records the transition and keeps the
+/// stock reservations in sync. Fails with `NotFound` when the order is no longer in `from`.
+async fn change_order_status(
+    order_id: &Uuid,
+    from: OrderStatus,
+    to: OrderStatus,
+    changed_by: Option<Uuid>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> std::result::Result<Order, diesel::result::Error> {
+    use crate::schema::{order_status_history, orders};
+
+    let order =
+        diesel::update(orders::table.filter(orders::id.eq(order_id).and(orders::status.eq(&from))))
+            .set(orders::status.eq(&to))
+            .returning(Order::as_returning())
+            .get_result(conn)
+            .await?;
+
+    let history = NewOrderStatusHistory {
+        order_id: order.id,
+        from_status: Some(from),
+        to_status: to,
+        changed_by,
+    };
+
+    diesel::insert_into(order_status_history::table)
+        .values(&history)
+        .execute(conn)
+        .await?;
+
+    match (from, to) {
+        (OrderStatus::Pending, OrderStatus::Cancelled) => {
+            move_order_stock(order_id, StockReason::Released, changed_by, conn).await?
+        }
+        (OrderStatus::Pending, OrderStatus::Paid) => {
+            move_order_stock(order_id, StockReason::Sold, changed_by, conn).await?
+        }
+        (OrderStatus::Paid, OrderStatus::Cancelled) => {
+            move_order_stock(order_id, StockReason::Returned, changed_by, conn).await?
+        }
+        // Delivered goods are with the customer, they come back through a
+        // `Returned` stock adjustment once they are actually received
+        (OrderStatus::Paid, OrderStatus::Refunded) => {
+            move_order_stock(order_id, StockReason::Refunded, changed_by, conn).await?
+        }
+        _ => {}
+    }
+
+    Ok(order)
+}
+
+/// Cancels pending orders whose reservation has expired so the stock is released
+pub async fn cancel_expired_orders(pool: &Pool, config: &Config) -> Result<usize, AppError> {
+    use crate::schema::orders;
+
+    let mut conn = pool.get().await?;
+
+    let expired_before = chrono::Utc::now().naive_utc()
+        - chrono::Duration::minutes(config.order.reservation_ttl_minutes);
+
+    let expired = orders::table
+        .filter(orders::status.eq(OrderStatus::Pending))
+        .filter(orders::created_at.lt(expired_before))
+        .select(orders::id)
+        .load::<Uuid>(&mut conn)
+        .await?;
+
+    let mut cancelled = 0;
+
+    for id in expired {
+        let res = conn
+            .transaction::<Order, diesel::result::Error, _>(move |conn| {
+                Box::pin(async move {
+                    change_order_status(
+                        &id,
+                        OrderStatus::Pending,
+                        OrderStatus::Cancelled,
+                        None,
+                        conn,
+                    )
+                    .await
+                })
+            })
+            .await;
+
+        match res {
+            Ok(order) => {
+                cancelled += 1;
+
+                if let Err(er) =
+                    publish_status_change(&config.rmq, &order, OrderStatus::Pending, &mut conn)
+                        .await
+                {
+                    eprintln!("Failed to publish event: {:?}", er);
+                }
+            }
+            // Paid or cancelled in the meantime
+            Err(diesel::result::Error::NotFound) => {}
+            Err(e) => return Err(AppError::from(e)),
+        }
+    }
+
+    Ok(cancelled)
+}
+
+pub fn spawn_reservation_expiry(pool: Pool, config: Arc<Config>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+
+        loop {
+            interval.tick().await;
+
+            if let Err(er) = cancel_expired_orders(&pool, &config).await {
+                eprintln!("Failed to cancel expired orders: {:?}", er);
+            }
+        }
+    });
+}
+
+pub async fn publish_status_change(
+    rmq: &RmqConfig,
+    order: &Order,
+    from: OrderStatus,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(), AppError> {
+    use crate::schema::{user_subscriptions, users};
+
+    let subscribed = user_subscriptions::table
+        .filter(user_subscriptions::user_id.eq(&order.user_id))
+        .filter(user_subscriptions::orders_notifications.eq(true))
+        .inner_join(users::table)
+        .select(users::email)
+        .first::<String>(conn)
+        .await
+        .optional()?;
+
+    let Some(email) = subscribed else {
+        return Ok(());
+    };
+
+    let event = serde_json::json!({
+        "type": "OrderStatusChanged",
+        "event": "order_status_changed",
+        "email": email,
+        "order_id": order.id,
+        "from_status": from,
+        "to_status": order.status,
+    })
+    .to_string();
+
+    crate::rmq::client::publish_event(rmq, QUEUE_NAME, &event).await
+}
+
+pub async fn get_order_with_items(
+    order_id: &Uuid,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> std::result::Result<OrderWithItems, diesel::result::Error> {
+    use crate::schema::{order_items, orders};
+
+    let (order, items_json) = orders::table
+        .find(order_id)
+        .left_join(order_items::table.on(orders::id.eq(order_items::order_id)))
+        .select((
+            Order::as_select(),
+            sql::<diesel::sql_types::Json>(ORDER_ITEMS_JSON),
+        ))
+        .group_by(orders::id)
+        .get_result::<(Order, serde_json::Value)>(conn)
+        .await?;
+
+    let res = OrderWithItems {
+        order,
+        items: serde_json::from_value(items_json).unwrap_or_default(),
+    };
+
+    Ok(res)
+}
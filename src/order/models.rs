@@ -0,0 +1,137 @@
+use crate::schema::{order_items, order_status_history, orders};
+use crate::utils::Money;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Selectable, Identifiable)]
+#[diesel(table_name = orders)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Order {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub status: OrderStatus,
+    pub total: Money,
+    pub created_at: NaiveDateTime,
+    pub coupon_id: Option<i32>,
+    pub currency: String,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = orders)]
+pub struct NewOrder {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub total: Money,
+    pub coupon_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Selectable, Identifiable, Associations)]
+#[diesel(table_name = order_items)]
+#[diesel(belongs_to(Order))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OrderItem {
+    pub id: i32,
+    pub order_id: Uuid,
+    pub product_id: Option<i32>,
+    pub title: String,
+    pub price: Money,
+    pub quantity: i32,
+    pub original_price: Money,
+    pub discount_id: Option<i32>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = order_items)]
+pub struct NewOrderItem {
+    pub order_id: Uuid,
+    pub product_id: Option<i32>,
+    pub title: String,
+    pub price: Money,
+    pub quantity: i32,
+    pub original_price: Money,
+    pub discount_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderWithItems {
+    #[serde(flatten)]
+    pub order: Order,
+    pub items: Vec<OrderItem>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::OrderStatus"]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatus {
+    Pending,
+    Paid,
+    Shipped,
+    Delivered,
+    Cancelled,
+    Refunded,
+}
+
+impl OrderStatus {
+    pub fn can_transition_to(&self, next: &OrderStatus) -> bool {
+        use OrderStatus::*;
+
+        matches!(
+            (self, next),
+            (Pending, Paid)
+                | (Pending, Cancelled)
+                | (Paid, Shipped)
+                | (Paid, Cancelled)
+                | (Paid, Refunded)
+                | (Shipped, Delivered)
+                | (Delivered, Refunded)
+        )
+    }
+
+    /// Label of the `order_status` postgres enum
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Paid => "paid",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Delivered => "delivered",
+            OrderStatus::Cancelled => "cancelled",
+            OrderStatus::Refunded => "refunded",
+        }
+    }
+}
+
+impl std::fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Selectable, Identifiable, Associations)]
+#[diesel(table_name = order_status_history)]
+#[diesel(belongs_to(Order))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct OrderStatusHistory {
+    pub id: i32,
+    pub order_id: Uuid,
+    pub from_status: Option<OrderStatus>,
+    pub to_status: OrderStatus,
+    pub changed_by: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = order_status_history)]
+pub struct NewOrderStatusHistory {
+    pub order_id: Uuid,
+    pub from_status: Option<OrderStatus>,
+    pub to_status: OrderStatus,
+    pub changed_by: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct UpdateOrderStatus {
+    pub status: OrderStatus,
+}
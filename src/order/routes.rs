@@ -0,0 +1,16 @@
+use axum::{
+    Router,
+    routing::{get, patch, post},
+};
+
+use super::handlers;
+use crate::utils::types::AppState;
+
+pub fn get_routes() -> Router<AppState> {
+    Router::new()
+        .route("/checkout", post(handlers::checkout))
+        .route("/me/orders", get(handlers::get_current_user_orders))
+        .route("/orders/{id}", get(handlers::get_order_by_id))
+        .route("/orders/{id}/status", patch(handlers::update_order_status))
+        .route("/orders/{id}/history", get(handlers::get_order_history))
+}
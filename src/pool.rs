@@ -1,11 +1,8 @@
 use diesel_async::{AsyncPgConnection, pooled_connection::AsyncDieselConnectionManager};
-use std::env;
-
-pub async fn get_pool() -> Result<bb8::Pool<AsyncDieselConnectionManager<AsyncPgConnection>>, String>
-{
-    let db_url =
-        env::var("DATABASE_URL").map_err(|e| format!("Data base url must be set: {}", e))?;
 
+pub async fn get_pool(
+    db_url: &str,
+) -> Result<bb8::Pool<AsyncDieselConnectionManager<AsyncPgConnection>>, String> {
     let config = AsyncDieselConnectionManager::<AsyncPgConnection>::new(db_url);
     let pool = bb8::Pool::builder()
         .build(config)
@@ -1,59 +1,63 @@
 #![allow(dead_code)]
 
 use super::models::{
-    CreateProductWithCategories, NewProduct, OrderByParams, Product, ProductCategory,
-    ProductWithCategories, ProductWithCategoriesResponse, QueryParams, SortByParams, UpdateProduct,
+    AdjustStock, CreateProductWithCategories, NewProduct, NewStockMovement, OrderByParams, Product,
+    ProductCategory, ProductWithCategories, ProductWithCategoriesResponse, QueryParams,
+    SortByParams, StockMovement, StockReason, UpdateProduct,
 };
-use crate::utils::internal_error;
+use crate::auth::models::AccessTokenClaims;
+use crate::category::models::Category;
+use crate::config::Config;
+use crate::currency::models::{ConvertPrices, DisplayCurrency};
+use crate::discount::pricing::{PriceResolver, get_product_categories};
+use crate::search::handlers::{MAX_PAGE_SIZE, build_facets, prefix_tsquery, search_config};
 use crate::utils::types::Pool;
-use axum::{
-    extract::{Json, Multipart, Path, Query, State},
-    http::StatusCode,
-    response::IntoResponse,
-};
-use diesel::{
-    dsl::{count_star, sql},
-    prelude::*,
-    sql_query,
-    sql_types::{Array, Text},
-};
-use diesel_async::RunQueryDsl;
+use crate::utils::{AppError, ValidatedJson};
+use axum::extract::{Json, Multipart, Path, Query, State};
+use diesel::{dsl::sql, prelude::*};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
-use validator::ValidateRequired;
+
+diesel::define_sql_function!(
+    fn coalesce(
+        x: diesel::sql_types::Nullable<diesel::sql_types::Integer>,
+        y: diesel::sql_types::Integer,
+    ) -> diesel::sql_types::Integer
+);
 
 pub async fn create_product(
     State(pool): State<Pool>,
-    Json(payload): Json<NewProduct>,
-) -> Result<Json<Product>, (StatusCode, String)> {
-    use axum_shop::schema::products;
+    ValidatedJson(payload): ValidatedJson<NewProduct>,
+) -> Result<Json<Product>, AppError> {
+    use crate::schema::products;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let res = diesel::insert_into(products::table)
         .values(&payload)
         .returning(Product::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
 
 pub async fn create_product_with_categories(
     State(pool): State<Pool>,
-    Json(payload): Json<CreateProductWithCategories>,
-) -> Result<Json<Product>, (StatusCode, String)> {
-    use axum_shop::schema::{product_categories, products};
+    ValidatedJson(payload): ValidatedJson<CreateProductWithCategories>,
+) -> Result<Json<Product>, AppError> {
+    use crate::schema::{product_categories, products};
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let product = diesel::insert_into(products::table)
         .values(&payload.product)
         .returning(Product::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     let categories = payload
         .category_ids
@@ -67,20 +71,30 @@ pub async fn create_product_with_categories(
     diesel::insert_into(product_categories::table)
         .values(&categories)
         .execute(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(product))
 }
 
 pub async fn get_products(
     State(pool): State<Pool>,
+    currency: DisplayCurrency,
     query_params: Query<QueryParams>,
-) -> Result<Json<ProductWithCategoriesResponse>, (StatusCode, String)> {
-    use axum_shop::schema::{categories, product_categories, products};
+) -> Result<Json<ProductWithCategoriesResponse>, AppError> {
+    use crate::schema::{categories, product_categories, products};
     use diesel_full_text_search::*;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
+
+    // Price filters are given in the display currency
+    let min_price = query_params
+        .min_price
+        .as_ref()
+        .map(|price| currency.to_base(price));
+    let max_price = query_params
+        .max_price
+        .as_ref()
+        .map(|price| currency.to_base(price));
 
     let mut query = products::table
         .left_join(product_categories::table.on(products::id.eq(product_categories::product_id)))
@@ -92,58 +106,83 @@ pub async fn get_products(
         .group_by(products::id)
         .into_boxed();
 
-    let mut count_query = products::table
-        .left_join(product_categories::table.on(products::id.eq(product_categories::product_id)))
-        .left_join(categories::table.on(product_categories::category_id.eq(categories::id)))
-        .into_boxed();
-
     if let Some(cat_id) = query_params.category_id {
         query = query.filter(product_categories::category_id.eq(cat_id));
-        count_query = count_query.filter(product_categories::category_id.eq(cat_id));
     }
 
-    if let Some(min_price) = query_params.min_price {
+    if let Some(min_price) = &min_price {
         query = query.filter(
             products::price
                 .gt(min_price)
                 .or(products::price.eq(min_price)),
         );
-
-        count_query = count_query.filter(
-            products::price
-                .gt(min_price)
-                .or(products::price.eq(min_price)),
-        );
     }
 
-    if let Some(max_price) = query_params.max_price {
+    if let Some(max_price) = &max_price {
         query = query.filter(
             products::price
                 .lt(max_price)
                 .or(products::price.eq(max_price)),
         );
-
-        count_query = count_query.filter(
-            products::price
-                .lt(max_price)
-                .or(products::price.eq(max_price)),
-        );
     }
 
-    if let Some(title) = &query_params.search_title {
-        // query = query.filter(to_tsvector(products::title).matches(to_tsquery(title)));
-        query = query.filter(products::title.ilike(format!("%{}%", title)));
-        count_query = count_query.filter(products::title.ilike(format!("%{}%", title)));
+    let search_query = query_params
+        .search_title
+        .as_deref()
+        .and_then(prefix_tsquery);
+
+    if let Some(search_query) = &search_query {
+        let tsquery = || to_tsquery_with_search_config(search_config(), search_query.clone());
+
+        query = query.filter(products::search_vector.matches(tsquery()));
+
+        // Best matches first unless another order is requested below
+        query = query.order(ts_rank(products::search_vector, tsquery()).desc());
     };
 
-    let total = count_query
-        .select(diesel::dsl::count_distinct(products::id))
-        .first::<i64>(&mut conn)
-        .await
-        .map_err(internal_error)?;
+    // Same filters without the category joins, one row per product
+    let filtered = || {
+        let mut filtered = products::table.into_boxed();
+
+        if let Some(cat_id) = query_params.category_id {
+            filtered = filtered.filter(
+                products::id.eq_any(
+                    product_categories::table
+                        .filter(product_categories::category_id.eq(cat_id))
+                        .select(product_categories::product_id),
+                ),
+            );
+        }
 
-    let page_size = query_params.limit.unwrap_or(10);
-    let page = query_params.offset.unwrap_or(0) / page_size + 1;
+        if let Some(min_price) = &min_price {
+            filtered = filtered.filter(products::price.ge(min_price));
+        }
+
+        if let Some(max_price) = &max_price {
+            filtered = filtered.filter(products::price.le(max_price));
+        }
+
+        if let Some(search_query) = &search_query {
+            filtered = filtered.filter(products::search_vector.matches(
+                to_tsquery_with_search_config(search_config(), search_query.clone()),
+            ));
+        }
+
+        filtered
+    };
+
+    let (total, facets) = if query_params.facets.unwrap_or(false) {
+        let (total, facets) = build_facets(filtered, &currency, &mut conn).await?;
+
+        (total, Some(facets))
+    } else {
+        let total = filtered().count().get_result::<i64>(&mut conn).await?;
+
+        (total, None)
+    };
+
+    let page_size = query_params.limit.unwrap_or(10).clamp(1, MAX_PAGE_SIZE);
+    let page = query_params.offset.unwrap_or(0).max(0) / page_size + 1;
 
     query = query.limit(page_size).offset((page - 1) * page_size);
 
@@ -159,47 +198,58 @@ pub async fn get_products(
             (SortByParams::Price, OrderByParams::Desc) => {
                 query = query.order(products::price.desc())
             }
-
-            _ => {
-                return Err((StatusCode::BAD_REQUEST, "Invalid sort param".to_owned()));
-            }
         }
     };
 
     let rows = query
         .load::<(Product, serde_json::Value)>(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
+
+    let product_ids: Vec<i32> = rows.iter().map(|(product, _)| product.id).collect();
+
+    let resolver = PriceResolver::load(&mut conn).await?;
+    let product_categories = get_product_categories(&product_ids, &mut conn).await?;
 
     let products_with_categories: Vec<ProductWithCategories> = rows
         .into_iter()
         .map(|(product, categories_json)| {
             let categories = serde_json::from_value(categories_json).unwrap_or_default();
+            let category_ids = product_categories
+                .get(&product.id)
+                .map(Vec::as_slice)
+                .unwrap_or(&[]);
+            let pricing = resolver.price_for(product.id, category_ids, &product.price);
+
             ProductWithCategories {
                 product,
                 categories,
+                pricing,
             }
         })
         .collect();
 
-    let res = ProductWithCategoriesResponse {
+    let mut res = ProductWithCategoriesResponse {
         total,
         page,
         page_size,
         has_next: page * page_size < total,
         products: products_with_categories,
+        facets,
     };
 
+    res.convert_prices(&currency);
+
     Ok(Json(res))
 }
 
 pub async fn get_product_by_id(
     State(pool): State<Pool>,
+    currency: DisplayCurrency,
     Path(id): Path<i32>,
-) -> Result<Json<ProductWithCategories>, (StatusCode, String)> {
-    use axum_shop::schema::{categories, product_categories, products};
+) -> Result<Json<ProductWithCategories>, AppError> {
+    use crate::schema::{categories, product_categories, products};
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let (product, categories_json) = products::table
         .find(id)
@@ -213,30 +263,37 @@ pub async fn get_product_by_id(
         ))
         .group_by(products::id)
         .get_result::<(Product, serde_json::Value)>(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
+
+    let categories: Vec<Category> = serde_json::from_value(categories_json).unwrap_or_default();
+    let category_ids: Vec<i32> = categories.iter().map(|category| category.id).collect();
+
+    let resolver = PriceResolver::load(&mut conn).await?;
+    let pricing = resolver.price_for(product.id, &category_ids, &product.price);
 
-    let res = ProductWithCategories {
-        product: product,
-        categories: serde_json::from_value(categories_json).unwrap_or_default(),
+    let mut res = ProductWithCategories {
+        product,
+        categories,
+        pricing,
     };
 
+    res.convert_prices(&currency);
+
     Ok(Json(res))
 }
 
 pub async fn delete_product(
     Path(id): Path<i32>,
     State(pool): State<Pool>,
-) -> Result<Json<Product>, (StatusCode, String)> {
-    use axum_shop::schema::products;
+) -> Result<Json<Product>, AppError> {
+    use crate::schema::products;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let res = diesel::delete(products::table.find(id))
         .returning(Product::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -244,46 +301,44 @@ pub async fn delete_product(
 pub async fn update_product(
     State(pool): State<Pool>,
     Path(id): Path<i32>,
-    Json(payload): Json<UpdateProduct>,
-) -> Result<Json<Product>, (StatusCode, String)> {
-    use axum_shop::schema::products;
+    ValidatedJson(payload): ValidatedJson<UpdateProduct>,
+) -> Result<Json<Product>, AppError> {
+    use crate::schema::products;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let res = diesel::update(products::table.find(id))
         .set(&payload)
         .returning(Product::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
 
 pub async fn upload_image(
     State(pool): State<Pool>,
+    State(config): State<Arc<Config>>,
     Path(id): Path<i32>,
     mut multipart: Multipart,
-) -> Result<(), (StatusCode, String)> {
-    use axum_shop::schema::products;
+) -> Result<(), AppError> {
+    use crate::schema::products;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let product = products::table
         .find(id)
         .select(Product::as_select())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     drop(product);
 
     let mut filename: Option<String> = None;
 
-    while let Some(field) = multipart.next_field().await.map_err(internal_error)? {
+    while let Some(field) = multipart.next_field().await? {
         if field.name().is_none() || field.file_name().is_none() {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
+            return Err(AppError::BadRequest(
                 "Unable to upload file without a name".to_owned(),
             ));
         }
@@ -293,8 +348,7 @@ pub async fn upload_image(
             .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref());
 
         if content_type != mime::IMAGE_JPEG && content_type != mime::IMAGE_PNG {
-            return Err((
-                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            return Err(AppError::UnsupportedMediaType(
                 "Only JPEG and PNG images are allowed".to_owned(),
             ));
         };
@@ -307,26 +361,21 @@ pub async fn upload_image(
 
         let date = chrono::Local::now().format("%Y-%m-%d_%H:%M:%S");
 
-        let saved_file = format!("uploads/{}_{}.{}", Uuid::new_v4(), date, extension);
+        let name = format!("{}_{}.{}", Uuid::new_v4(), date, extension);
+        let saved_file = config.server.uploads_dir.join(&name);
 
-        filename = Some(format!("{}_{}.{}", Uuid::new_v4(), date, extension));
+        filename = Some(name);
 
-        let mut file = tokio::fs::File::create(&saved_file).await.map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Unable to create a file".to_owned(),
-            )
-        })?;
+        let mut file = tokio::fs::File::create(&saved_file)
+            .await
+            .map_err(|_| AppError::internal("Unable to create a file".to_owned()))?;
 
         let mut field = field;
 
-        while let Some(chunk) = field.chunk().await.map_err(internal_error)? {
-            file.write_all(&chunk).await.map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to write a file: {}", e),
-                )
-            })?;
+        while let Some(chunk) = field.chunk().await? {
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| AppError::internal(format!("Failed to write a file: {}", e)))?;
         }
     }
 
@@ -335,8 +384,219 @@ pub async fn upload_image(
             .set(products::image.eq(image))
             .returning(Product::as_returning())
             .get_result(&mut conn)
-            .await
-            .map_err(internal_error)?;
+            .await?;
+    };
+    Ok(())
+}
+
+pub async fn adjust_stock(
+    State(pool): State<Pool>,
+    Path(id): Path<i32>,
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<AdjustStock>,
+) -> Result<Json<Product>, AppError> {
+    use crate::schema::{products, stock_movements};
+
+    let valid = match payload.reason {
+        StockReason::Restock | StockReason::Returned => payload.quantity > 0,
+        StockReason::Damaged => payload.quantity < 0,
+        StockReason::Correction => payload.quantity != 0,
+        _ => false,
     };
+
+    if !valid {
+        return Err(AppError::BadRequest(
+            "Quantity does not match the reason code".to_owned(),
+        ));
+    }
+
+    let user_id = uuid::Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
+
+    let mut conn = pool.get().await?;
+
+    products::table
+        .find(id)
+        .select(Product::as_select())
+        .get_result(&mut conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("Product not found".to_owned()),
+            e => e.into(),
+        })?;
+
+    let res = conn
+        .transaction::<Product, diesel::result::Error, _>(move |mut conn| {
+            Box::pin(async move {
+                // The first adjustment of an untracked product starts tracking it
+                let stock = coalesce(products::stock, 0) + payload.quantity;
+
+                let product = diesel::update(
+                    products::table
+                        .find(id)
+                        .filter(stock.ge(products::reserved)),
+                )
+                .set(products::stock.eq(stock.nullable()))
+                .returning(Product::as_returning())
+                .get_result(&mut conn)
+                .await?;
+
+                let movement = NewStockMovement {
+                    product_id: id,
+                    quantity: payload.quantity,
+                    reason: payload.reason,
+                    note: payload.note,
+                    order_id: None,
+                    created_by: Some(user_id),
+                };
+
+                diesel::insert_into(stock_movements::table)
+                    .values(&movement)
+                    .execute(&mut conn)
+                    .await?;
+
+                Ok(product)
+            })
+        })
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => {
+                AppError::Conflict("Stock cannot go below the reserved quantity".to_owned())
+            }
+            e => e.into(),
+        })?;
+
+    Ok(Json(res))
+}
+
+pub async fn get_stock_movements(
+    State(pool): State<Pool>,
+    Path(id): Path<i32>,
+) -> Result<Json<Vec<StockMovement>>, AppError> {
+    use crate::schema::stock_movements;
+
+    let mut conn = pool.get().await?;
+
+    let res = stock_movements::table
+        .filter(stock_movements::product_id.eq(id))
+        .select(StockMovement::as_select())
+        .order(stock_movements::id.desc())
+        .load(&mut conn)
+        .await?;
+
+    Ok(Json(res))
+}
+
+/// Reserves `quantity` items of a product for an order, products whose stock is
+/// not tracked always have enough.
+/// Returns `false` without changing anything when there is not enough available stock.
+pub async fn reserve_stock(
+    product_id: i32,
+    quantity: i32,
+    order_id: &uuid::Uuid,
+    created_by: Option<uuid::Uuid>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> std::result::Result<bool, diesel::result::Error> {
+    use crate::schema::{products, stock_movements};
+
+    let updated = diesel::update(
+        products::table.find(product_id).filter(
+            products::stock
+                .is_null()
+                .or((products::stock - products::reserved.nullable()).ge(quantity)),
+        ),
+    )
+    .set(products::reserved.eq(products::reserved + quantity))
+    .execute(conn)
+    .await?;
+
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    let movement = NewStockMovement {
+        product_id,
+        quantity,
+        reason: StockReason::Reserved,
+        note: None,
+        order_id: Some(*order_id),
+        created_by,
+    };
+
+    diesel::insert_into(stock_movements::table)
+        .values(&movement)
+        .execute(conn)
+        .await?;
+
+    Ok(true)
+}
+
+/// Moves the stock of every item of an order according to `reason`:
+/// `Released` frees the reservation, `Sold` takes reserved items out of stock
+/// and `Returned` puts sold items back.
+pub async fn move_order_stock(
+    order_id: &uuid::Uuid,
+    reason: StockReason,
+    created_by: Option<uuid::Uuid>,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> std::result::Result<(), diesel::result::Error> {
+    use crate::schema::{order_items, products, stock_movements};
+
+    let items = order_items::table
+        .filter(order_items::order_id.eq(order_id))
+        .filter(order_items::product_id.is_not_null())
+        .select((
+            order_items::product_id.assume_not_null(),
+            order_items::quantity,
+        ))
+        .load::<(i32, i32)>(conn)
+        .await?;
+
+    for (product_id, quantity) in items {
+        let target = products::table.find(product_id);
+
+        let movement_qty = match reason {
+            StockReason::Released => {
+                diesel::update(target)
+                    .set(products::reserved.eq(products::reserved - quantity))
+                    .execute(conn)
+                    .await?;
+                -quantity
+            }
+            StockReason::Sold => {
+                diesel::update(target)
+                    .set((
+                        products::stock.eq(products::stock - quantity),
+                        products::reserved.eq(products::reserved - quantity),
+                    ))
+                    .execute(conn)
+                    .await?;
+                -quantity
+            }
+            StockReason::Returned | StockReason::Refunded => {
+                diesel::update(target)
+                    .set(products::stock.eq(products::stock + quantity))
+                    .execute(conn)
+                    .await?;
+                quantity
+            }
+            _ => return Err(diesel::result::Error::RollbackTransaction),
+        };
+
+        let movement = NewStockMovement {
+            product_id,
+            quantity: movement_qty,
+            reason,
+            note: None,
+            order_id: Some(*order_id),
+            created_by,
+        };
+
+        diesel::insert_into(stock_movements::table)
+            .values(&movement)
+            .execute(conn)
+            .await?;
+    }
+
     Ok(())
 }
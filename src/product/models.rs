@@ -1,4 +1,5 @@
-use axum_shop::schema::{product_categories, products};
+use crate::schema::{product_categories, products, stock_movements};
+use crate::utils::{Money, validation};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use validator::Validate;
@@ -9,16 +10,20 @@ use validator::Validate;
 pub struct Product {
     pub id: i32,
     pub title: String,
-    pub price: f64,
+    pub price: Money,
     pub description: String,
     pub image: Option<String>,
+    /// `None` while the stock is not tracked, the product then sells without a limit
+    pub stock: Option<i32>,
+    pub reserved: i32,
+    pub currency: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProductWithQty {
     pub id: i32,
     pub title: String,
-    pub price: f64,
+    pub price: Money,
     pub description: String,
     pub image: Option<String>,
     pub quantity: i32,
@@ -29,7 +34,8 @@ pub struct ProductWithQty {
 pub struct NewProduct {
     #[validate(length(min = 6, message = "Product title must be at least 6 symbols long"))]
     pub title: String,
-    pub price: f64,
+    #[validate(custom(function = "validation::positive_money"))]
+    pub price: Money,
     #[validate(length(
         min = 6,
         message = "Product description must be at least 6 symbols long"
@@ -48,11 +54,17 @@ pub struct ProductCategory {
     pub category_id: i32,
 }
 
-#[derive(Insertable, Deserialize, AsChangeset)]
+#[derive(Insertable, Deserialize, AsChangeset, Validate)]
 #[diesel(table_name = products)]
 pub struct UpdateProduct {
+    #[validate(length(min = 6, message = "Product title must be at least 6 symbols long"))]
     pub title: Option<String>,
-    pub price: Option<f64>,
+    #[validate(custom(function = "validation::positive_money"))]
+    pub price: Option<Money>,
+    #[validate(length(
+        min = 6,
+        message = "Product description must be at least 6 symbols long"
+    ))]
     pub description: Option<String>,
     pub image: Option<String>,
     // pub category_id: Option<i32>,
@@ -65,11 +77,20 @@ pub struct CreateProductWithCategories {
     pub category_ids: Vec<i32>,
 }
 
+// Not derived, `product` is flattened so its errors belong at the top level
+impl Validate for CreateProductWithCategories {
+    fn validate(&self) -> Result<(), validator::ValidationErrors> {
+        self.product.validate()
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct ProductWithCategories {
     #[serde(flatten)]
     pub product: Product,
     pub categories: Vec<crate::category::models::Category>,
+    #[serde(flatten)]
+    pub pricing: crate::discount::pricing::Pricing,
 }
 
 #[derive(Debug, Serialize)]
@@ -79,6 +100,8 @@ pub struct ProductWithCategoriesResponse {
     pub page_size: i64,
     pub has_next: bool,
     pub products: Vec<ProductWithCategories>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facets: Option<crate::search::models::Facets>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -86,11 +109,12 @@ pub struct QueryParams {
     pub offset: Option<i64>,
     pub limit: Option<i64>,
     pub category_id: Option<i32>,
-    pub min_price: Option<f64>,
-    pub max_price: Option<f64>,
+    pub min_price: Option<Money>,
+    pub max_price: Option<Money>,
     pub sort_by: Option<SortByParams>,
     pub sort_ord: Option<OrderByParams>,
     pub search_title: Option<String>,
+    pub facets: Option<bool>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -107,3 +131,52 @@ pub enum SortByParams {
     Title,
     Price,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::StockReason"]
+#[serde(rename_all = "lowercase")]
+pub enum StockReason {
+    Restock,
+    Correction,
+    Damaged,
+    Returned,
+    Reserved,
+    Released,
+    Sold,
+    /// Items of an order refunded before shipping put back on the shelf
+    Refunded,
+}
+
+#[derive(Debug, Serialize, Queryable, Selectable, Identifiable)]
+#[diesel(table_name = stock_movements)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct StockMovement {
+    pub id: i32,
+    pub product_id: i32,
+    pub quantity: i32,
+    pub reason: StockReason,
+    pub note: Option<String>,
+    pub order_id: Option<uuid::Uuid>,
+    pub created_by: Option<uuid::Uuid>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = stock_movements)]
+pub struct NewStockMovement {
+    pub product_id: i32,
+    pub quantity: i32,
+    pub reason: StockReason,
+    pub note: Option<String>,
+    pub order_id: Option<uuid::Uuid>,
+    pub created_by: Option<uuid::Uuid>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct AdjustStock {
+    #[validate(custom(function = "validation::non_zero"))]
+    pub quantity: i32,
+    pub reason: StockReason,
+    #[validate(length(max = 500))]
+    pub note: Option<String>,
+}
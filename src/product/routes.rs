@@ -1,22 +1,38 @@
 use axum::{
-    Router,
+    Router, middleware,
     routing::{delete, get, post},
 };
 
 use super::handlers;
-use crate::utils::types::Pool;
+use crate::auth::rbac::{Permission, RequireRole};
+use crate::utils::types::AppState;
 
-pub fn get_routes() -> Router<Pool> {
-    Router::new()
-        .route(
-            "/products",
-            get(handlers::get_products).post(handlers::create_product_with_categories),
-        )
+pub fn get_routes() -> Router<AppState> {
+    let public = Router::new()
+        .route("/products", get(handlers::get_products))
+        .route("/products/{id}", get(handlers::get_product_by_id));
+
+    let manage_products = Router::new()
+        .route("/products", post(handlers::create_product_with_categories))
         .route(
             "/products/{id}",
-            delete(handlers::delete_product)
-                .patch(handlers::update_product)
-                .get(handlers::get_product_by_id),
+            delete(handlers::delete_product).patch(handlers::update_product),
         )
         .route("/products/{id}/image", post(handlers::upload_image))
+        .route_layer(middleware::from_fn_with_state(
+            RequireRole(Permission::ManageProducts),
+            RequireRole::middleware,
+        ));
+
+    let manage_stock = Router::new()
+        .route(
+            "/products/{id}/stock",
+            post(handlers::adjust_stock).get(handlers::get_stock_movements),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            RequireRole(Permission::ManageStock),
+            RequireRole::middleware,
+        ));
+
+    public.merge(manage_products).merge(manage_stock)
 }
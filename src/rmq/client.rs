@@ -1,38 +1,36 @@
-use axum::http::StatusCode;
 use futures_util::stream::StreamExt;
 use lapin::{BasicProperties, Connection, ConnectionProperties, options::*, types::FieldTable};
-use std::env;
+use std::sync::Arc;
 use tokio_executor_trait::Tokio as TokioExec;
 use tokio_reactor_trait::Tokio as TokioReactor;
 
-use crate::utils::{internal_error, types::Pool};
+use crate::config::{Config, RmqConfig};
+use crate::utils::{AppError, types::Pool};
 
-async fn connect(url: &str) -> Result<Connection, (StatusCode, String)> {
+async fn connect(url: &str) -> Result<Connection, AppError> {
     let conn = Connection::connect(
         url,
         ConnectionProperties::default()
             .with_executor(TokioExec::current())
             .with_reactor(TokioReactor::current()),
     )
-    .await
-    .map_err(internal_error)?;
+    .await?;
 
     Ok(conn)
 }
 
-pub async fn publish_event(queue: &str, payload: &str) -> Result<(), (StatusCode, String)> {
-    let url = env::var("RMQ_URL").map_err(internal_error)?;
+fn rmq_url(rmq: &RmqConfig) -> Result<&str, AppError> {
+    rmq.url
+        .as_deref()
+        .ok_or_else(|| AppError::internal("RMQ url is not configured".to_owned()))
+}
 
-    let channel = connect(&url)
-        .await?
-        .create_channel()
-        .await
-        .map_err(internal_error)?;
+pub async fn publish_event(rmq: &RmqConfig, queue: &str, payload: &str) -> Result<(), AppError> {
+    let channel = connect(rmq_url(rmq)?).await?.create_channel().await?;
 
     channel
         .queue_declare(queue, QueueDeclareOptions::default(), FieldTable::default())
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     channel
         .basic_publish(
@@ -42,10 +40,8 @@ pub async fn publish_event(queue: &str, payload: &str) -> Result<(), (StatusCode
             payload.as_bytes(),
             BasicProperties::default(),
         )
-        .await
-        .map_err(internal_error)?
-        .await
-        .map_err(internal_error)?;
+        .await?
+        .await?;
 
     Ok(())
 }
@@ -55,23 +51,17 @@ pub async fn consume<
     // H: Fn(T) -> Fut + Send + Sync + 'static,
     Fut: Future<Output = Result<(), String>> + Send,
 >(
+    rmq: &RmqConfig,
     queue: &str,
     consumer_tag: &str,
     pool: crate::utils::types::Pool,
     handler: impl Fn(crate::notification::models::Notification, Pool) -> Fut + Send + Sync + 'static,
-) -> Result<(), (StatusCode, String)> {
-    let url = env::var("RMQ_URL").map_err(internal_error)?;
-
-    let channel = connect(&url)
-        .await?
-        .create_channel()
-        .await
-        .map_err(internal_error)?;
+) -> Result<(), AppError> {
+    let channel = connect(rmq_url(rmq)?).await?.create_channel().await?;
 
     channel
         .queue_declare(queue, QueueDeclareOptions::default(), FieldTable::default())
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     let mut consumer = channel
         .basic_consume(
@@ -80,19 +70,16 @@ pub async fn consume<
             BasicConsumeOptions::default(),
             FieldTable::default(),
         )
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     while let Some(delivery) = consumer.next().await {
-        let delivery = delivery.map_err(internal_error)?;
+        let delivery = delivery?;
         let data = String::from_utf8_lossy(&delivery.data);
 
-        println!("Data received: {}", data);
-
         if let Ok(notification) =
             serde_json::from_str::<crate::notification::models::Notification>(&data)
         {
-            println!("Parsed data: {:?}", notification);
+            println!("Received {} notification", notification.kind());
 
             let pool = pool.clone();
 
@@ -100,22 +87,25 @@ pub async fn consume<
                 eprintln!("Failed so send an email: {:?}", er);
             }
         } else {
-            eprintln!("Failed to parse a message: {:?}", data);
+            eprintln!("Failed to parse a message from {}", queue);
         }
 
-        delivery
-            .ack(BasicAckOptions::default())
-            .await
-            .map_err(internal_error)?;
+        delivery.ack(BasicAckOptions::default()).await?;
     }
 
     Ok(())
 }
 
-pub fn spawn_consumer(queue: &'static str, tag: &'static str, pool: Pool) {
+pub fn spawn_consumer(queue: &'static str, tag: &'static str, pool: Pool, config: Arc<Config>) {
     tokio::spawn(async move {
-        if let Err(er) = consume(queue, tag, pool, crate::notification::handlers::send_email).await
-        {
+        let send_email = {
+            let config = config.clone();
+            move |notification, pool| {
+                crate::notification::handlers::send_email(notification, pool, config.clone())
+            }
+        };
+
+        if let Err(er) = consume(&config.rmq, queue, tag, pool, send_email).await {
             eprintln!("Error: {:?}", er);
         }
     });
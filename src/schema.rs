@@ -1,5 +1,15 @@
 // @generated automatically by Diesel CLI.
 
+pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "order_status"))]
+    pub struct OrderStatus;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "stock_reason"))]
+    pub struct StockReason;
+}
+
 diesel::table! {
     addresses (id) {
         id -> Uuid,
@@ -19,6 +29,24 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    api_keys (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        #[max_length = 100]
+        name -> Varchar,
+        #[max_length = 16]
+        prefix -> Varchar,
+        #[max_length = 64]
+        key_hash -> Varchar,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+        last_used_at -> Nullable<Timestamp>,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     cart_products (product_id, cart_id) {
         product_id -> Int4,
@@ -32,6 +60,7 @@ diesel::table! {
         id -> Int4,
         user_id -> Uuid,
         updated_at -> Date,
+        coupon_id -> Nullable<Int4>,
     }
 }
 
@@ -43,6 +72,31 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    coupon_redemptions (id) {
+        id -> Int4,
+        coupon_id -> Int4,
+        user_id -> Uuid,
+        order_id -> Uuid,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    coupons (id) {
+        id -> Int4,
+        #[max_length = 30]
+        code -> Varchar,
+        discount_id -> Int4,
+        usage_limit -> Nullable<Int4>,
+        per_user_limit -> Nullable<Int4>,
+        min_subtotal -> Nullable<Numeric>,
+        expires_at -> Nullable<Timestamp>,
+        is_active -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     discount_categories (discount_id, category_id) {
         discount_id -> Int4,
@@ -72,6 +126,113 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    exchange_rates (currency) {
+        #[max_length = 3]
+        currency -> Varchar,
+        rate -> Numeric,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    login_ip_attempts (ip) {
+        #[max_length = 45]
+        ip -> Varchar,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
+        last_failed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    mfa_policies (role) {
+        #[max_length = 10]
+        role -> Varchar,
+        required -> Bool,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    mfa_recovery_codes (id) {
+        id -> Int4,
+        user_id -> Uuid,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        created_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    oidc_states (state) {
+        #[max_length = 64]
+        state -> Varchar,
+        #[max_length = 128]
+        code_verifier -> Varchar,
+        #[max_length = 64]
+        nonce -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    order_items (id) {
+        id -> Int4,
+        order_id -> Uuid,
+        product_id -> Nullable<Int4>,
+        #[max_length = 100]
+        title -> Varchar,
+        price -> Numeric,
+        quantity -> Int4,
+        original_price -> Numeric,
+        discount_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::OrderStatus;
+
+    order_status_history (id) {
+        id -> Int4,
+        order_id -> Uuid,
+        from_status -> Nullable<OrderStatus>,
+        to_status -> OrderStatus,
+        changed_by -> Nullable<Uuid>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::OrderStatus;
+
+    orders (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        status -> OrderStatus,
+        total -> Numeric,
+        created_at -> Timestamp,
+        coupon_id -> Nullable<Int4>,
+        #[max_length = 3]
+        currency -> Varchar,
+    }
+}
+
+diesel::table! {
+    password_reset_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     product_categories (product_id, category_id) {
         product_id -> Int4,
@@ -80,13 +241,21 @@ diesel::table! {
 }
 
 diesel::table! {
+    use diesel::sql_types::*;
+    use diesel_full_text_search::Tsvector;
+
     products (id) {
         id -> Int4,
         #[max_length = 100]
         title -> Varchar,
-        price -> Float8,
+        price -> Numeric,
         description -> Text,
         image -> Nullable<Text>,
+        stock -> Nullable<Int4>,
+        reserved -> Int4,
+        #[max_length = 3]
+        currency -> Varchar,
+        search_vector -> Tsvector,
     }
 }
 
@@ -110,6 +279,46 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        family_id -> Uuid,
+        token_hash -> Text,
+        user_agent -> Nullable<Text>,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::StockReason;
+
+    stock_movements (id) {
+        id -> Int4,
+        product_id -> Int4,
+        quantity -> Int4,
+        reason -> StockReason,
+        note -> Nullable<Text>,
+        order_id -> Nullable<Uuid>,
+        created_by -> Nullable<Uuid>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_identities (id) {
+        id -> Int4,
+        user_id -> Uuid,
+        issuer -> Text,
+        subject -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     user_subscriptions (id) {
         id -> Int4,
@@ -130,36 +339,79 @@ diesel::table! {
         email -> Varchar,
         #[max_length = 100]
         password_hash -> Varchar,
-        hashed_rt -> Nullable<Text>,
         #[max_length = 10]
         role -> Varchar,
+        email_verified_at -> Nullable<Timestamp>,
+        verification_sent_at -> Nullable<Timestamp>,
+        failed_login_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
+        #[max_length = 64]
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled_at -> Nullable<Timestamp>,
+        totp_last_step -> Nullable<Int8>,
+        password_reset_sent_at -> Nullable<Timestamp>,
     }
 }
 
 diesel::joinable!(addresses -> users (user_id));
+diesel::joinable!(api_keys -> users (user_id));
 diesel::joinable!(cart_products -> carts (cart_id));
 diesel::joinable!(cart_products -> products (product_id));
+diesel::joinable!(carts -> coupons (coupon_id));
 diesel::joinable!(carts -> users (user_id));
+diesel::joinable!(coupon_redemptions -> coupons (coupon_id));
+diesel::joinable!(coupon_redemptions -> orders (order_id));
+diesel::joinable!(coupon_redemptions -> users (user_id));
+diesel::joinable!(coupons -> discounts (discount_id));
 diesel::joinable!(discount_categories -> categories (category_id));
 diesel::joinable!(discount_categories -> discounts (discount_id));
 diesel::joinable!(discount_products -> discounts (discount_id));
 diesel::joinable!(discount_products -> products (product_id));
+diesel::joinable!(mfa_recovery_codes -> users (user_id));
+diesel::joinable!(order_items -> discounts (discount_id));
+diesel::joinable!(order_items -> orders (order_id));
+diesel::joinable!(order_items -> products (product_id));
+diesel::joinable!(order_status_history -> orders (order_id));
+diesel::joinable!(order_status_history -> users (changed_by));
+diesel::joinable!(orders -> coupons (coupon_id));
+diesel::joinable!(orders -> users (user_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
 diesel::joinable!(product_categories -> categories (category_id));
 diesel::joinable!(product_categories -> products (product_id));
 diesel::joinable!(profiles -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(stock_movements -> orders (order_id));
+diesel::joinable!(stock_movements -> products (product_id));
+diesel::joinable!(stock_movements -> users (created_by));
+diesel::joinable!(user_identities -> users (user_id));
 diesel::joinable!(user_subscriptions -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     addresses,
+    api_keys,
     cart_products,
     carts,
     categories,
+    coupon_redemptions,
+    coupons,
     discount_categories,
     discount_products,
     discounts,
+    exchange_rates,
+    login_ip_attempts,
+    mfa_policies,
+    mfa_recovery_codes,
+    oidc_states,
+    order_items,
+    order_status_history,
+    orders,
+    password_reset_tokens,
     product_categories,
     products,
     profiles,
+    refresh_tokens,
+    stock_movements,
+    user_identities,
     user_subscriptions,
     users,
 );
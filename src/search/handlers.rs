@@ -0,0 +1,327 @@
+use super::models::{
+    CategoryFacet, Facets, Highlight, PriceBucket, SearchHit, SearchParams, SearchResponse,
+};
+use crate::currency::models::{ConvertPrices, DisplayCurrency};
+use crate::discount::pricing::{PriceResolver, get_product_categories};
+use crate::product::models::Product;
+use crate::schema::products;
+use crate::utils::{AppError, Money, types::Pool};
+use axum::extract::{Json, Query, State};
+use bigdecimal::{BigDecimal, One, RoundingMode, num_bigint};
+use diesel::dsl::{count_star, sql};
+use diesel::sql_types::{BigInt, Nullable, Numeric};
+use diesel::{expression::SqlLiteral, pg::Pg, prelude::*};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use diesel_full_text_search::{
+    RegConfig, TsVectorExtensions, to_tsquery_with_search_config, ts_headline_with_search_config,
+    ts_rank,
+};
+
+pub const MAX_PAGE_SIZE: i64 = 50;
+
+/// Roughly how many price buckets a histogram is split into
+const PRICE_BUCKETS: u64 = 5;
+
+/// Turns free text into a `to_tsquery` expression where every word is matched
+/// as a prefix, so `wire mou` finds "Wireless Mouse" while the user is typing.
+/// Returns `None` when nothing searchable is left.
+pub fn prefix_tsquery(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+                .to_lowercase()
+        })
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word))
+        .collect();
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(terms.join(" & "))
+}
+
+/// Text search configuration `products.search_vector` is built with
+pub fn search_config() -> SqlLiteral<RegConfig> {
+    sql::<RegConfig>("'english'::regconfig")
+}
+
+pub async fn search(
+    State(pool): State<Pool>,
+    currency: DisplayCurrency,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<SearchResponse>, AppError> {
+    let query = prefix_tsquery(&params.q).ok_or(AppError::BadRequest(
+        "Search query cannot be empty".to_owned(),
+    ))?;
+
+    let tsquery = || to_tsquery_with_search_config(search_config(), query.clone());
+
+    let mut conn = pool.get().await?;
+
+    let (total, facets) = if params.facets.unwrap_or(false) {
+        let filtered = || {
+            products::table
+                .filter(products::search_vector.matches(tsquery()))
+                .into_boxed()
+        };
+
+        let (total, facets) = build_facets(filtered, &currency, &mut conn).await?;
+
+        (total, Some(facets))
+    } else {
+        let total = products::table
+            .filter(products::search_vector.matches(tsquery()))
+            .count()
+            .get_result::<i64>(&mut conn)
+            .await?;
+
+        (total, None)
+    };
+
+    let page_size = params.limit.unwrap_or(10).clamp(1, MAX_PAGE_SIZE);
+    let page = params.offset.unwrap_or(0).max(0) / page_size + 1;
+
+    let rows = products::table
+        .filter(products::search_vector.matches(tsquery()))
+        .select((
+            Product::as_select(),
+            ts_rank(products::search_vector, tsquery()),
+            ts_headline_with_search_config(search_config(), products::title, tsquery()),
+            ts_headline_with_search_config(search_config(), products::description, tsquery()),
+        ))
+        .order((
+            ts_rank(products::search_vector, tsquery()).desc(),
+            products::id.asc(),
+        ))
+        .limit(page_size)
+        .offset((page - 1) * page_size)
+        .load::<(Product, f32, String, String)>(&mut conn)
+        .await?;
+
+    let product_ids: Vec<i32> = rows.iter().map(|(product, ..)| product.id).collect();
+
+    let resolver = PriceResolver::load(&mut conn).await?;
+    let product_categories = get_product_categories(&product_ids, &mut conn).await?;
+
+    let results: Vec<SearchHit> = rows
+        .into_iter()
+        .map(|(product, rank, title, description)| {
+            let category_ids = product_categories
+                .get(&product.id)
+                .map(Vec::as_slice)
+                .unwrap_or(&[]);
+            let pricing = resolver.price_for(product.id, category_ids, &product.price);
+
+            SearchHit {
+                product,
+                pricing,
+                rank,
+                highlight: Highlight { title, description },
+            }
+        })
+        .collect();
+
+    let mut res = SearchResponse {
+        total,
+        page,
+        page_size,
+        has_next: page * page_size < total,
+        results,
+        facets,
+    };
+
+    res.convert_prices(&currency);
+
+    Ok(Json(res))
+}
+
+/// Total and facet counts over every product `filtered` returns, aggregated
+/// in the database. Price buckets are in the display currency.
+pub async fn build_facets<'a>(
+    filtered: impl Fn() -> products::BoxedQuery<'a, Pg>,
+    currency: &DisplayCurrency,
+    conn: &mut bb8::PooledConnection<'_, AsyncDieselConnectionManager<AsyncPgConnection>>,
+) -> Result<(i64, Facets), diesel::result::Error> {
+    use crate::schema::{categories, product_categories};
+
+    // `Numeric` has no `max` in diesel's DSL
+    let (total, max_price) = filtered()
+        .select((
+            count_star(),
+            sql::<Nullable<Numeric>>("max(products.price)"),
+        ))
+        .get_result::<(i64, Option<Money>)>(conn)
+        .await?;
+
+    let Some(mut max_price) = max_price else {
+        return Ok((total, Facets::default()));
+    };
+
+    let categories = product_categories::table
+        .inner_join(categories::table)
+        .filter(product_categories::product_id.eq_any(filtered().select(products::id)))
+        .group_by((categories::id, categories::title))
+        .select((categories::id, categories::title, count_star()))
+        .order((count_star().desc(), categories::title.asc()))
+        .load::<(i32, String, i64)>(conn)
+        .await?
+        .into_iter()
+        .map(|(id, title, count)| CategoryFacet { id, title, count })
+        .collect();
+
+    currency.convert(&mut max_price);
+    let width = bucket_width(max_price.amount());
+
+    // The numbers are inlined rather than bound so Postgres sees the same
+    // expression in the select list and in GROUP BY
+    let bucket = || {
+        sql::<BigInt>(&format!(
+            "floor(round(products.price * {}, 2) / {})::bigint",
+            currency.rate, width
+        ))
+    };
+
+    let bucket_counts = products::table
+        .filter(products::id.eq_any(filtered().select(products::id)))
+        .group_by(bucket())
+        .select((bucket(), count_star()))
+        .load::<(i64, i64)>(conn)
+        .await?;
+
+    let targets = PriceResolver::load(conn).await?.targets();
+
+    let mut discounted_query = filtered().filter(products::price.gt(Money::zero()));
+
+    if !targets.all_products {
+        discounted_query = discounted_query.filter(
+            products::id
+                .eq_any(targets.product_ids)
+                .or(products::id.eq_any(
+                    product_categories::table
+                        .filter(product_categories::category_id.eq_any(targets.category_ids))
+                        .select(product_categories::product_id),
+                )),
+        );
+    }
+
+    let discounted = discounted_query.count().get_result::<i64>(conn).await?;
+
+    let facets = Facets {
+        categories,
+        price_buckets: price_buckets(&width, &bucket_counts),
+        discounted,
+        full_price: total - discounted,
+    };
+
+    Ok((total, facets))
+}
+
+/// Turns `(bucket index, count)` rows into buckets of `width` starting at zero,
+/// including the empty ones in between
+fn price_buckets(width: &BigDecimal, bucket_counts: &[(i64, i64)]) -> Vec<PriceBucket> {
+    let last = bucket_counts.iter().map(|(index, _)| *index).max();
+
+    let Some(last) = last else {
+        return Vec::new();
+    };
+
+    (0..=last.max(0))
+        .map(|index| PriceBucket {
+            min: Money::new(width * BigDecimal::from(index)),
+            max: Money::new(width * BigDecimal::from(index + 1)),
+            count: bucket_counts
+                .iter()
+                .filter(|(i, _)| *i == index)
+                .map(|(_, count)| count)
+                .sum(),
+        })
+        .collect()
+}
+
+/// Bucket width of 1, 2 or 5 times a power of ten splitting `0..=max` into
+/// about `PRICE_BUCKETS` buckets. Kept in `BigDecimal` since prices are unbounded.
+fn bucket_width(max: &BigDecimal) -> BigDecimal {
+    let raw_width = (max / BigDecimal::from(PRICE_BUCKETS))
+        .with_scale_round(0, RoundingMode::Up)
+        .max(BigDecimal::one());
+
+    // Largest power of ten not above the raw width
+    let magnitude =
+        BigDecimal::new(num_bigint::BigInt::one(), 1 - raw_width.digits() as i64).with_scale(0);
+
+    [1, 2, 5, 10]
+        .into_iter()
+        .map(|step| &magnitude * BigDecimal::from(step))
+        .find(|width| *width >= raw_width)
+        .unwrap_or(raw_width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn bucket_width_is_a_nice_number() {
+        let width = |max: &str| bucket_width(&BigDecimal::from_str(max).unwrap()).to_string();
+
+        assert_eq!(width("0"), "1");
+        assert_eq!(width("4.99"), "1");
+        assert_eq!(width("10"), "2");
+        assert_eq!(width("230"), "50");
+        assert_eq!(width("999.99"), "200");
+        assert_eq!(width("1000"), "200");
+        assert_eq!(width("1000.01"), "500");
+        assert_eq!(width("4999"), "1000");
+    }
+
+    #[test]
+    fn bucket_width_does_not_overflow_on_large_prices() {
+        let max = BigDecimal::from_str("99999999999999999999999999.99").unwrap();
+
+        assert_eq!(
+            bucket_width(&max),
+            BigDecimal::from_str("20000000000000000000000000").unwrap()
+        );
+        assert_eq!(
+            bucket_width(&BigDecimal::from(u64::MAX)),
+            BigDecimal::from_str("5000000000000000000").unwrap()
+        );
+    }
+
+    #[test]
+    fn price_buckets_fill_the_empty_ones() {
+        let width = BigDecimal::from(20);
+        let buckets = price_buckets(&width, &[(5, 1), (0, 2), (1, 1), (4, 1)]);
+
+        let bounds: Vec<(String, String, i64)> = buckets
+            .iter()
+            .map(|b| {
+                (
+                    b.min.amount().to_string(),
+                    b.max.amount().to_string(),
+                    b.count,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            bounds,
+            vec![
+                ("0".to_owned(), "20".to_owned(), 2),
+                ("20".to_owned(), "40".to_owned(), 1),
+                ("40".to_owned(), "60".to_owned(), 0),
+                ("60".to_owned(), "80".to_owned(), 0),
+                ("80".to_owned(), "100".to_owned(), 1),
+                ("100".to_owned(), "120".to_owned(), 1),
+            ]
+        );
+        assert!(price_buckets(&width, &[]).is_empty());
+    }
+}
@@ -0,0 +1,64 @@
+use crate::discount::pricing::Pricing;
+use crate::product::models::Product;
+use crate::utils::Money;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Debug)]
+pub struct SearchParams {
+    pub q: String,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+    pub facets: Option<bool>,
+}
+
+/// Matched fragments with the search terms wrapped in `<b>` tags
+#[derive(Serialize, Debug)]
+pub struct Highlight {
+    pub title: String,
+    pub description: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub product: Product,
+    #[serde(flatten)]
+    pub pricing: Pricing,
+    pub rank: f32,
+    pub highlight: Highlight,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchResponse {
+    pub total: i64,
+    pub page: i64,
+    pub page_size: i64,
+    pub has_next: bool,
+    pub results: Vec<SearchHit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facets: Option<Facets>,
+}
+
+/// Aggregates over every product matching the filters, not only the current page
+#[derive(Serialize, Debug, Default)]
+pub struct Facets {
+    pub categories: Vec<CategoryFacet>,
+    pub price_buckets: Vec<PriceBucket>,
+    pub discounted: i64,
+    pub full_price: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CategoryFacet {
+    pub id: i32,
+    pub title: String,
+    pub count: i64,
+}
+
+/// Products priced from `min` (inclusive) up to `max` (exclusive)
+#[derive(Serialize, Debug)]
+pub struct PriceBucket {
+    pub min: Money,
+    pub max: Money,
+    pub count: i64,
+}
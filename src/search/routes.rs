@@ -0,0 +1,8 @@
+use axum::{Router, routing::get};
+
+use super::handlers;
+use crate::utils::types::AppState;
+
+pub fn get_routes() -> Router<AppState> {
+    Router::new().route("/search", get(handlers::search))
+}
@@ -0,0 +1,20 @@
+use axum_shop::{admin, config};
+use std::env;
+
+/// Operational tasks run against the shop database, see `admin::USAGE`
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let res = match config::Config::load() {
+        Ok(config) => admin::run(&args, &config).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,30 +1,28 @@
 use super::models::{Address, NewAddress, Profile, UpdateAddress, UpdateProfile};
 
 use crate::auth::models::AccessTokenClaims;
-use crate::utils::{internal_error, types::Pool};
-use axum::{
-    extract::{Json, Path, State},
-    http::StatusCode,
-};
-use diesel::{dsl::sql, prelude::*};
-use diesel_async::pooled_connection::AsyncDieselConnectionManager;
-use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use crate::utils::{AppError, ValidatedJson, types::Pool};
+use axum::extract::{Json, Path, State};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
 use uuid::Uuid;
 
 pub async fn get_user_profile_by_id(
     State(pool): State<Pool>,
     Path(id): Path<Uuid>,
-) -> Result<Json<Profile>, (StatusCode, String)> {
-    use axum_shop::schema::profiles;
+    claims: AccessTokenClaims,
+) -> Result<Json<Profile>, AppError> {
+    use crate::schema::profiles;
+
+    claims.authorize_owner(&id)?;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
     let res = profiles::table
         .filter(profiles::user_id.eq(&id))
         .select(Profile::as_select())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -32,24 +30,19 @@ pub async fn get_user_profile_by_id(
 pub async fn get_current_user_profile(
     State(pool): State<Pool>,
     claims: AccessTokenClaims,
-) -> Result<Json<Profile>, (StatusCode, String)> {
-    use axum_shop::schema::profiles;
+) -> Result<Json<Profile>, AppError> {
+    use crate::schema::profiles;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
-    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| {
-        (
-            StatusCode::BAD_REQUEST,
-            "Failed to parse user id".to_owned(),
-        )
-    })?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
 
     let res = profiles::table
         .filter(profiles::user_id.eq(&user_id))
         .select(Profile::as_select())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -57,45 +50,51 @@ pub async fn get_current_user_profile(
 pub async fn update_profile(
     State(pool): State<Pool>,
     Path(id): Path<Uuid>,
-    Json(payload): Json<UpdateProfile>,
-) -> Result<Json<Profile>, (StatusCode, String)> {
-    use axum_shop::schema::profiles;
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<UpdateProfile>,
+) -> Result<Json<Profile>, AppError> {
+    use crate::schema::profiles;
+
+    let mut conn = pool.get().await?;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let owner_id = profiles::table
+        .find(&id)
+        .select(profiles::user_id)
+        .get_result::<Uuid>(&mut conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("Profile not found".to_owned()),
+            e => e.into(),
+        })?;
+
+    claims.authorize_owner(&owner_id)?;
 
     let res = diesel::update(profiles::table.find(&id))
         .set(&payload)
         .returning(Profile::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
 
 pub async fn update_current_user_profile(
     State(pool): State<Pool>,
-    Path(id): Path<Uuid>,
     claims: AccessTokenClaims,
-    Json(payload): Json<UpdateProfile>,
-) -> Result<Json<Profile>, (StatusCode, String)> {
-    use axum_shop::schema::profiles;
+    ValidatedJson(payload): ValidatedJson<UpdateProfile>,
+) -> Result<Json<Profile>, AppError> {
+    use crate::schema::profiles;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
-    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| {
-        (
-            StatusCode::BAD_REQUEST,
-            "Failed to parse user id".to_owned(),
-        )
-    })?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
 
     let res = diesel::update(profiles::table.filter(profiles::user_id.eq(&user_id)))
         .set(&payload)
         .returning(Profile::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -103,11 +102,14 @@ pub async fn update_current_user_profile(
 pub async fn create_address(
     State(pool): State<Pool>,
     Path(id): Path<Uuid>,
-    Json(payload): Json<NewAddress>,
-) -> Result<Json<Address>, (StatusCode, String)> {
-    use axum_shop::schema::addresses;
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<NewAddress>,
+) -> Result<Json<Address>, AppError> {
+    use crate::schema::addresses;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    claims.authorize_owner(&id)?;
+
+    let mut conn = pool.get().await?;
 
     let address = Address {
         id: Uuid::new_v4(),
@@ -123,8 +125,7 @@ pub async fn create_address(
         .values(&address)
         .returning(Address::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -132,17 +133,19 @@ pub async fn create_address(
 pub async fn get_user_addresses_by_id(
     State(pool): State<Pool>,
     Path(id): Path<Uuid>,
-) -> Result<Json<Vec<Address>>, (StatusCode, String)> {
-    use axum_shop::schema::addresses;
+    claims: AccessTokenClaims,
+) -> Result<Json<Vec<Address>>, AppError> {
+    use crate::schema::addresses;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    claims.authorize_owner(&id)?;
+
+    let mut conn = pool.get().await?;
 
     let res = addresses::table
         .filter(addresses::user_id.eq(&id))
         .select(Address::as_select())
         .load(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -150,18 +153,30 @@ pub async fn get_user_addresses_by_id(
 pub async fn update_address(
     State(pool): State<Pool>,
     Path(id): Path<Uuid>,
-    Json(payload): Json<UpdateAddress>,
-) -> Result<Json<Address>, (StatusCode, String)> {
-    use axum_shop::schema::addresses;
+    claims: AccessTokenClaims,
+    ValidatedJson(payload): ValidatedJson<UpdateAddress>,
+) -> Result<Json<Address>, AppError> {
+    use crate::schema::addresses;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
+
+    let owner_id = addresses::table
+        .find(&id)
+        .select(addresses::user_id)
+        .get_result::<Uuid>(&mut conn)
+        .await
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => AppError::NotFound("Address not found".to_owned()),
+            e => e.into(),
+        })?;
+
+    claims.authorize_owner(&owner_id)?;
 
     let res = diesel::update(addresses::table.find(&id))
         .set(&payload)
         .returning(Address::as_returning())
         .get_result(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -170,18 +185,14 @@ pub async fn update_current_user_address(
     State(pool): State<Pool>,
     Path(id): Path<Uuid>,
     claims: AccessTokenClaims,
-    Json(payload): Json<UpdateAddress>,
-) -> Result<Json<Address>, (StatusCode, String)> {
-    use axum_shop::schema::addresses;
+    ValidatedJson(payload): ValidatedJson<UpdateAddress>,
+) -> Result<Json<Address>, AppError> {
+    use crate::schema::addresses;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
-    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| {
-        (
-            StatusCode::BAD_REQUEST,
-            "Failed to parse user id".to_owned(),
-        )
-    })?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
 
     let res = diesel::update(
         addresses::table.filter(addresses::user_id.eq(&user_id).and(addresses::id.eq(&id))),
@@ -189,8 +200,7 @@ pub async fn update_current_user_address(
     .set(&payload)
     .returning(Address::as_returning())
     .get_result(&mut conn)
-    .await
-    .map_err(internal_error)?;
+    .await?;
 
     Ok(Json(res))
 }
@@ -199,26 +209,20 @@ pub async fn delete_current_user_address(
     State(pool): State<Pool>,
     Path(id): Path<Uuid>,
     claims: AccessTokenClaims,
-    Json(payload): Json<UpdateAddress>,
-) -> Result<Json<Address>, (StatusCode, String)> {
-    use axum_shop::schema::addresses;
+) -> Result<Json<Address>, AppError> {
+    use crate::schema::addresses;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
-    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| {
-        (
-            StatusCode::BAD_REQUEST,
-            "Failed to parse user id".to_owned(),
-        )
-    })?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
 
     let res = diesel::delete(
         addresses::table.filter(addresses::user_id.eq(&user_id).and(addresses::id.eq(&id))),
     )
     .returning(Address::as_returning())
     .get_result(&mut conn)
-    .await
-    .map_err(internal_error)?;
+    .await?;
 
     Ok(Json(res))
 }
@@ -226,24 +230,19 @@ pub async fn delete_current_user_address(
 pub async fn get_current_user_addresses(
     State(pool): State<Pool>,
     claims: AccessTokenClaims,
-) -> Result<Json<Vec<Address>>, (StatusCode, String)> {
-    use axum_shop::schema::addresses;
+) -> Result<Json<Vec<Address>>, AppError> {
+    use crate::schema::addresses;
 
-    let mut conn = pool.get().await.map_err(internal_error)?;
+    let mut conn = pool.get().await?;
 
-    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| {
-        (
-            StatusCode::BAD_REQUEST,
-            "Failed to parse user id".to_owned(),
-        )
-    })?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::BadRequest("Failed to parse user id".to_owned()))?;
 
     let res = addresses::table
         .filter(addresses::user_id.eq(&user_id))
         .select(Address::as_select())
         .load(&mut conn)
-        .await
-        .map_err(internal_error)?;
+        .await?;
 
     Ok(Json(res))
 }
@@ -1,8 +1,10 @@
-use axum_shop::schema::{addresses, profiles};
+use crate::schema::{addresses, profiles};
+use crate::utils::validation;
 use chrono::NaiveDate;
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use validator::Validate;
 
 #[derive(Debug, Serialize, Queryable, Selectable, Insertable)]
 #[diesel(table_name = profiles)]
@@ -18,14 +20,20 @@ pub struct Profile {
     pub currency: String,
 }
 
-#[derive(Debug, Deserialize, AsChangeset)]
+#[derive(Debug, Deserialize, AsChangeset, Validate)]
 #[diesel(table_name = profiles)]
 pub struct UpdateProfile {
+    #[validate(custom(function = "validation::not_blank"), length(max = 50))]
     pub first_name: Option<String>,
+    #[validate(custom(function = "validation::not_blank"), length(max = 50))]
     pub last_name: Option<String>,
+    #[validate(length(min = 5, max = 20))]
     pub phone_number: Option<String>,
+    #[validate(custom(function = "validation::past_date"))]
     pub birth_date: Option<NaiveDate>,
+    #[validate(length(min = 2, max = 10))]
     pub language: Option<String>,
+    #[validate(custom(function = "validation::currency_code"))]
     pub currency: Option<String>,
 }
 
@@ -42,22 +50,32 @@ pub struct Address {
     pub country: Option<String>,
 }
 
-#[derive(Debug, Deserialize, AsChangeset)]
+#[derive(Debug, Deserialize, AsChangeset, Validate)]
 #[diesel(table_name = addresses)]
 pub struct NewAddress {
+    #[validate(length(max = 50))]
     pub label: Option<String>,
+    #[validate(custom(function = "validation::not_blank"), length(max = 255))]
     pub address_line: String,
+    #[validate(length(max = 30))]
     pub city: Option<String>,
+    #[validate(length(max = 30))]
     pub postal_code: Option<String>,
+    #[validate(length(max = 30))]
     pub country: Option<String>,
 }
 
-#[derive(Debug, Deserialize, AsChangeset)]
+#[derive(Debug, Deserialize, AsChangeset, Validate)]
 #[diesel(table_name = addresses)]
 pub struct UpdateAddress {
+    #[validate(length(max = 50))]
     pub label: Option<String>,
+    #[validate(custom(function = "validation::not_blank"), length(max = 255))]
     pub address_line: Option<String>,
+    #[validate(length(max = 30))]
     pub city: Option<String>,
+    #[validate(length(max = 30))]
     pub postal_code: Option<String>,
+    #[validate(length(max = 30))]
     pub country: Option<String>,
 }
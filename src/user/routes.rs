@@ -1,12 +1,12 @@
 use axum::{
     Router,
-    routing::{delete, get, patch, post},
+    routing::{get, patch, post},
 };
 
 use super::handlers;
-use crate::utils::types::Pool;
+use crate::utils::types::AppState;
 
-pub fn get_routes() -> Router<Pool> {
+pub fn get_routes() -> Router<AppState> {
     Router::new()
         .route("/users/{id}/profile", get(handlers::get_user_profile_by_id))
         .route("/profiles/{id}", patch(handlers::update_profile))
@@ -1,12 +1,230 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use super::request_id;
+use axum::{
+    Json,
+    extract::multipart::MultipartError,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use diesel::result::DatabaseErrorKind;
+use serde::Serialize;
+use thiserror::Error;
+use validator::{ValidationErrors, ValidationErrorsKind};
 
-pub fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Error returned by every handler, rendered as an RFC 7807 problem
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unprocessable(String),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    #[error("{0}")]
+    TooManyRequests(String),
+    #[error("{0}")]
+    BadGateway(String),
+    #[error("{0}")]
+    ServiceUnavailable(String),
+    #[error("Request validation failed")]
+    Validation(#[from] ValidationErrors),
+    #[error(transparent)]
+    Database(#[from] diesel::result::Error),
+    /// The message is logged, the client only gets a generic one
+    #[error("{0}")]
+    Internal(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl AppError {
+    pub fn internal(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        AppError::Internal(err.into())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) | AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            AppError::ServiceUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(e) => match e {
+                diesel::result::Error::NotFound => StatusCode::NOT_FOUND,
+                diesel::result::Error::DatabaseError(
+                    DatabaseErrorKind::UniqueViolation | DatabaseErrorKind::CheckViolation,
+                    _,
+                ) => StatusCode::CONFLICT,
+                diesel::result::Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                }
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Message shown to the client, database and internal errors never leak their cause
+    fn detail(&self) -> String {
+        use diesel::result::Error::{DatabaseError, NotFound};
+
+        match self {
+            AppError::Database(NotFound) => "Resource not found".to_owned(),
+            AppError::Database(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                "Resource already exists".to_owned()
+            }
+            AppError::Database(DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                "Referenced resource does not exist".to_owned()
+            }
+            // Stock going below zero or below what is reserved
+            AppError::Database(DatabaseError(DatabaseErrorKind::CheckViolation, _)) => {
+                "Request conflicts with the current state of the resource".to_owned()
+            }
+            AppError::Database(_) | AppError::Internal(_) => "Internal server error".to_owned(),
+            e => e.to_string(),
+        }
+    }
+}
+
+/// `field` is `None` for errors of the whole body, like a date range
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: Option<String>,
+    pub code: String,
+    pub message: Option<String>,
+}
+
+/// `application/problem+json` body
+#[derive(Serialize, Debug)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Unknown error"),
+            status: status.as_u16(),
+            detail: detail.into(),
+            request_id: request_id::current(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut res = (status, Json(self)).into_response();
+
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(PROBLEM_CONTENT_TYPE),
+        );
+
+        res
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        if status.is_server_error() {
+            tracing::error!(request_id = request_id::current(), "{}", self);
+        }
+
+        let mut problem = Problem::new(status, self.detail());
+
+        if let AppError::Validation(errors) = &self {
+            collect_field_errors("", errors, &mut problem.errors);
+            problem.errors.sort_by(|a, b| a.field.cmp(&b.field));
+        }
+
+        problem.into_response()
+    }
+}
+
+/// Flattens nested validation errors into `address.city` / `items[0].quantity` paths
+fn collect_field_errors(prefix: &str, errors: &ValidationErrors, out: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        // Struct level (`schema`) errors are reported on the struct itself
+        let path = match (prefix, field.as_ref()) {
+            (prefix, "__all__") => prefix.to_owned(),
+            ("", field) => field.to_owned(),
+            (prefix, field) => format!("{}.{}", prefix, field),
+        };
+
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                out.extend(errors.iter().map(|error| FieldError {
+                    field: Some(path.clone()).filter(|path| !path.is_empty()),
+                    code: error.code.to_string(),
+                    message: error.message.as_ref().map(|message| message.to_string()),
+                }))
+            }
+            ValidationErrorsKind::Struct(errors) => collect_field_errors(&path, errors, out),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect_field_errors(&format!("{}[{}]", path, index), errors, out);
+                }
+            }
+        }
+    }
+}
+
+/// Infrastructure errors that can only end up as a 500
+macro_rules! internal_errors {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl From<$ty> for AppError {
+                fn from(err: $ty) -> Self {
+                    AppError::Internal(Box::new(err))
+                }
+            }
+        )*
+    };
+}
+
+internal_errors!(
+    bb8::RunError<diesel_async::pooled_connection::PoolError>,
+    bcrypt::BcryptError,
+    jsonwebtoken::errors::Error,
+    lapin::Error,
+    serde_json::Error,
+    std::env::VarError,
+    std::io::Error,
+    tokio::task::JoinError,
+    uuid::Error,
+);
+
+impl From<MultipartError> for AppError {
+    fn from(err: MultipartError) -> Self {
+        AppError::BadRequest(err.body_text())
+    }
 }
 
 pub async fn handler_404() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "nothing to see here")
+    AppError::NotFound("nothing to see here".to_owned())
 }
@@ -1,7 +1,14 @@
 pub mod error;
+pub mod money;
 mod print_request;
+pub mod request_id;
 pub mod types;
+pub mod validated;
+pub mod validation;
 
+pub use error::AppError;
 pub use error::handler_404;
-pub use error::internal_error;
+pub use money::Money;
 pub use print_request::print_req_res;
+pub use request_id::request_id;
+pub use validated::ValidatedJson;
@@ -0,0 +1,149 @@
+use bigdecimal::{BigDecimal, RoundingMode, Zero};
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Numeric;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::ops::{Add, Mul, Sub};
+use std::str::FromStr;
+
+/// Currency every price is stored and charged in
+pub const BASE_CURRENCY: &str = "usd";
+
+/// Number of decimal places money amounts are rounded to
+pub const MONEY_SCALE: i64 = 2;
+
+/// Exact decimal money amount backed by a `NUMERIC` column.
+/// The currency is stored next to the amount, see `BASE_CURRENCY`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Numeric)]
+pub struct Money(BigDecimal);
+
+impl Money {
+    pub fn new(amount: BigDecimal) -> Self {
+        Money(amount)
+    }
+
+    pub fn zero() -> Self {
+        Money(BigDecimal::zero())
+    }
+
+    pub fn amount(&self) -> &BigDecimal {
+        &self.0
+    }
+
+    /// The only rounding rule for money: half up to `MONEY_SCALE` decimals
+    pub fn round(&self) -> Self {
+        Money(self.0.with_scale_round(MONEY_SCALE, RoundingMode::HalfUp))
+    }
+
+    /// Takes `percent` off the amount, `percent` is clamped to 0..=100
+    pub fn percent_off(&self, percent: &BigDecimal) -> Self {
+        let zero = BigDecimal::zero();
+        let hundred = BigDecimal::from(100);
+        let percent = percent.clamp(&zero, &hundred);
+
+        Money(&self.0 * (&hundred - percent) / hundred).round()
+    }
+
+    /// Subtracts without going below zero
+    pub fn saturating_sub(&self, other: &Money) -> Self {
+        if other >= self {
+            return Money::zero();
+        }
+
+        Money(&self.0 - &other.0)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, rhs: Money) -> Money {
+        Money(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, rhs: Money) -> Money {
+        Money(self.0 - rhs.0)
+    }
+}
+
+impl Mul<i32> for &Money {
+    type Output = Money;
+
+    fn mul(self, rhs: i32) -> Money {
+        Money(&self.0 * BigDecimal::from(rhs))
+    }
+}
+
+impl std::iter::Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Self {
+        iter.fold(Money::zero(), |acc, money| acc + money)
+    }
+}
+
+impl From<BigDecimal> for Money {
+    fn from(amount: BigDecimal) -> Self {
+        Money(amount)
+    }
+}
+
+impl std::fmt::Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.round().0)
+    }
+}
+
+impl FromStr for Money {
+    type Err = bigdecimal::ParseBigDecimalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BigDecimal::from_str(s.trim()).map(Money)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawMoney {
+            Text(String),
+            Number(f64),
+        }
+
+        // Numbers go through their shortest representation so 19.99 stays 19.99
+        let raw = match RawMoney::deserialize(deserializer)? {
+            RawMoney::Text(text) => text,
+            RawMoney::Number(number) => number.to_string(),
+        };
+
+        Money::from_str(&raw).map_err(de::Error::custom)
+    }
+}
+
+impl ToSql<Numeric, Pg> for Money {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <BigDecimal as ToSql<Numeric, Pg>>::to_sql(&self.0, out)
+    }
+}
+
+impl FromSql<Numeric, Pg> for Money {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        <BigDecimal as FromSql<Numeric, Pg>>::from_sql(bytes).map(Money)
+    }
+}
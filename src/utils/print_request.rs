@@ -1,16 +1,25 @@
+use crate::utils::AppError;
 use axum::{
     body::{Body, Bytes},
-    extract::Request,
-    http::StatusCode,
+    extract::{OriginalUri, Request},
     middleware::Next,
     response::{IntoResponse, Response},
 };
 use http_body_util::BodyExt;
 
-pub async fn print_req_res(
-    req: Request,
-    next: Next,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+/// Bodies of these routes carry passwords, tokens, TOTP secrets and API keys
+const UNLOGGED_PATHS: [&str; 3] = ["/api/auth/", "/api/me/api-keys", "/api/users"];
+
+pub async fn print_req_res(req: Request, next: Next) -> Result<impl IntoResponse, AppError> {
+    let path = match req.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.path(),
+        None => req.uri().path(),
+    };
+
+    if UNLOGGED_PATHS.iter().any(|prefix| path.starts_with(prefix)) {
+        return Ok(next.run(req).await);
+    }
+
     let (parts, body) = req.into_parts();
     let bytes = buffer_and_print("Request", body).await?;
     let req = Request::from_parts(parts, Body::from(bytes));
@@ -24,7 +33,7 @@ pub async fn print_req_res(
     Ok(res)
 }
 
-async fn buffer_and_print<B>(direction: &str, body: B) -> Result<Bytes, (StatusCode, String)>
+async fn buffer_and_print<B>(direction: &str, body: B) -> Result<Bytes, AppError>
 where
     B: axum::body::HttpBody<Data = Bytes>,
     B::Error: std::fmt::Display,
@@ -32,10 +41,9 @@ where
     let bytes = match body.collect().await {
         Ok(collected) => collected.to_bytes(),
         Err(err) => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                format!("failed to read {direction} body: {err}"),
-            ));
+            return Err(AppError::BadRequest(format!(
+                "failed to read {direction} body: {err}"
+            )));
         }
     };
 
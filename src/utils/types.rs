@@ -1,6 +1,37 @@
 #![allow(dead_code)]
 
+use crate::auth::keys::JwtKeys;
+use crate::config::Config;
+use axum::extract::FromRef;
 use diesel_async::{AsyncPgConnection, pooled_connection::AsyncDieselConnectionManager};
+use std::sync::Arc;
+
 pub type Pool = bb8::Pool<AsyncDieselConnectionManager<AsyncPgConnection>>;
 
-pub type Result<T> = std::result::Result<axum::Json<T>, (axum::http::StatusCode, String)>;
+pub type Result<T> = std::result::Result<axum::Json<T>, super::AppError>;
+
+/// Handlers take the part they need, `State<Pool>`, `State<Arc<Config>>` or `State<Arc<JwtKeys>>`
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: Pool,
+    pub config: Arc<Config>,
+    pub keys: Arc<JwtKeys>,
+}
+
+impl FromRef<AppState> for Pool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<JwtKeys> {
+    fn from_ref(state: &AppState) -> Self {
+        state.keys.clone()
+    }
+}
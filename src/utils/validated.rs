@@ -0,0 +1,36 @@
+use super::AppError;
+use axum::extract::{FromRequest, Json, Request, rejection::JsonRejection};
+use axum::http::StatusCode;
+use serde::de::DeserializeOwned;
+use validator::Validate;
+
+/// JSON body that is validated before reaching the handler
+pub struct ValidatedJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+
+        value.validate()?;
+
+        Ok(ValidatedJson(value))
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        let message = rejection.body_text();
+
+        match rejection.status() {
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => AppError::UnsupportedMediaType(message),
+            StatusCode::UNPROCESSABLE_ENTITY => AppError::Unprocessable(message),
+            _ => AppError::BadRequest(message),
+        }
+    }
+}
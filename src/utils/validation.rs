@@ -0,0 +1,62 @@
+use super::Money;
+use bigdecimal::{BigDecimal, Zero};
+use chrono::NaiveDate;
+use std::borrow::Cow;
+use validator::ValidationError;
+
+fn error(code: &'static str, message: &'static str) -> ValidationError {
+    ValidationError::new(code).with_message(Cow::Borrowed(message))
+}
+
+pub fn not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(error("blank", "Cannot be empty"));
+    }
+
+    Ok(())
+}
+
+pub fn positive_money(value: &Money) -> Result<(), ValidationError> {
+    positive_decimal(value.amount())
+}
+
+pub fn non_negative_money(value: &Money) -> Result<(), ValidationError> {
+    if value.amount() < &BigDecimal::zero() {
+        return Err(error("negative", "Cannot be negative"));
+    }
+
+    Ok(())
+}
+
+pub fn positive_decimal(value: &BigDecimal) -> Result<(), ValidationError> {
+    if value <= &BigDecimal::zero() {
+        return Err(error("not_positive", "Must be greater than 0"));
+    }
+
+    Ok(())
+}
+
+pub fn non_zero(value: i32) -> Result<(), ValidationError> {
+    if value == 0 {
+        return Err(error("zero", "Cannot be 0"));
+    }
+
+    Ok(())
+}
+
+/// Three letter ISO 4217 code, in any case
+pub fn currency_code(value: &str) -> Result<(), ValidationError> {
+    if value.len() != 3 || !value.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(error("currency", "Must be a three letter currency code"));
+    }
+
+    Ok(())
+}
+
+pub fn past_date(value: &NaiveDate) -> Result<(), ValidationError> {
+    if value >= &chrono::Utc::now().date_naive() {
+        return Err(error("not_past", "Must be in the past"));
+    }
+
+    Ok(())
+}
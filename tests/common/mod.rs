@@ -0,0 +1,23 @@
+//! Helpers of the tests that need a migrated database. They are `#[ignore]`d,
+//! run them with `DATABASE_URL=... cargo test -- --ignored`.
+
+use axum_shop::schema::users;
+use diesel::prelude::*;
+
+pub fn database_url() -> String {
+    std::env::var("DATABASE_URL").expect("DATABASE_URL must point at a migrated database")
+}
+
+/// Deletes a test user, and the rows cascading from it, when dropped so a
+/// failed assertion does not leave it behind
+pub struct UserGuard(pub uuid::Uuid);
+
+impl Drop for UserGuard {
+    fn drop(&mut self) {
+        // Blocking on purpose, drop cannot await. Errors are ignored so a
+        // panicking test is not turned into an abort.
+        if let Ok(mut conn) = PgConnection::establish(&database_url()) {
+            let _ = diesel::delete(users::table.find(self.0)).execute(&mut conn);
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! OpenID Connect login against a local mock provider: PKCE, the nonce check
+//! and linking by verified email. Runs against the database in
+//! `DATABASE_URL` with `cargo test -- --ignored`.
+
+mod common;
+
+use axum::extract::{Form, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use axum_shop::auth::{self, keys::JwtKeys};
+use axum_shop::config::{AuthConfig, Config, OidcConfig};
+use axum_shop::pool::get_pool;
+use axum_shop::schema::{user_identities, users};
+use axum_shop::utils::types::{AppState, Pool};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use common::{UserGuard, database_url};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use url::Url;
+use uuid::Uuid;
+
+const CLIENT_ID: &str = "shop";
+/// Modulus of `src/auth/testdata/rsa.pem`, the mock provider signing key
+const RSA_N: &str = "mUlUM-AyrYBWCbkTM_VHNv2Go5d8mCKyjb65IgW2qIjcSP_x12rcYMjusox_Gy90ddd7_5ny80E6ieSbFpFBny-Ogs5iZbtsMMmOPxID499USuInDU5nAgTkWL4F_jxF5FFER5P8Ze_M680j7fJ84MeuVs-6G9qznfwThrBLVOUjJ1pfqU2bH755WcWD16KU8NoAA_wa2F69pWsfl33tMX7zZDQnc07mK1Mso92oP1A4fSy2XH4L7qfj-LAGSZNC2EBd5g05NewsjI9glaiVBljq49eJlM8poqmtJd_mZZZNhwO88LvyBpLzFftLWOw3Snno64tJ7-Jto-WA-v1CFQ";
+
+/// What the mock provider issued an authorization code for
+#[derive(Clone)]
+struct Grant {
+    code_challenge: String,
+    nonce: String,
+    sub: String,
+    email: String,
+    email_verified: bool,
+}
+
+#[derive(Clone)]
+struct MockProvider {
+    issuer: String,
+    redirect_url: String,
+    grants: Arc<Mutex<HashMap<String, Grant>>>,
+}
+
+async fn discovery(State(mock): State<MockProvider>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "issuer": mock.issuer,
+        "authorization_endpoint": format!("{}/authorize", mock.issuer),
+        "token_endpoint": format!("{}/token", mock.issuer),
+        "jwks_uri": format!("{}/jwks", mock.issuer),
+    }))
+}
+
+async fn jwks() -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "keys": [{ "kty": "RSA", "kid": "mock", "use": "sig", "alg": "RS256", "n": RSA_N, "e": "AQAB" }]
+    }))
+}
+
+/// Checks the PKCE verifier against the challenge sent to `/authorize`
+async fn token(
+    State(mock): State<MockProvider>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
+    let invalid_grant = || {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({ "error": "invalid_grant" })),
+        )
+    };
+
+    let field = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+
+    if field("grant_type") != "authorization_code"
+        || field("client_id") != CLIENT_ID
+        || field("redirect_uri") != mock.redirect_url
+    {
+        return Err(invalid_grant());
+    }
+
+    let grant = mock
+        .grants
+        .lock()
+        .unwrap()
+        .remove(field("code"))
+        .ok_or_else(invalid_grant)?;
+
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(field("code_verifier").as_bytes()));
+
+    if challenge != grant.code_challenge {
+        return Err(invalid_grant());
+    }
+
+    let claims = serde_json::json!({
+        "iss": mock.issuer,
+        "aud": CLIENT_ID,
+        "sub": grant.sub,
+        "exp": usize::MAX,
+        "email": grant.email,
+        "email_verified": grant.email_verified,
+        "nonce": grant.nonce,
+    });
+
+    let pem =
+        std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("src/auth/testdata/rsa.pem"))
+            .unwrap();
+    let header = Header {
+        kid: Some("mock".to_owned()),
+        ..Header::new(Algorithm::RS256)
+    };
+    let id_token =
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_rsa_pem(&pem).unwrap()).unwrap();
+
+    Ok(Json(serde_json::json!({
+        "access_token": "mock",
+        "token_type": "Bearer",
+        "id_token": id_token,
+    })))
+}
+
+/// Query of the shop redirect to the provider `/authorize`, and the state
+/// cookie it set in the browser
+struct Authorization {
+    state: String,
+    nonce: String,
+    code_challenge: String,
+    cookie: String,
+}
+
+struct Shop {
+    url: String,
+    mock: MockProvider,
+    client: reqwest::Client,
+}
+
+impl Shop {
+    async fn authorize(&self) -> Authorization {
+        let res = self
+            .client
+            .get(format!("{}/api/auth/oidc/authorize", self.url))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        let set_cookie = res.headers()["set-cookie"].to_str().unwrap().to_owned();
+        let location = Url::parse(res.headers()["location"].to_str().unwrap()).unwrap();
+        let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+
+        assert!(location.as_str().starts_with(&self.mock.issuer));
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert_eq!(query["redirect_uri"], self.mock.redirect_url);
+
+        let cookie = format!("oidc_state={}", query["state"]);
+        assert!(set_cookie.starts_with(&format!("{};", cookie)));
+        assert!(set_cookie.contains("; HttpOnly"));
+        assert!(set_cookie.contains("; SameSite=Lax"));
+        assert!(set_cookie.contains("; Path=/api/auth/oidc/callback"));
+
+        Authorization {
+            state: query["state"].clone(),
+            nonce: query["nonce"].clone(),
+            code_challenge: query["code_challenge"].clone(),
+            cookie,
+        }
+    }
+
+    /// The provider redirecting the browser holding `cookie` back with a code
+    /// issued for `grant`
+    async fn callback(&self, state: &str, cookie: &str, grant: Grant) -> reqwest::Response {
+        let code = Uuid::new_v4().to_string();
+
+        self.mock.grants.lock().unwrap().insert(code.clone(), grant);
+
+        self.client
+            .get(format!("{}/api/auth/oidc/callback", self.url))
+            .query(&[("code", code.as_str()), ("state", state)])
+            .header("cookie", cookie)
+            .send()
+            .await
+            .unwrap()
+    }
+}
+
+async fn insert_user(pool: &Pool, email: &str) -> Uuid {
+    let mut conn = pool.get().await.unwrap();
+    let id = Uuid::new_v4();
+
+    diesel::insert_into(users::table)
+        .values((
+            users::id.eq(id),
+            users::email.eq(email),
+            users::password_hash.eq("not-a-hash"),
+            users::role.eq("user"),
+        ))
+        .execute(&mut conn)
+        .await
+        .unwrap();
+
+    id
+}
+
+async fn linked_user(pool: &Pool, issuer: &str, subject: &str) -> Option<Uuid> {
+    let mut conn = pool.get().await.unwrap();
+
+    user_identities::table
+        .filter(user_identities::issuer.eq(issuer))
+        .filter(user_identities::subject.eq(subject))
+        .select(user_identities::user_id)
+        .first(&mut conn)
+        .await
+        .optional()
+        .unwrap()
+}
+
+// A single test, the provider discovery document is cached for the process
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn oidc_login_with_mock_provider() {
+    let pool = get_pool(&database_url()).await.unwrap();
+
+    let provider_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = format!("http://{}", provider_listener.local_addr().unwrap());
+    let shop_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let shop_url = format!("http://{}", shop_listener.local_addr().unwrap());
+    let redirect_url = format!("{}/api/auth/oidc/callback", shop_url);
+
+    let mock = MockProvider {
+        issuer: issuer.clone(),
+        redirect_url: redirect_url.clone(),
+        grants: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    let provider = Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/jwks", get(jwks))
+        .route("/token", post(token))
+        .with_state(mock.clone());
+
+    tokio::spawn(async move { axum::serve(provider_listener, provider).await.unwrap() });
+
+    let config = Config {
+        auth: AuthConfig {
+            access_secret: Some("access".to_owned()),
+            refresh_secret: "refresh".to_owned(),
+            ..AuthConfig::default()
+        },
+        oidc: OidcConfig {
+            issuer: Some(issuer.clone()),
+            client_id: Some(CLIENT_ID.to_owned()),
+            client_secret: None,
+            redirect_url: Some(redirect_url),
+        },
+        ..Config::default()
+    };
+
+    let state = AppState {
+        pool: pool.clone(),
+        keys: Arc::new(JwtKeys::from_config(&config.auth).unwrap()),
+        config: Arc::new(config),
+    };
+
+    let shop = Router::new()
+        .nest("/api", auth::routes::get_routes())
+        .with_state(state);
+
+    tokio::spawn(async move { axum::serve(shop_listener, shop).await.unwrap() });
+
+    let shop = Shop {
+        url: shop_url,
+        mock,
+        client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap(),
+    };
+
+    let tag = &Uuid::new_v4().simple().to_string()[..12];
+    let email = format!("oidc-{}@example.com", tag);
+    let user_id = insert_user(&pool, &email).await;
+    let _user = UserGuard(user_id);
+
+    let grant = |auth: &Authorization, sub: &str, email_verified: bool| Grant {
+        code_challenge: auth.code_challenge.clone(),
+        nonce: auth.nonce.clone(),
+        sub: sub.to_owned(),
+        email: email.to_uppercase(),
+        email_verified,
+    };
+
+    // The provider does not vouch for the email, nothing is linked
+    let auth = shop.authorize().await;
+    let res = shop
+        .callback(&auth.state, &auth.cookie, grant(&auth, "unverified", false))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(linked_user(&pool, &issuer, "unverified").await, None);
+
+    // A code whose challenge was made for another verifier
+    let auth = shop.authorize().await;
+    let other = shop.authorize().await;
+    let res = shop
+        .callback(
+            &auth.state,
+            &auth.cookie,
+            Grant {
+                code_challenge: other.code_challenge.clone(),
+                ..grant(&auth, "pkce", true)
+            },
+        )
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(linked_user(&pool, &issuer, "pkce").await, None);
+
+    // An ID token minted for another login attempt
+    let res = shop
+        .callback(
+            &other.state,
+            &other.cookie,
+            Grant {
+                nonce: auth.nonce.clone(),
+                ..grant(&other, "nonce", true)
+            },
+        )
+        .await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(linked_user(&pool, &issuer, "nonce").await, None);
+
+    // A callback url started in another browser, which holds its own state
+    let auth = shop.authorize().await;
+    let victim = shop.authorize().await;
+
+    for cookie in [victim.cookie.as_str(), ""] {
+        let res = shop
+            .callback(&auth.state, cookie, grant(&auth, "forced", true))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(linked_user(&pool, &issuer, "forced").await, None);
+    }
+
+    // Verified email, linked to the existing account. The state rejected above
+    // was not used up.
+    let res = shop
+        .callback(&auth.state, &auth.cookie, grant(&auth, "linked", true))
+        .await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body: serde_json::Value = res.json().await.unwrap();
+    assert!(body["access_token"].is_string());
+    assert!(body["refresh_token"].is_string());
+    assert_eq!(linked_user(&pool, &issuer, "linked").await, Some(user_id));
+
+    let mut conn = pool.get().await.unwrap();
+    let verified_at = users::table
+        .find(user_id)
+        .select(users::email_verified_at)
+        .get_result::<Option<chrono::NaiveDateTime>>(&mut conn)
+        .await
+        .unwrap();
+    assert!(verified_at.is_some());
+
+    // Every state is single use
+    let res = shop
+        .callback(&auth.state, &auth.cookie, grant(&auth, "linked", true))
+        .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,161 @@
+//! Ownership checks of the address handlers, run against the database in
+//! `DATABASE_URL` with `cargo test -- --ignored`
+
+mod common;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum_shop::auth::models::AccessTokenClaims;
+use axum_shop::pool::get_pool;
+use axum_shop::schema::{addresses, users};
+use axum_shop::user::handlers;
+use axum_shop::user::models::UpdateAddress;
+use axum_shop::utils::{AppError, ValidatedJson, types::Pool};
+use common::{UserGuard, database_url};
+use diesel::prelude::*;
+use diesel_async::RunQueryDsl;
+use uuid::Uuid;
+
+struct Fixture {
+    pool: Pool,
+    owner_id: Uuid,
+    address_id: Uuid,
+    _owner: UserGuard,
+}
+
+impl Fixture {
+    async fn new() -> Self {
+        let pool = get_pool(&database_url()).await.unwrap();
+        let mut conn = pool.get().await.unwrap();
+
+        let owner_id = Uuid::new_v4();
+        let address_id = Uuid::new_v4();
+        let owner = UserGuard(owner_id);
+
+        diesel::insert_into(users::table)
+            .values((
+                users::id.eq(owner_id),
+                users::email.eq(format!(
+                    "{}@example.com",
+                    &owner_id.simple().to_string()[..16]
+                )),
+                users::password_hash.eq("not-a-hash"),
+                users::role.eq("user"),
+            ))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        diesel::insert_into(addresses::table)
+            .values((
+                addresses::id.eq(address_id),
+                addresses::user_id.eq(owner_id),
+                addresses::address_line.eq("1 Owner Street"),
+            ))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+
+        drop(conn);
+
+        Fixture {
+            pool,
+            owner_id,
+            address_id,
+            _owner: owner,
+        }
+    }
+
+    async fn address_line(&self) -> String {
+        let mut conn = self.pool.get().await.unwrap();
+
+        addresses::table
+            .find(self.address_id)
+            .select(addresses::address_line)
+            .get_result(&mut conn)
+            .await
+            .unwrap()
+    }
+}
+
+fn claims(sub: &Uuid, role: &str) -> AccessTokenClaims {
+    AccessTokenClaims {
+        sub: sub.to_string(),
+        email: "someone@example.com".to_owned(),
+        role: role.to_owned(),
+        exp: 0,
+        sid: None,
+        api_key_id: None,
+        scopes: None,
+    }
+}
+
+fn update() -> ValidatedJson<UpdateAddress> {
+    ValidatedJson(UpdateAddress {
+        label: None,
+        address_line: Some("2 Intruder Road".to_owned()),
+        city: None,
+        postal_code: None,
+        country: None,
+    })
+}
+
+fn assert_forbidden(err: AppError) {
+    assert!(matches!(err, AppError::Forbidden(_)), "got {:?}", err);
+    assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn update_address_is_forbidden_for_non_owner() {
+    let fixture = Fixture::new().await;
+
+    let res = handlers::update_address(
+        State(fixture.pool.clone()),
+        Path(fixture.address_id),
+        claims(&Uuid::new_v4(), "user"),
+        update(),
+    )
+    .await;
+
+    assert_forbidden(res.unwrap_err());
+    assert_eq!(fixture.address_line().await, "1 Owner Street");
+
+    let res = handlers::update_address(
+        State(fixture.pool.clone()),
+        Path(fixture.address_id),
+        claims(&fixture.owner_id, "user"),
+        update(),
+    )
+    .await;
+
+    assert!(res.is_ok());
+    assert_eq!(fixture.address_line().await, "2 Intruder Road");
+}
+
+#[tokio::test]
+#[ignore = "needs DATABASE_URL"]
+async fn get_user_addresses_is_forbidden_for_non_owner() {
+    let fixture = Fixture::new().await;
+
+    let res = handlers::get_user_addresses_by_id(
+        State(fixture.pool.clone()),
+        Path(fixture.owner_id),
+        claims(&Uuid::new_v4(), "seller"),
+    )
+    .await;
+
+    assert_forbidden(res.unwrap_err());
+
+    let res = handlers::get_user_addresses_by_id(
+        State(fixture.pool.clone()),
+        Path(fixture.owner_id),
+        claims(&Uuid::new_v4(), "admin"),
+    )
+    .await;
+
+    let addresses = res.unwrap().0;
+    assert_eq!(addresses.len(), 1);
+    assert_eq!(addresses[0].id, fixture.address_id);
+}